@@ -0,0 +1,69 @@
+// Record which commit the agent was built from, and when, for `/monitor/version` to report.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_sha = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_default();
+    println!("cargo:rustc-env=SERVAL_GIT_SHA={git_sha}");
+
+    // Build again when HEAD moves: to another branch, or when the branch it names gets a new
+    // commit, whether its ref is loose or packed. Cargo would rebuild every time for a file
+    // that doesn't exist, so only files that do are watched.
+    let mut watched = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        watched.push(head_ref);
+    }
+    for name in watched {
+        let path = git(&["rev-parse", "--git-path", &name]);
+        if let Some(path) = path.filter(|path| Path::new(path).exists()) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Honor SOURCE_DATE_EPOCH so that reproducible builds stay reproducible.
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=SERVAL_BUILT_AT={}", rfc3339(built_at));
+}
+
+/// Run git with the given arguments, returning what it printed if it succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|out| out.trim().to_string())
+}
+
+/// Format seconds since the Unix epoch as a UTC timestamp, e.g. `2023-05-01T12:00:00Z`.
+fn rfc3339(secs: u64) -> String {
+    let (days, secs) = ((secs / 86_400) as i64, secs % 86_400);
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
@@ -1,54 +1,453 @@
 use anyhow::Result;
-use axum::extract::State;
-use axum::http::{Request, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderName, Request, StatusCode};
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
-use http::header::HeaderValue;
+use axum::Json;
+use engine::executor::{SUPPORTED_RUNTIMES, SUPPORTED_WASM_FEATURES};
+use http::header::{HeaderValue, RETRY_AFTER};
+use once_cell::sync::OnceCell;
+use utils::errors::ApiError;
+use utils::mesh::{KaboodleMesh, KaboodlePeer};
+use utils::structs::api::{BuildInfo, ErrorResponse, MeshHealth, NodeStatus, TENANT_HEADER};
+use utils::structs::Manifest;
+use uuid::Uuid;
 
-use crate::structures::AppState;
+use crate::breaker::{CircuitBreaker, PROXY_BREAKER};
+use crate::partition::MESH_WATCH;
+use crate::ratelimit::RATE_LIMITER;
+use crate::resources;
+use crate::structures::{AppState, MESH};
 
+pub mod openapi;
 pub mod v1;
 // Follow this pattern for additional major versions. E.g.,
 // pub mod v2;
 
-/// Remember what is important.
-pub async fn clacks<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+/// The headers the operator asked for on every response, from `RESPONSE_HEADERS`.
+pub static RESPONSE_HEADERS: OnceCell<Vec<(HeaderName, HeaderValue)>> = OnceCell::new();
+
+/// Add the operator's headers to every response, along with one to remember what is important.
+/// Headers the handler already set are left alone.
+pub async fn response_headers<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
     let mut response = next.run(req).await;
-    if !response.headers().contains_key("X-Clacks-Overhead") {
-        response.headers_mut().append(
+    let headers = response.headers_mut();
+    if !headers.contains_key("X-Clacks-Overhead") {
+        headers.append(
             "X-Clacks-Overhead",
             HeaderValue::from_static("GNU/Terry Pratchett"),
         );
     }
+    for (name, value) in RESPONSE_HEADERS.get().into_iter().flatten() {
+        if !headers.contains_key(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+    Ok(response)
+}
+
+/// Parse a list of response headers such as `X-Served-By={instance_id},X-Team=mesh`. The
+/// placeholder `{instance_id}` in a value is replaced with this node's instance id.
+pub fn parse_response_headers(
+    spec: &str,
+    instance_id: &str,
+) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    spec.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let Some((name, value)) = pair.split_once('=') else {
+                return Err(format!("{pair} should look like NAME=VALUE"));
+            };
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("{} is not a valid header name", name.trim()))?;
+            let value = value.trim().replace("{instance_id}", instance_id);
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| format!("{value} is not a valid header value"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// The header that carries a request's id between clients and nodes.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+tokio::task_local! {
+    /// The id of the request being handled by the current task, for tagging log lines with.
+    static REQUEST_ID: String;
+    /// The id of the job being run by the current task, for tagging log lines with.
+    static JOB_ID: Uuid;
+}
+
+/// The id of the request the current task is handling, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// The id of the job the current task is running, if any.
+pub fn current_job_id() -> Option<Uuid> {
+    JOB_ID.try_with(|id| *id).ok()
+}
+
+/// Run a job with its id attached to every log line written while it runs.
+pub fn with_job_id<R>(id: Uuid, run: impl FnOnce() -> R) -> R {
+    JOB_ID.sync_scope(id, run)
+}
+
+/// Give every request an id, keeping the one the caller sent if there is one. The id is attached
+/// to every log line written while handling the request, forwarded by the proxy along with the
+/// other request headers, and echoed back on the response.
+pub async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    let id = match req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+    {
+        Some(id) => id.to_string(),
+        None => Uuid::new_v4().to_string(),
+    };
+    let header = HeaderValue::from_str(&id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    req.headers_mut().insert(REQUEST_ID_HEADER, header.clone());
+
+    let mut response = REQUEST_ID.scope(id, next.run(req)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
     Ok(response)
 }
 
+/// The W3C trace context header; see https://www.w3.org/TR/trace-context/. We only forward
+/// `traceparent`: `tracestate` passes through the proxy untouched, and we don't export spans.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Make sure every request carries a W3C `traceparent` header, starting a new trace if the caller
+/// didn't send us a usable one. The proxy forwards this header, so one trace id follows a request
+/// through every node it touches.
+pub async fn trace_context<B>(mut req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
+    let has_context = req
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(trace_id)
+        .is_some();
+    if !has_context {
+        let traceparent = format!("00-{}-{}-01", Uuid::new_v4().simple(), new_span_id());
+        req.headers_mut().insert(
+            TRACEPARENT,
+            HeaderValue::from_str(&traceparent).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        );
+    }
+    Ok(next.run(req).await)
+}
+
+/// The fields of a `traceparent` header value.
+struct TraceParent<'a> {
+    trace_id: &'a str,
+    flags: &'a str,
+}
+
+/// Parse a `traceparent` header value, returning None unless it has exactly the four fields of a
+/// version 00 header. Version `ff` is reserved as invalid.
+fn parse_traceparent(traceparent: &str) -> Option<TraceParent<'_>> {
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    let [version, trace_id, parent_id, flags] = parts[..] else {
+        return None;
+    };
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !is_hex(version, 2) || !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if version.eq_ignore_ascii_case("ff") {
+        return None;
+    }
+    // All-zero ids are explicitly invalid.
+    if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    Some(TraceParent { trace_id, flags })
+}
+
+/// Pull the trace id out of a `traceparent` header value, if it is well-formed.
+pub fn trace_id(traceparent: &str) -> Option<&str> {
+    parse_traceparent(traceparent).map(|parsed| parsed.trace_id)
+}
+
+/// Build the `traceparent` value to send on a relayed request: same trace, new parent span.
+pub fn child_traceparent(traceparent: &str) -> Option<String> {
+    let TraceParent { trace_id, flags } = parse_traceparent(traceparent)?;
+    Some(format!("00-{trace_id}-{}-{flags}", new_span_id()))
+}
+
+fn new_span_id() -> String {
+    Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
 pub async fn http_logging<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
     let method = req.method().to_owned();
     let uri = req.uri().to_owned();
+    let trace = req
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(trace_id)
+        .unwrap_or("-")
+        .to_string();
     let response = next.run(req).await;
     if let Some(proxied_from) = response.headers().get("Serval-Proxied-From") {
         log::info!(
-            "{} {} {} (via {})",
+            "{} {} {} (via {}); trace={trace}",
             response.status().as_u16(),
             method,
             uri,
             proxied_from.to_str().unwrap(),
         );
     } else {
-        log::info!("{} {} {}", response.status().as_u16(), method, uri);
+        log::info!(
+            "{} {} {}; trace={trace}",
+            response.status().as_u16(),
+            method,
+            uri
+        );
     }
     Ok(response)
 }
 
+/// Turn away clients that start work faster than `RATE_LIMIT_RPS` allows, with a 429 that says
+/// when to try again. Clients are told apart by address. Requests relayed by another mesh node
+/// were already counted by that node against the client that sent them, so they pass freely.
+pub async fn rate_limit<B>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(limiter) = RATE_LIMITER.get() else {
+        return next.run(req).await;
+    };
+    if req.headers().contains_key("Serval-Proxied-For") && is_mesh_peer(addr.ip()).await {
+        return next.run(req).await;
+    }
+
+    match limiter.check(addr.ip()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            metrics::increment_counter!("ratelimit:rejected");
+            log::info!("rate limited a client; addr={}", addr.ip());
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let error = ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "too many requests; slow down",
+            )
+            .with_details(serde_json::json!({ "retry_after_secs": retry_after }));
+            ([(RETRY_AFTER, retry_after.to_string())], error).into_response()
+        }
+    }
+}
+
+/// Rate limit requests for jobs, and pass everything else. For middleware stacks that see every
+/// path, such as the fallback's, where `rate_limit` can't be put on the job routes alone.
+pub async fn rate_limit_jobs<B>(
+    connect_info: ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if req.uri().path().starts_with("/v1/jobs") {
+        rate_limit(connect_info, req, next).await
+    } else {
+        next.run(req).await
+    }
+}
+
+async fn is_mesh_peer(ip: IpAddr) -> bool {
+    let Some(mesh) = MESH.get() else {
+        return false;
+    };
+    mesh.peers()
+        .await
+        .iter()
+        .any(|peer| peer.address() == ip || peer.http_address().map(|addr| addr.ip()) == Some(ip))
+}
+
+/// The tenant a request is acting for, from the `X-Serval-Tenant` header. Requests without one act
+/// on the shared namespace, and can see every tenant's job types; deployments that host several
+/// tenants are expected to set the header at whatever sits in front of the mesh.
+#[derive(Clone, Debug, Default)]
+pub struct Tenant(pub Option<String>);
+
+impl Tenant {
+    /// Scope a fully-qualified job name to this tenant.
+    pub fn scope(&self, fq_name: &str) -> String {
+        Manifest::scoped_name(self.0.as_deref(), fq_name)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tenant {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(TENANT_HEADER) else {
+            return Ok(Tenant(None));
+        };
+        // Tenants end up in storage keys, so keep them to a safe alphabet.
+        match value.to_str() {
+            Ok(tenant)
+                if !tenant.is_empty()
+                    && tenant.len() <= 64
+                    && tenant
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Ok(Tenant(Some(tenant.to_ascii_lowercase())))
+            }
+            _ => Err(ApiError::bad_request(
+                "invalid_tenant",
+                "tenant names may include only alphanumerics, - and _, up to 64 characters",
+            )),
+        }
+    }
+}
+
 /// Respond to ping. Useful for monitoring.
+#[utoipa::path(
+    get,
+    path = "/monitor/ping",
+    tag = "monitor",
+    responses((status = 200, description = "The node is up", body = String))
+)]
 pub async fn ping() -> String {
     metrics::increment_counter!("monitor:ping");
     "pong".to_string()
 }
 
-/// Report on node health.
-pub async fn monitor_status(_state: State<AppState>) -> impl IntoResponse {
+/// Report on node health, including enough about its load to tell whether it is saturated.
+#[utoipa::path(
+    get,
+    path = "/monitor/status",
+    tag = "monitor",
+    responses((status = 200, description = "This node's health and load", body = NodeStatus))
+)]
+pub async fn monitor_status(state: State<AppState>) -> impl IntoResponse {
     metrics::increment_counter!("monitor:status");
-    StatusCode::NOT_IMPLEMENTED
+    Json(NodeStatus {
+        instance_id: state.instance_id.to_string(),
+        load_average: resources::load_average(),
+        available_memory_bytes: resources::available_memory_bytes(),
+        running_jobs: state.running_jobs.load(Ordering::Relaxed),
+        runtimes: if state.should_run_jobs {
+            SUPPORTED_RUNTIMES.to_vec()
+        } else {
+            Vec::new()
+        },
+        wasm_features: if state.should_run_jobs {
+            SUPPORTED_WASM_FEATURES.to_vec()
+        } else {
+            Vec::new()
+        },
+        proxy_circuits: PROXY_BREAKER
+            .get()
+            .map(CircuitBreaker::status)
+            .unwrap_or_default(),
+        build: Some(build_info()),
+    })
+}
+
+/// Report which build of the agent this node is running.
+#[utoipa::path(
+    get,
+    path = "/monitor/version",
+    tag = "monitor",
+    responses((status = 200, description = "This node's build", body = BuildInfo))
+)]
+pub async fn monitor_version() -> Json<BuildInfo> {
+    metrics::increment_counter!("monitor:version");
+    Json(build_info())
+}
+
+/// Report on this node's view of the mesh, including any peers it expected to see but can't.
+#[utoipa::path(
+    get,
+    path = "/monitor/mesh",
+    tag = "monitor",
+    responses(
+        (status = 200, description = "This node's view of the mesh", body = MeshHealth),
+        (status = 503, description = "This node has not joined the mesh yet", body = ErrorResponse),
+    )
+)]
+pub async fn monitor_mesh() -> Result<Json<MeshHealth>, ApiError> {
+    metrics::increment_counter!("monitor:mesh");
+    let (Some(mesh), Some(watch)) = (MESH.get(), MESH_WATCH.get()) else {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "mesh_not_joined",
+            "this node has not joined the mesh yet",
+        ));
+    };
+    watch.observe(mesh.peers().await);
+    Ok(Json(watch.health()))
+}
+
+/// This build of the agent, as recorded by the build script.
+fn build_info() -> BuildInfo {
+    let git_sha = env!("SERVAL_GIT_SHA");
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: (!git_sha.is_empty()).then(|| git_sha.to_string()),
+        built_at: env!("SERVAL_BUILT_AT").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_parsing() {
+        let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(trace_id(parent), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert_eq!(trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-01"), None);
+        assert_eq!(
+            trace_id("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"),
+            None
+        );
+        assert_eq!(
+            trace_id("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+
+        let child = child_traceparent(parent).unwrap();
+        assert_eq!(trace_id(&child), trace_id(parent));
+        assert_ne!(child, parent);
+        assert!(child.ends_with("-01"));
+    }
+
+    #[test]
+    fn response_header_parsing() {
+        let headers =
+            parse_response_headers("X-Served-By={instance_id}, X-Team = mesh,", "abc").unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].0, "x-served-by");
+        assert_eq!(headers[0].1, "abc");
+        assert_eq!(headers[1].0, "x-team");
+        assert_eq!(headers[1].1, "mesh");
+
+        assert!(parse_response_headers("", "abc").unwrap().is_empty());
+        assert!(parse_response_headers("X-Served-By", "abc").is_err());
+        assert!(parse_response_headers("Bad Name=1", "abc").is_err());
+    }
+
+    #[test]
+    fn job_ids_are_scoped_to_the_run() {
+        let id = Uuid::new_v4();
+        assert_eq!(current_job_id(), None);
+        assert_eq!(with_job_id(id, current_job_id), Some(id));
+        assert_eq!(current_job_id(), None);
+    }
 }
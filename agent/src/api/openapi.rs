@@ -0,0 +1,110 @@
+// The agent's HTTP API, described as an OpenAPI document. Every node serves it at `/openapi.json`,
+// along with a Swagger UI for browsing it at `/docs`. A route added to the API should be added
+// here too, or it won't show up in either.
+
+use utils::mesh::ServalRole;
+use utils::structs::api::{
+    BuildInfo, CircuitState, CircuitStatus, ErrorDetail, ErrorResponse, ImportSummary, JobCallback,
+    JobFailureResponse, MeshHealth, MeshMember, MissingPeer, MissingRanges, NodeStatus, RunningJob,
+    StartUpload, UploadStarted,
+};
+use utils::structs::{FailureKind, JobFailure, Manifest, Runtime, WasmFeature};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::structures::*;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        super::ping,
+        super::monitor_status,
+        super::monitor_version,
+        super::monitor_mesh,
+        super::v1::mesh::list_peers,
+        super::v1::mesh::filter_peers,
+        super::v1::jobs::running,
+        super::v1::jobs::cancel_job,
+        super::v1::jobs::run_job,
+        super::v1::jobs::run_adhoc_job,
+        super::v1::jobs::run_inline_job,
+        super::v1::storage::list_manifests,
+        super::v1::storage::store_manifest,
+        super::v1::storage::get_manifest,
+        super::v1::storage::has_manifest,
+        super::v1::storage::list_versions,
+        super::v1::storage::get_manifest_version,
+        super::v1::storage::get_version_tag,
+        super::v1::storage::set_version_tag,
+        super::v1::storage::store_executable,
+        super::v1::storage::get_executable,
+        super::v1::storage::has_executable,
+        super::v1::storage::start_upload,
+        super::v1::storage::put_upload_chunk,
+        super::v1::storage::finalize_upload,
+        super::v1::storage::export_storage,
+        super::v1::storage::import_storage,
+        super::v1::storage::store_by_content_address,
+        super::v1::storage::get_by_content_address,
+        super::v1::storage::has_content_address,
+        super::v1::storage::patch_content_at_address,
+    ),
+    components(schemas(
+        ErrorResponse,
+        ErrorDetail,
+        JobFailureResponse,
+        JobFailure,
+        FailureKind,
+        RunningJob,
+        JobCallback,
+        NodeStatus,
+        BuildInfo,
+        CircuitStatus,
+        CircuitState,
+        MeshHealth,
+        MissingPeer,
+        MeshMember,
+        StartUpload,
+        UploadStarted,
+        MissingRanges,
+        ImportSummary,
+        Manifest,
+        Runtime,
+        WasmFeature,
+        ServalRole,
+    )),
+    tags(
+        (name = "jobs", description = "Running jobs, and seeing what is running"),
+        (name = "storage", description = "Manifests, executables, and content-addressed data"),
+        (name = "mesh", description = "The peers this node knows of"),
+        (name = "monitor", description = "Node health, for monitoring"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Mount the OpenAPI document at `/openapi.json` and a Swagger UI for it at `/docs`. Both describe
+/// the whole API, whichever parts of it this node handles itself.
+pub fn mount(router: ServalRouter) -> ServalRouter {
+    router.merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_covers_the_api() {
+        let doc = ApiDoc::openapi();
+        for path in [
+            "/monitor/status",
+            "/v1/mesh/peers/{role}",
+            "/v1/jobs/{name}/run",
+            "/v1/running/{id}",
+            "/v1/storage/manifests/{name}/executable/{version}",
+            "/v1/storage/data/{address}",
+        ] {
+            assert!(doc.paths.paths.contains_key(path), "{path} is missing");
+        }
+        assert!(doc.to_json().is_ok());
+    }
+}
@@ -1,26 +1,69 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use axum::body::{Body, Bytes};
-use axum::extract::{Path, State};
-use axum::http::{Request, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::{any, get, post};
+use axum::extract::{Multipart, Path, Query, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, delete, get, post};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use engine::errors::ServalEngineError;
+use engine::executor::Executor;
 use engine::ServalEngine;
+use jsonschema::JSONSchema;
+use reqwest::Url;
+use serde::Deserialize;
+use ssri::Integrity;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use utils::errors::{ApiError, ServalError};
 use utils::mesh::ServalRole;
-use utils::structs::Job;
+use utils::structs::api::{
+    ErrorResponse, JobCallback, JobFailureResponse, RunningJob, CACHE_HEADER, EXIT_CODE_HEADER,
+    OUTPUT_LOCATION_HEADER, STORAGE_POINTER_SCHEME,
+};
+use utils::structs::{FailureKind, Job, JobFailure, Manifest, ManifestOverrides, Runtime};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 
-use crate::storage::STORAGE;
+use crate::api::{rate_limit, with_job_id, Tenant};
+use crate::cache::{CachedResult, ResultCache};
+use crate::callback::{CallbackPolicy, CALLBACK_POLICY};
+use crate::storage::{Storage, STORAGE};
 use crate::structures::*;
 
 /// Mount all jobs endpoint handlers onto the passed-in router.
 pub fn mount(router: ServalRouter) -> ServalRouter {
     router
-        .route("/v1/jobs", get(running)) // TODO
-        .route("/v1/jobs/:name/run", post(run_job)) // has an input payload; TODO options (needs design)
+        .route("/v1/jobs", get(running))
+        .route(
+            "/v1/jobs/:name/run",
+            post(run_job).layer(middleware::from_fn(rate_limit)),
+        )
+        .route(
+            "/v1/jobs/run",
+            post(run_inline_job).layer(middleware::from_fn(rate_limit)),
+        )
+        .route(
+            "/v1/jobs/run-adhoc",
+            post(run_adhoc_job).layer(middleware::from_fn(rate_limit)),
+        )
+        // Not under /v1/jobs, where it would shadow running a job named `running`.
+        .route("/v1/running/:id", delete(cancel_job))
 }
 
 /// Mount a handler that relays all job-running requests to another node.
 pub fn mount_proxy(router: ServalRouter) -> ServalRouter {
-    router.route("/v1/jobs/*rest", any(proxy))
+    router.route(
+        "/v1/jobs/*rest",
+        any(proxy).layer(middleware::from_fn(rate_limit)),
+    )
 }
 
 /// Relay all storage requests to a node that can handle them.
@@ -36,36 +79,306 @@ async fn proxy(State(state): State<AppState>, mut request: Request<Body>) -> imp
     } else {
         // Welp, not much we can do
         metrics::increment_counter!("proxy:error");
-        (
-            StatusCode::SERVICE_UNAVAILABLE,
+        ApiError::unavailable(
+            "peer_unavailable",
             "Peer with the job runner role not available",
         )
-            .into_response()
+        .into_response()
     }
 }
 
-/// Get running jobs
-async fn running(_state: State<AppState>) -> impl IntoResponse {
-    StatusCode::NOT_IMPLEMENTED
+/// Which running jobs to list.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RunningFilter {
+    /// Only list jobs carrying this label, given as `key=value`.
+    label: Option<String>,
 }
 
-/// This is the main worker endpoint. It accepts incoming jobs and runs them.
+/// List the jobs this node is running right now, longest-running first.
+#[utoipa::path(
+    get,
+    path = "/v1/jobs",
+    tag = "jobs",
+    params(
+        RunningFilter,
+    ),
+    responses(
+        (status = 200, description = "The jobs this node is running", body = [RunningJob]),
+        (status = 400, description = "The label filter is malformed", body = ErrorResponse),
+    )
+)]
+async fn running(
+    Query(filter): Query<RunningFilter>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("jobs:running");
+    let label = match filter.label.as_deref().map(|label| label.split_once('=')) {
+        None => None,
+        Some(Some(label)) => Some(label),
+        Some(None) => {
+            return ApiError::bad_request("invalid_label", "label filters look like key=value")
+                .into_response()
+        }
+    };
+    let has_label = |job: &InFlightJob| match label {
+        Some((key, value)) => job.labels.get(key).map(String::as_str) == Some(value),
+        None => true,
+    };
+
+    let now = SystemTime::now();
+    let mut jobs: Vec<RunningJob> = state
+        .in_flight
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, job)| has_label(job))
+        .map(|(id, job)| RunningJob {
+            id: *id,
+            name: job.name.clone(),
+            started_at_secs: job
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            elapsed_ms: now
+                .duration_since(job.started_at)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            input_bytes: job.input_bytes,
+            labels: job.labels.clone(),
+        })
+        .collect();
+    jobs.sort_by_key(|job| std::cmp::Reverse(job.elapsed_ms));
+    Json(jobs).into_response()
+}
+
+/// Stop a job that this node is running right now. The job's run request fails with a
+/// `cancelled` failure, carrying whatever output the job had produced.
+#[utoipa::path(
+    delete,
+    path = "/v1/running/{id}",
+    tag = "jobs",
+    params(
+        ("id" = Uuid, Path, description = "The running job's id"),
+    ),
+    responses(
+        (status = 202, description = "The job is being cancelled", body = String),
+        (status = 404, description = "This node is not running that job", body = ErrorResponse),
+    )
+)]
+async fn cancel_job(Path(id): Path<Uuid>, State(state): State<AppState>) -> impl IntoResponse {
+    metrics::increment_counter!("run:cancel");
+    let Some(handle) = state
+        .in_flight
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|job| job.cancel.clone())
+    else {
+        return ServalError::JobNotFound(id.to_string()).into_response();
+    };
+    handle.cancel();
+    log::info!("cancelled job; id={id}");
+    (StatusCode::ACCEPTED, format!("cancelling job {id}")).into_response()
+}
+
+/// How long a client turned away by a busy runner should wait before trying again.
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+/// Options a caller may pass as query parameters when running a job.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RunOptions {
+    /// A URL to POST the job's outcome to once it has finished.
+    callback_url: Option<String>,
+    /// A JSON object of manifest fields to change for this run only; see `ManifestOverrides`.
+    overrides: Option<String>,
+    /// Pass output along even if it doesn't match the manifest's output schema. For debugging.
+    #[serde(default)]
+    skip_output_schema: bool,
+    /// A JSON object of labels to attach to this run, e.g. `{"team":"birds"}`.
+    labels: Option<String>,
+}
+
+/// This is the main worker endpoint. It accepts incoming jobs and runs them. Jobs are named as
+/// `name`, which runs the version in the stored manifest, or as `name@version` or `name@tag`.
+#[utoipa::path(
+    post,
+    path = "/v1/jobs/{name}/run",
+    tag = "jobs",
+    params(
+        ("name" = String, Path, description = "The job's name, with `@version` or `@tag` if wanted"),
+        RunOptions,
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The job's input"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The job's output",
+            body = String,
+            content_type = "application/octet-stream",
+            headers(
+                ("Serval-Exit-Code" = i32, description = "The code the job exited with"),
+                ("Serval-Cache" = String, description = "`hit` if the result was cached")
+            )
+        ),
+        (status = 400, description = "The job was given bad input or bad options", body = JobFailureResponse),
+        (status = 404, description = "No such job", body = ErrorResponse),
+        (status = 413, description = "The input is bigger than the job accepts", body = ErrorResponse),
+        (status = 422, description = "The job ran, but failed", body = JobFailureResponse),
+        (status = 429, description = "The caller is running jobs too quickly", body = ErrorResponse),
+        (status = 503, description = "Every slot for running jobs is taken", body = ErrorResponse),
+    )
+)]
 async fn run_job(
     Path(name): Path<String>,
+    Query(options): Query<RunOptions>,
+    tenant: Tenant,
     state: State<AppState>,
     input: Bytes,
 ) -> impl IntoResponse {
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "unable to locate a storage node on the mesh".to_string()).into_response();
+        return ApiError::unavailable(
+            "storage_unavailable",
+            "unable to locate a storage node on the mesh",
+        )
+        .into_response();
+    };
+    // The name may pin a version, or a tag pointing at one: `name@1.2.0` or `name@stable`.
+    let (name, reference) = match name.split_once('@') {
+        Some((name, reference)) => (name.to_string(), Some(reference.to_string())),
+        None => (name, None),
+    };
+    let name = tenant.scope(&name);
+
+    // A pinned version runs with the manifest it was stored with, not the latest one.
+    let mut manifest = match reference {
+        Some(reference) => match storage.manifest_for_reference(&name, &reference).await {
+            Ok(manifest) => manifest,
+            Err(e) => return e.into_response(),
+        },
+        None => match storage.manifest(&name).await {
+            Ok(manifest) => manifest,
+            Err(_) => return ServalError::ManifestNotFound(name).into_response(),
+        },
+    };
+    if let Some(overrides) = options.overrides.as_deref() {
+        match serde_json::from_str::<ManifestOverrides>(overrides) {
+            Ok(overrides) => manifest.apply_overrides(&overrides),
+            Err(e) => {
+                return ApiError::bad_request(
+                    "invalid_overrides",
+                    format!("overrides may only set resource and time limits: {e}"),
+                )
+                .into_response()
+            }
+        }
+    }
+    let labels = match options.labels.as_deref().map(serde_json::from_str) {
+        None => BTreeMap::new(),
+        Some(Ok(labels)) => labels,
+        Some(Err(e)) => {
+            return ApiError::bad_request(
+                "invalid_labels",
+                format!("labels must be a JSON object of strings: {e}"),
+            )
+            .into_response()
+        }
+    };
+    if let Err(e) = Job::check_labels(&labels) {
+        return e.into_response();
+    }
+    let policy = CALLBACK_POLICY.get_or_init(CallbackPolicy::default);
+    let callback_url = options
+        .callback_url
+        .as_deref()
+        .map(|url| policy.check_url(url));
+    let callback_url = match callback_url {
+        None => None,
+        Some(Ok(url)) => Some(url),
+        Some(Err(e)) => {
+            return ApiError::bad_request(
+                "invalid_callback_url",
+                format!("callback_url is not allowed: {e}"),
+            )
+            .into_response()
+        }
     };
 
-    let Ok(manifest) = storage.manifest(&name).await else {
-        return (StatusCode::NOT_FOUND, "no manifest of that name found").into_response();
+    if let Some(limit) = manifest.max_input_bytes() {
+        if input.len() as u64 > limit {
+            return input_too_large(input.len() as u64, limit);
+        }
+    }
+
+    // Pure jobs give the same answer for the same input, so there's no need to run them twice.
+    // Their results are only cached when we know which executable they come from.
+    let cache_key = if manifest.pure() {
+        let signed = manifest
+            .executable_integrity()
+            .and_then(|integrity| integrity.parse::<Integrity>().ok());
+        let executable = match signed {
+            Some(integrity) => Some(integrity),
+            None => storage
+                .executable_integrity(&name, manifest.version())
+                .await
+                .ok(),
+        };
+        executable.map(|executable| ResultCache::key(&name, &manifest, &executable, &input))
+    } else {
+        None
     };
+    if let Some(cached) = cache_key.as_ref().and_then(|key| state.result_cache.get(key)) {
+        metrics::increment_counter!("run:cache_hit");
+        log::info!("serving cached result; name={name}; code={}", cached.code);
+        if let Some(callback_url) = callback_url {
+            let callback = JobCallback {
+                job_id: Uuid::new_v4(),
+                name: manifest.fq_name(),
+                exit_code: Some(cached.code),
+                failure: None,
+                labels,
+            };
+            tokio::spawn(deliver_callback(callback_url, callback));
+        }
+        let headers = [
+            (CACHE_HEADER, "hit".to_string()),
+            (EXIT_CODE_HEADER, cached.code.to_string()),
+        ];
+        let response = (StatusCode::OK, headers, cached.output).into_response();
+        if manifest.output_to_storage() {
+            return store_output(response, storage).await;
+        }
+        return response;
+    }
 
-    let Ok(executable) = storage.executable_as_bytes(&name, manifest.version()).await else {
-        return (StatusCode::NOT_FOUND,
-            format!("no executable found for manifest;  name={name}; version={}", manifest.version())).into_response();
+    let _slot = match job_slot(&name) {
+        Ok(slot) => slot,
+        Err(busy) => return busy,
+    };
+
+    let executable = match storage.executable_as_bytes(&name, manifest.version()).await {
+        Ok(executable) => executable,
+        Err(ServalError::IntegrityMismatch(key)) => {
+            // Most likely a corrupted transfer, so worth retrying; we just can't run these bytes.
+            let failure = JobFailure::new(
+                FailureKind::Internal,
+                format!("integrity mismatch for executable {key}; refusing to run it"),
+            );
+            metrics::increment_counter!("run:error", "kind" => failure.kind.to_string());
+            log::warn!("{}", failure.message);
+            return failure_response(failure, String::new());
+        }
+        Err(_) => {
+            return ServalError::ExecutableNotFound(format!("{name}@{}", manifest.version()))
+                .into_response();
+        }
     };
 
     if executable.is_empty() {
@@ -75,18 +388,381 @@ async fn run_job(
             manifest.version()
         );
         log::warn!("{warning}");
-        return (StatusCode::NOT_FOUND, warning).into_response();
+        return ApiError::not_found("empty_executable", warning).into_response();
     }
 
-    let job = Job::new(manifest, executable, input.to_vec());
+    // Load the output schema now, since checking output against it happens mid-run.
+    let output_schema = match manifest.output_schema() {
+        Some(_) if options.skip_output_schema => {
+            log::info!("not checking output against its schema; name={name}");
+            None
+        }
+        Some(address) => match load_output_schema(storage, address).await {
+            Ok(schema) => Some(schema),
+            Err(e) => {
+                return ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "output_schema_unavailable",
+                    format!("unable to load the output schema for {name}: {e}"),
+                )
+                .into_response()
+            }
+        },
+        None => None,
+    };
+    // Output that wasn't checked mustn't be served to runs that would have checked it.
+    let checks_skipped = options.skip_output_schema && manifest.output_schema().is_some();
+    let cache_key = cache_key.filter(|_| !checks_skipped);
+
+    let job = Job::new(manifest, executable, input.to_vec()).with_labels(labels);
     log::info!(
-        "received Wasm job; name={}; executable length={}; input length={}; id={}",
+        "received Wasm job; name={}; executable length={}; input length={}; id={}; labels={:?}",
+        job.manifest().fq_name(),
+        job.executable().len(),
+        input.len(),
+        job.id(),
+        job.labels()
+    );
+
+    let running = RunningCount::start(&state);
+    let (response, outcome) = with_job_id(*job.id(), || {
+        execute_job(&job, &state, cache_key, output_schema.as_ref())
+    });
+    drop(running);
+
+    if let Some(callback_url) = callback_url {
+        let (exit_code, failure) = match outcome {
+            Ok(code) => (Some(code), None),
+            Err(failure) => (None, Some(failure)),
+        };
+        let callback = JobCallback {
+            job_id: *job.id(),
+            name: job.manifest().fq_name(),
+            exit_code,
+            failure,
+            labels: job.labels().clone(),
+        };
+        tokio::spawn(deliver_callback(callback_url, callback));
+    }
+
+    if job.manifest().output_to_storage() {
+        return store_output(response, storage).await;
+    }
+    response
+}
+
+/// Take a slot to run a job in, if this node limits how many it runs at once. With every slot
+/// taken, say so now rather than leaving the job to wait where nobody can see.
+fn job_slot(name: &str) -> Result<Option<SemaphorePermit<'static>>, Response> {
+    match JOB_SLOTS.get().map(Semaphore::try_acquire) {
+        Some(Ok(permit)) => Ok(Some(permit)),
+        Some(Err(_)) => {
+            metrics::increment_counter!("run:busy");
+            log::info!("turning away a job; every slot is taken; name={name}");
+            let details = serde_json::json!({ "retry_after_secs": BUSY_RETRY_AFTER_SECS });
+            let error =
+                ApiError::unavailable("runner_busy", "this node is running all the jobs it can")
+                    .with_details(details);
+            Err(([(RETRY_AFTER, BUSY_RETRY_AFTER_SECS.to_string())], error).into_response())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Counts a job as running for as long as it is held, so that the count comes back down however
+/// the run ends.
+struct RunningCount(Arc<AtomicUsize>);
+
+impl RunningCount {
+    fn start(state: &RunnerState) -> Self {
+        state.running_jobs.fetch_add(1, Ordering::Relaxed);
+        Self(state.running_jobs.clone())
+    }
+}
+
+impl Drop for RunningCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Run a Wasm executable sent along with its input, storing neither. The body is multipart, with
+/// an `executable` part, an optional `input` part, and an optional `name` part to label the run.
+/// Nothing is cached and no manifest applies, so the job runs under this node's default limits.
+#[utoipa::path(
+    post,
+    path = "/v1/jobs/run-adhoc",
+    tag = "jobs",
+    request_body(
+        content = String,
+        content_type = "multipart/form-data",
+        description = "An `executable` part, and optional `input` and `name` parts"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The job's output",
+            body = String,
+            content_type = "application/octet-stream",
+            headers(
+                ("Serval-Exit-Code" = i32, description = "The code the job exited with"),
+                ("Serval-Cache" = String, description = "`hit` if the result was cached")
+            )
+        ),
+        (status = 400, description = "The job was given bad input or bad options", body = JobFailureResponse),
+        (status = 422, description = "The job ran, but failed", body = JobFailureResponse),
+        (status = 429, description = "The caller is running jobs too quickly", body = ErrorResponse),
+        (status = 503, description = "Every slot for running jobs is taken", body = ErrorResponse),
+    )
+)]
+async fn run_adhoc_job(state: State<AppState>, mut multipart: Multipart) -> Response {
+    let mut name = String::from("adhoc");
+    let mut executable = Bytes::new();
+    let mut input = Bytes::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                return ApiError::bad_request("invalid_multipart", e.to_string()).into_response()
+            }
+        };
+        let part = field.name().unwrap_or_default().to_string();
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return ApiError::bad_request("invalid_multipart", e.to_string()).into_response()
+            }
+        };
+        match part.as_str() {
+            "name" => name = String::from_utf8_lossy(&bytes).into_owned(),
+            "executable" => executable = bytes,
+            "input" => input = bytes,
+            _ => {
+                return ApiError::bad_request(
+                    "invalid_multipart",
+                    format!("unexpected part `{part}`; send executable, input, and name"),
+                )
+                .into_response()
+            }
+        }
+    }
+    if executable.is_empty() {
+        return ApiError::bad_request("empty_executable", "no executable was sent to run")
+            .into_response();
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic() || c == '_') {
+        return ApiError::bad_request(
+            "invalid_name",
+            "ad hoc job names may include only letters plus _ (underscore)",
+        )
+        .into_response();
+    }
+
+    let _slot = match job_slot(&name) {
+        Ok(slot) => slot,
+        Err(busy) => return busy,
+    };
+
+    let job = Job::new(Manifest::ad_hoc(&name), executable.to_vec(), input.to_vec());
+    metrics::increment_counter!("run:adhoc");
+    log::info!(
+        "received ad hoc Wasm job; name={}; executable length={}; input length={}; id={}",
         job.manifest().fq_name(),
         job.executable().len(),
         input.len(),
         job.id()
     );
 
+    let _running = RunningCount::start(&state);
+    let (response, _) = with_job_id(*job.id(), || execute_job(&job, &state, None, None));
+    response
+}
+
+/// Respond to a run whose input is bigger than its job accepts.
+fn input_too_large(len: u64, limit: u64) -> Response {
+    let failure = JobFailure::new(
+        FailureKind::LimitExceeded,
+        format!("input is {len} bytes; this job accepts at most {limit}"),
+    );
+    metrics::increment_counter!("run:error", "kind" => failure.kind.to_string());
+    let body = JobFailureResponse {
+        failure,
+        stdout: String::new(),
+        stderr: String::new(),
+    };
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+}
+
+/// A run request that carries its job's input inline, base64-encoded, for clients that would
+/// rather send a single JSON document than a raw body.
+#[derive(Debug, Deserialize, ToSchema)]
+struct InlineRun {
+    /// The job to run, named as for `run_job`.
+    name: String,
+    input_b64: String,
+}
+
+/// Run a job named in a JSON body, on the base64-encoded input alongside it. Query parameters are
+/// the same as for `run_job`, which this hands off to once the input is decoded.
+#[utoipa::path(
+    post,
+    path = "/v1/jobs/run",
+    tag = "jobs",
+    params(
+        RunOptions,
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    request_body(content = inline(InlineRun)),
+    responses(
+        (
+            status = 200,
+            description = "The job's output",
+            body = String,
+            content_type = "application/octet-stream",
+            headers(
+                ("Serval-Exit-Code" = i32, description = "The code the job exited with"),
+                ("Serval-Cache" = String, description = "`hit` if the result was cached")
+            )
+        ),
+        (status = 400, description = "The job was given bad input or bad options", body = JobFailureResponse),
+        (status = 404, description = "No such job", body = ErrorResponse),
+        (status = 413, description = "The input is bigger than the job accepts", body = ErrorResponse),
+        (status = 422, description = "The job ran, but failed", body = JobFailureResponse),
+        (status = 429, description = "The caller is running jobs too quickly", body = ErrorResponse),
+        (status = 503, description = "Every slot for running jobs is taken", body = ErrorResponse),
+    )
+)]
+async fn run_inline_job(
+    Query(options): Query<RunOptions>,
+    tenant: Tenant,
+    state: State<AppState>,
+    Json(body): Json<InlineRun>,
+) -> Response {
+    // Turn away input that is too big for the job before spending the time to decode it. This
+    // checks the stored version's limit; `run_job` checks again against the version it runs.
+    let (name, _) = body.name.split_once('@').unwrap_or((&body.name, ""));
+    if let Some(storage) = STORAGE.get() {
+        if let Ok(mut manifest) = storage.manifest(&tenant.scope(name)).await {
+            let overrides = options.overrides.as_deref().map(serde_json::from_str);
+            if let Some(Ok(overrides)) = overrides {
+                manifest.apply_overrides(&overrides);
+            }
+            let len = base64_decoded_len(&body.input_b64);
+            match manifest.max_input_bytes() {
+                Some(limit) if len > limit => return input_too_large(len, limit),
+                _ => {}
+            }
+        }
+    }
+
+    let input = match BASE64.decode(&body.input_b64) {
+        Ok(input) => input,
+        Err(e) => {
+            return ApiError::bad_request(
+                "invalid_input_encoding",
+                format!("input_b64 is not valid base64: {e}"),
+            )
+            .into_response()
+        }
+    };
+    run_job(
+        Path(body.name),
+        Query(options),
+        tenant,
+        state,
+        Bytes::from(input),
+    )
+    .await
+    .into_response()
+}
+
+/// How many bytes the given base64 decodes to, without decoding it.
+fn base64_decoded_len(encoded: &str) -> u64 {
+    let padding = encoded.bytes().rev().take_while(|b| *b == b'=').count();
+    (encoded.len() * 3 / 4).saturating_sub(padding) as u64
+}
+
+/// Move a successful job's output into the blob store, responding with a pointer to it in place
+/// of the output itself. If the output can't be stored, it is sent back as usual.
+async fn store_output(response: Response, storage: &Storage) -> Response {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let output = match hyper::body::to_bytes(body).await {
+        Ok(output) => output,
+        Err(e) => {
+            log::warn!("unable to read job output for storage; error={e}");
+            return ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "output_unreadable",
+                format!("unable to read job output for storage: {e}"),
+            )
+            .into_response();
+        }
+    };
+    let integrity = match storage.store_by_integrity(&output).await {
+        Ok(integrity) => integrity,
+        Err(e) => {
+            log::warn!("unable to store job output; sending it inline; error={e}");
+            return (parts, output).into_response();
+        }
+    };
+
+    let pointer = format!("{STORAGE_POINTER_SCHEME}{integrity}");
+    log::info!(
+        "stored job output; size={}; integrity={integrity}",
+        output.len()
+    );
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Ok(value) = HeaderValue::from_str(&pointer) {
+        parts.headers.insert(OUTPUT_LOCATION_HEADER, value);
+    }
+    (parts, pointer).into_response()
+}
+
+/// Fetch the JSON Schema stored at the given integrity address and compile it.
+async fn load_output_schema(storage: &Storage, address: &str) -> Result<JSONSchema, String> {
+    let integrity: Integrity = address
+        .parse()
+        .map_err(|e| format!("{address} is not an integrity address: {e}"))?;
+    let bytes = storage
+        .data_by_integrity(integrity)
+        .await
+        .map_err(|e| e.to_string())?;
+    let schema: serde_json::Value =
+        serde_json::from_slice(&bytes).map_err(|e| format!("schema is not JSON: {e}"))?;
+    JSONSchema::compile(&schema).map_err(|e| format!("schema is invalid: {e}"))
+}
+
+/// Check a job's output against its output schema, describing the first few ways it falls short.
+fn check_output_schema(schema: &JSONSchema, output: &[u8]) -> Result<(), String> {
+    const MAX_REPORTED: usize = 3;
+
+    let output: serde_json::Value =
+        serde_json::from_slice(output).map_err(|e| format!("output is not JSON: {e}"))?;
+    schema.validate(&output).map_err(|errors| {
+        errors
+            .take(MAX_REPORTED)
+            .map(|e| format!("{e} (at {})", e.instance_path))
+            .collect::<Vec<_>>()
+            .join("; ")
+    })
+}
+
+/// Run a job to completion, responding with both the HTTP response for the caller and the
+/// outcome: the exit code if the job ran, or a description of why it failed. Given a cache key,
+/// a job that runs to completion has its result remembered under that key. Given a schema, output
+/// that doesn't match it fails the job.
+fn execute_job(
+    job: &Job,
+    state: &AppState,
+    cache_key: Option<String>,
+    output_schema: Option<&JSONSchema>,
+) -> (Response, Result<i32, JobFailure>) {
     let start = std::time::Instant::now();
 
     // What we'll do later is accept this job for processing and send it to a thread or something.
@@ -100,21 +776,109 @@ async fn run_job(
     );
 
     let extensions = state.extensions.clone();
+    let module_cache = state.module_cache.as_ref();
 
-    let Ok(mut engine) = ServalEngine::new(extensions) else {
-        return (StatusCode::INTERNAL_SERVER_ERROR, "unable to create wasm engine").into_response();
+    let wasm_features = job.manifest().wasm_features();
+    let mut engine = match ServalEngine::with_wasm_features(extensions, module_cache, wasm_features)
+    {
+        Ok(engine) => engine,
+        Err(err) => {
+            let failure = JobFailure::from(&err);
+            return (failure_response(failure.clone(), String::new()), Err(failure));
+        }
     };
+    let max_memory_bytes = job
+        .manifest()
+        .max_memory_bytes()
+        .or(state.max_memory_bytes);
+    engine.set_memory_limit(max_memory_bytes.map(|bytes| bytes as usize));
+    let max_duration_ms = match (job.manifest().max_duration_ms(), state.max_duration_ms) {
+        (Some(asked), Some(cap)) => Some(asked.min(cap)),
+        (asked, cap) => asked.or(cap),
+    };
+    engine.set_time_limit(max_duration_ms.map(Duration::from_millis));
+    engine.set_pipeline(job.manifest().pipeline().to_vec());
+    engine.set_deterministic(job.manifest().deterministic());
+    if job.manifest().needs_shared_data() {
+        let Some(path) = &state.shared_data_path else {
+            let failure = JobFailure::from(&ServalEngineError::SharedDataUnavailable);
+            return (failure_response(failure.clone(), String::new()), Err(failure));
+        };
+        engine.set_shared_data(Some(path.clone()));
+    }
+
+    // Until the job finishes, it can be listed and cancelled by id.
+    let in_flight = InFlightJob {
+        name: job.manifest().fq_name(),
+        started_at: SystemTime::now(),
+        input_bytes: job.input().len() as u64,
+        labels: job.labels().clone(),
+        cancel: engine.cancel_handle(),
+    };
+    state.in_flight.lock().unwrap().insert(*job.id(), in_flight);
 
     // todo: verify that the user who submitted the job is actually authorized for all of the
     // permissions that are listed in the manifest. If not, return a 403 error.
-    let result = engine.execute(
-        job.executable(),
-        job.input(),
-        job.manifest().required_permissions(),
-    );
+    let permissions = job.manifest().required_permissions();
+    // Preprocessors are Wasm extensions, whatever runtime the job itself is for.
+    let input = match job.manifest().preprocessor() {
+        Some(preprocessor) => engine.preprocess(preprocessor, job.input()).map(Cow::Owned),
+        None => Ok(Cow::Borrowed(job.input())),
+    };
+    // This is where a job is handed to the executor for its runtime.
+    let executor: &mut dyn Executor = match job.manifest().runtime() {
+        Runtime::Wasm => &mut engine,
+    };
+    let result = input.and_then(|input| executor.execute(job.executable(), &input, permissions));
+    state.in_flight.lock().unwrap().remove(job.id());
 
     match result {
         Ok(result) => {
+            // Zero exit status code is a success, as is any code the manifest says is meaningful.
+            if result.code != 0 && !job.manifest().acceptable_exit_codes().contains(&result.code) {
+                let mut failure = JobFailure::new(
+                    FailureKind::NonZeroExit,
+                    format!("job exited with code {}", result.code),
+                );
+                failure.exit_code = Some(result.code);
+                metrics::increment_counter!("run:error", "kind" => failure.kind.to_string());
+                log::info!("job failed; job={}; code={}", job.id(), result.code);
+                let stderr = String::from_utf8_lossy(&result.stderr).to_string();
+                return (failure_response(failure.clone(), stderr), Err(failure));
+            }
+
+            let output_len = result.stdout.len();
+            if let Some(limit) = job.manifest().max_output_bytes() {
+                if output_len as u64 > limit {
+                    let failure = JobFailure::new(
+                        FailureKind::LimitExceeded,
+                        format!("job produced {output_len} bytes of output; its limit is {limit}"),
+                    );
+                    metrics::increment_counter!("run:error", "kind" => failure.kind.to_string());
+                    log::info!(
+                        "job output over limit; job={}; output length={output_len}; limit={limit}",
+                        job.id()
+                    );
+                    return (failure_response(failure.clone(), String::new()), Err(failure));
+                }
+            }
+
+            if let Some(schema) = output_schema {
+                if let Err(reason) = check_output_schema(schema, &result.stdout) {
+                    let failure = JobFailure::new(
+                        FailureKind::SchemaViolation,
+                        format!("output does not match its schema: {reason}"),
+                    );
+                    metrics::increment_counter!("run:error", "kind" => failure.kind.to_string());
+                    log::info!(
+                        "job output failed its schema; job={}; reason={reason}",
+                        job.id()
+                    );
+                    let stderr = String::from_utf8_lossy(&result.stderr).to_string();
+                    return (failure_response(failure.clone(), stderr), Err(failure));
+                }
+            }
+
             // We're not doing anything with stderr here.
             metrics::increment_counter!("run:success");
             metrics::histogram!("run:latency", start.elapsed().as_millis() as f64);
@@ -124,24 +888,198 @@ async fn run_job(
                 result.code,
                 start.elapsed().as_millis()
             );
-            if result.code == 0 {
-                // Zero exit status code is a success.
-                (StatusCode::OK, result.stdout).into_response()
-            } else {
-                (StatusCode::OK, result.stderr).into_response()
+            let cacheable = cache_key.is_some();
+            if let Some(key) = cache_key {
+                state.result_cache.insert(
+                    key,
+                    CachedResult {
+                        code: result.code,
+                        output: result.stdout.clone(),
+                    },
+                );
+            }
+            let mut response = (StatusCode::OK, result.stdout).into_response();
+            let headers = response.headers_mut();
+            headers.insert(EXIT_CODE_HEADER, HeaderValue::from(result.code));
+            if cacheable {
+                headers.insert(CACHE_HEADER, HeaderValue::from_static("miss"));
+            }
+            (response, Ok(result.code))
+        }
+        Err(err) => {
+            let (stdout, stderr) = match &err {
+                ServalEngineError::ExecutionError { stderr, .. }
+                | ServalEngineError::PipelineStageFailed { stderr, .. } => {
+                    (String::new(), String::from_utf8_lossy(stderr).to_string())
+                }
+                ServalEngineError::Cancelled { stdout, stderr }
+                | ServalEngineError::TimedOut { stdout, stderr, .. } => (
+                    String::from_utf8_lossy(stdout).to_string(),
+                    String::from_utf8_lossy(stderr).to_string(),
+                ),
+                _ => (String::new(), String::new()),
+            };
+            let failure = JobFailure::from(&err);
+            metrics::increment_counter!("run:error", "kind" => failure.kind.to_string());
+            log::info!(
+                "job failed; job={}; kind={}; message={}",
+                job.id(),
+                failure.kind,
+                failure.message
+            );
+            let body = JobFailureResponse {
+                failure: failure.clone(),
+                stdout,
+                stderr,
+            };
+            (failure_body_response(body), Err(failure))
+        }
+    }
+}
+
+/// POST the outcome of a job to the URL the submitter asked us to notify, retrying with
+/// exponential backoff. There is nowhere to record delivery yet, so we log and count it.
+async fn deliver_callback(url: Url, callback: JobCallback) {
+    const MAX_ATTEMPTS: u32 = 4;
+
+    let policy = CALLBACK_POLICY.get_or_init(CallbackPolicy::default);
+    let addrs = match policy.resolve(&url).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            metrics::increment_counter!("callback:refused");
+            log::warn!(
+                "refusing job callback; job={}; url={url}; reason={e}",
+                callback.job_id
+            );
+            return;
+        }
+    };
+    // Send to the addresses we checked, and don't follow redirects to ones we didn't.
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    if let Some(host) = url.host_str() {
+        builder = builder.resolve_to_addrs(host, &addrs);
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            log::warn!(
+                "unable to build a callback client; job={}; err={e}",
+                callback.job_id
+            );
+            return;
+        }
+    };
+    let mut delay = Duration::from_secs(1);
+    for attempt in 1..=MAX_ATTEMPTS {
+        match client.post(url.clone()).json(&callback).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                metrics::increment_counter!("callback:delivered");
+                log::info!(
+                    "job callback delivered; job={}; url={url}; attempt={attempt}",
+                    callback.job_id
+                );
+                return;
             }
+            Ok(resp) => {
+                log::info!(
+                    "job callback rejected; job={}; url={url}; attempt={attempt}; status={}",
+                    callback.job_id,
+                    resp.status()
+                );
+            }
+            Err(err) => {
+                log::info!(
+                    "job callback failed; job={}; url={url}; attempt={attempt}; err={err}",
+                    callback.job_id
+                );
+            }
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    metrics::increment_counter!("callback:undelivered");
+    log::warn!(
+        "giving up on job callback; job={}; url={url}; attempts={MAX_ATTEMPTS}",
+        callback.job_id
+    );
+}
+
+/// Respond with a structured description of why a job failed. The HTTP status is only a coarse
+/// hint; the failure kind in the body is what callers should switch on.
+fn failure_response(failure: JobFailure, stderr: String) -> Response {
+    failure_body_response(JobFailureResponse {
+        failure,
+        stdout: String::new(),
+        stderr,
+    })
+}
+
+/// Respond with a failure body we've already put together.
+fn failure_body_response(body: JobFailureResponse) -> Response {
+    let status = match body.failure.kind {
+        FailureKind::BadInput => StatusCode::BAD_REQUEST,
+        FailureKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        FailureKind::Cancelled => StatusCode::CONFLICT,
+        FailureKind::EngineTrap
+        | FailureKind::Timeout
+        | FailureKind::MissingCapability
+        | FailureKind::LimitExceeded
+        | FailureKind::NonZeroExit
+        | FailureKind::SchemaViolation => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+    };
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_is_checked_against_its_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "count": { "type": "integer" } },
+            "required": ["count"]
+        });
+        let schema = JSONSchema::compile(&schema).unwrap();
+
+        assert!(check_output_schema(&schema, br#"{"count": 3}"#).is_ok());
+        let reason = check_output_schema(&schema, br#"{"count": "three"}"#).unwrap_err();
+        assert!(reason.contains("/count"), "{reason}");
+        assert!(check_output_schema(&schema, b"not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn output_schemas_are_loaded_by_their_address() {
+        let path = std::env::temp_dir().join(format!("serval-schema-{}", Uuid::new_v4()));
+        let storage = Storage::new(None, Some(crate::storage::BlobStore::new(&path).unwrap()));
+        let schema = serde_json::json!({ "type": "object", "required": ["count"] });
+
+        let integrity = storage
+            .store_by_integrity(schema.to_string().as_bytes())
+            .await
+            .unwrap();
+        let loaded = load_output_schema(&storage, &integrity.to_string())
+            .await
+            .unwrap();
+        assert!(check_output_schema(&loaded, br#"{"count": 3}"#).is_ok());
+        assert!(check_output_schema(&loaded, br#"{}"#).is_err());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn base64_lengths_are_known_before_decoding() {
+        for input in [&b""[..], b"a", b"ab", b"abc", b"abcd", b"hello, world"] {
+            let encoded = BASE64.encode(input);
+            assert_eq!(
+                base64_decoded_len(&encoded),
+                input.len() as u64,
+                "{encoded}"
+            );
         }
-        Err(ServalEngineError::ExecutionError {
-            stdout: _,
-            error: _,
-            stderr,
-        }) => {
-            metrics::increment_counter!("run:error:execution");
-            // Now the fun part of http error signaling: the request was successful, but the
-            // result of the operation was bad from the user's point of view. Our behavior here
-            // is yet to be defined but I'm sending back stderr just to show we can.
-            (StatusCode::OK, stderr).into_response()
-        }
-        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
     }
 }
@@ -14,6 +14,12 @@ pub fn mount(router: ServalRouter) -> ServalRouter {
 }
 
 /// List all known peers.
+#[utoipa::path(
+    get,
+    path = "/v1/mesh/peers",
+    tag = "mesh",
+    responses((status = 200, description = "Every peer this node knows of", body = [MeshMember]))
+)]
 async fn list_peers(_state: State<AppState>) -> Json<Vec<MeshMember>> {
     let mesh = MESH.get().expect("Peer network not initialized!"); // yes, we crash in this case
     let peers = mesh
@@ -26,6 +32,13 @@ async fn list_peers(_state: State<AppState>) -> Json<Vec<MeshMember>> {
 }
 
 /// Filter known peers to only those that advertise the specific role.
+#[utoipa::path(
+    get,
+    path = "/v1/mesh/peers/{role}",
+    tag = "mesh",
+    params(("role" = ServalRole, Path, description = "The role peers must advertise")),
+    responses((status = 200, description = "The peers with that role", body = [MeshMember]))
+)]
 async fn filter_peers(
     Path(role): Path<ServalRole>,
     _state: State<AppState>,
@@ -1,41 +1,153 @@
-use axum::body::{Body, HttpBody};
+use axum::body::{Body, Bytes};
+use axum::extract::State;
 use axum::http::{Request, StatusCode};
 use axum::response::{IntoResponse, Response};
-use http::header::{CONTENT_LENGTH, EXPECT, HOST};
+use http::header::{CONTENT_LENGTH, EXPECT, HOST, RETRY_AFTER};
 use http::HeaderValue;
-use utils::errors::ServalError;
+use utils::errors::{ApiError, ServalError};
 use utils::mesh::{PeerMetadata, ServalRole};
 use uuid::Uuid;
 
-use crate::structures::MESH;
+use crate::api::{child_traceparent, TRACEPARENT};
+use crate::breaker::PROXY_BREAKER;
+use crate::structures::{AppState, RunnerState, MESH};
 
-// Relay the given request to to the first node that we discover that is advertising the given
-// service. in the future, we may keep a list of known nodes for a given service so we can avoid
-// running the discovery process for every proxy request.
+/// The fallback for every request no route matched. A request under `/v1/` for a service this node
+/// doesn't offer is relayed to a node that does, whatever its sub-path, so that new endpoints are
+/// reachable from every node without anyone having to remember to mount a proxy for them.
+pub async fn proxy_unavailable_services(
+    State(state): State<AppState>,
+    mut request: Request<Body>,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let Some(role) = missing_role(&state, &path) else {
+        return ApiError::not_found("route_not_found", format!("no such endpoint: {path}"))
+            .into_response();
+    };
+    log::info!("relaying a request for a missing service; path={path}; service={role}");
+    metrics::increment_counter!("proxy:fallback");
+
+    match relay_request(&mut request, &role, &state.instance_id).await {
+        Ok(resp) => resp,
+        Err(_) => ApiError::unavailable(
+            "peer_unavailable",
+            format!("Peer with the {role} role not available"),
+        )
+        .into_response(),
+    }
+}
+
+/// The role that serves the given API path, if this node doesn't fill that role itself.
+fn missing_role(state: &RunnerState, path: &str) -> Option<ServalRole> {
+    let service = path.strip_prefix("/v1/")?.split('/').next()?;
+    match service {
+        "storage" if !state.has_storage => Some(ServalRole::Storage),
+        "jobs" | "running" if !state.should_run_jobs => Some(ServalRole::Runner),
+        "scheduler" if !state.should_run_scheduler => Some(ServalRole::Scheduler),
+        _ => None,
+    }
+}
+
+// Relay the given request to a node that is advertising the given service. If relaying to that
+// service has been failing, the circuit breaker turns the request away with a 503 instead, so that
+// callers find out at once rather than after another round of timeouts.
 pub async fn relay_request(
     req: &mut Request<Body>,
     role: &ServalRole,
     source_instance_id: &Uuid,
 ) -> Result<Response, ServalError> {
-    let mesh = MESH.get().expect("Peer network not initialized!");
+    let Some(breaker) = PROXY_BREAKER.get() else {
+        return relay_to_any_peer(req, role, source_instance_id).await;
+    };
+    if let Err(wait) = breaker.allow(role) {
+        metrics::increment_counter!("proxy:circuit_open");
+        let mut resp = ApiError::unavailable(
+            "circuit_open",
+            format!("relaying to the {role} role keeps failing; not trying again yet"),
+        )
+        .into_response();
+        let retry_after = wait.as_secs().max(1).to_string();
+        if let Ok(value) = HeaderValue::from_str(&retry_after) {
+            resp.headers_mut().insert(RETRY_AFTER, value);
+        }
+        return Ok(resp);
+    }
 
-    let candidates = mesh.peers_with_role(role).await;
-    let Some(peer) = candidates.first() else {
-        log::warn!("proxy_unavailable_services failed to find a node offering the service; service={role}");
-        metrics::increment_counter!("proxy:no_service");
+    let result = relay_to_any_peer(req, role, source_instance_id).await;
+    match &result {
+        Ok(_) => breaker.record_success(role),
+        Err(_) => breaker.record_failure(role),
+    }
+    result
+}
+
+// Relay the given request to a node that is advertising the given service, chosen at random but
+// weighted by the capacity each node advertises. A node that answers 503 with a Retry-After is
+// too busy to take the request, so we try the others before passing its answer along. In the
+// future, we may keep a list of known nodes for a given service so we can avoid running the
+// discovery process for every proxy request.
+async fn relay_to_any_peer(
+    req: &mut Request<Body>,
+    role: &ServalRole,
+    source_instance_id: &Uuid,
+) -> Result<Response, ServalError> {
+    let Some(mesh) = MESH.get() else {
+        log::warn!("no peer network to relay a request over; service={role}");
         return Err(ServalError::ServiceNotFound);
     };
 
-    let result = proxy_request_to_other_node(req, peer, source_instance_id).await;
-    result.map_err(|err| {
-        log::warn!("Failed to proxy request to peer; peer={peer:?}; err={err:?}");
-        metrics::increment_counter!("proxy:failure");
-        err
-    })
+    // We may send the body more than once, so hold on to it.
+    let body = match hyper::body::to_bytes(req.body_mut()).await {
+        Ok(body) => body,
+        Err(_) => {
+            log::warn!("Failed to copy body bytes over; aborting this request");
+            return Ok(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "proxy_failed",
+                "Failed to copy body bytes",
+            )
+            .into_response());
+        }
+    };
+
+    let mut busy: Vec<String> = Vec::new();
+    let mut busy_response = None;
+    while let Some(peer) = mesh.weighted_peer_with_role_except(role, &busy).await {
+        let resp = proxy_request_to_other_node(req, body.clone(), &peer, source_instance_id)
+            .await
+            .map_err(|err| {
+                log::warn!("Failed to proxy request to peer; peer={peer:?}; err={err:?}");
+                metrics::increment_counter!("proxy:failure");
+                err
+            })?;
+        if resp.status() != StatusCode::SERVICE_UNAVAILABLE
+            || !resp.headers().contains_key(RETRY_AFTER)
+        {
+            return Ok(resp);
+        }
+        log::info!(
+            "peer is too busy; trying another; peer={}; service={role}",
+            peer.instance_id()
+        );
+        metrics::increment_counter!("proxy:busy");
+        busy.push(peer.instance_id().to_string());
+        busy_response = Some(resp);
+    }
+
+    // Every node we tried was busy; let the caller know when to come back.
+    if let Some(resp) = busy_response {
+        return Ok(resp);
+    }
+    log::warn!(
+        "proxy_unavailable_services failed to find a node offering the service; service={role}"
+    );
+    metrics::increment_counter!("proxy:no_service");
+    Err(ServalError::ServiceNotFound)
 }
 
 async fn proxy_request_to_other_node(
-    req: &mut Request<Body>,
+    req: &Request<Body>,
+    body: Bytes,
     peer: &PeerMetadata,
     source_instance_id: &Uuid,
 ) -> Result<Response, ServalError> {
@@ -57,6 +169,13 @@ async fn proxy_request_to_other_node(
         if k == CONTENT_LENGTH || k == EXPECT || k == HOST {
             continue;
         }
+        if k == TRACEPARENT {
+            // This relay is a new span in the same trace.
+            if let Some(child) = v.to_str().ok().and_then(child_traceparent) {
+                inner_req = inner_req.header(k, child);
+                continue;
+            }
+        }
         inner_req = inner_req.header(k, v);
     }
 
@@ -66,17 +185,8 @@ async fn proxy_request_to_other_node(
     );
 
     // Copy the body over
-    if let Some(req_body_bytes_res) = req.body_mut().data().await {
-        if let Ok(req_body_bytes) = req_body_bytes_res {
-            inner_req = inner_req.body(req_body_bytes);
-        } else {
-            log::warn!("Failed to copy body bytes over; aborting this request");
-            return Ok((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to copy body bytes",
-            )
-                .into_response());
-        }
+    if !body.is_empty() {
+        inner_req = inner_req.body(body);
     }
 
     // Actually send the request
@@ -1,23 +1,50 @@
+use std::collections::HashMap;
+
 use axum::body::{Body, Bytes};
-use axum::extract::{Path, State};
-use axum::http::{header, Request, StatusCode};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
 use axum::response::IntoResponse;
 use axum::routing::{any, get, head, patch, post, put};
+use axum::Json;
+use serde::Deserialize;
 use ssri::Integrity;
 use utils::diffs::apply_patch;
-use utils::errors::ServalError;
+use utils::errors::{ApiError, ServalError};
 use utils::mesh::ServalRole;
+use utils::structs::api::{
+    ArchiveEntry, ArchiveIndex, ErrorResponse, ImportSummary, MissingRanges, StartUpload,
+    UploadStarted, INTEGRITY_HEADER,
+};
 use utils::structs::Manifest;
+use utoipa::IntoParams;
+use uuid::Uuid;
 
-use crate::storage::STORAGE;
+use crate::api::Tenant;
+use crate::storage::archive::{read_tar, write_tar};
+use crate::storage::uploads::{UploadStatus, Uploads, UPLOADS};
+use crate::storage::{Storage, SIGNING_POLICY, STORAGE};
 use crate::structures::*;
 
 /// Mount all storage endpoint handlers onto the passed-in router.
 pub fn mount(router: ServalRouter) -> ServalRouter {
     router
+        .route("/v1/storage/manifests", get(list_manifests))
         .route("/v1/storage/manifests", post(store_manifest))
         .route("/v1/storage/manifests/:name", get(get_manifest))
         .route("/v1/storage/manifests/:name", head(has_manifest))
+        .route("/v1/storage/manifests/:name/versions", get(list_versions))
+        .route(
+            "/v1/storage/manifests/:name/versions/:version",
+            get(get_manifest_version),
+        )
+        .route(
+            "/v1/storage/manifests/:name/tags/:tag",
+            get(get_version_tag),
+        )
+        .route(
+            "/v1/storage/manifests/:name/tags/:tag",
+            put(set_version_tag),
+        )
         .route(
             "/v1/storage/manifests/:name/executable/:version",
             put(store_executable),
@@ -26,6 +53,18 @@ pub fn mount(router: ServalRouter) -> ServalRouter {
             "/v1/storage/manifests/:name/executable/:version",
             get(get_executable),
         )
+        .route(
+            "/v1/storage/manifests/:name/executable/:version",
+            head(has_executable),
+        )
+        .route(
+            "/v1/storage/manifests/:name/executable/:version/uploads",
+            post(start_upload),
+        )
+        .route("/v1/storage/uploads/:id/chunks/:offset", put(put_upload_chunk))
+        .route("/v1/storage/uploads/:id/finalize", post(finalize_upload))
+        .route("/v1/storage/export", get(export_storage))
+        .route("/v1/storage/import", post(import_storage))
         .route("/v1/storage/data", post(store_by_content_address))
         .route("/v1/storage/data/*address", get(get_by_content_address))
         .route("/v1/storage/data/*address", head(has_content_address))
@@ -49,18 +88,32 @@ async fn proxy(State(state): State<AppState>, mut request: Request<Body>) -> imp
         resp
     } else {
         // Welp, not much we can do
-        (
-            StatusCode::SERVICE_UNAVAILABLE,
+        ApiError::unavailable(
+            "peer_unavailable",
             "Peer with the storage role not available",
         )
-            .into_response()
+        .into_response()
     }
 }
 
+/// Store a blob in the content-addressed store.
+#[utoipa::path(
+    post,
+    path = "/v1/storage/data",
+    tag = "storage",
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The blob to store"
+    ),
+    responses(
+        (status = 201, description = "The blob's integrity checksum, which is its address", body = String),
+    )
+)]
 async fn store_by_content_address(body: Bytes) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:get");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
 
     let bytes = body.to_vec();
@@ -78,10 +131,24 @@ async fn store_by_content_address(body: Bytes) -> impl IntoResponse {
     }
 }
 
+/// Fetch a blob from the content-addressed store.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/data/{address}",
+    tag = "storage",
+    params(
+        ("address" = String, Path, description = "The blob's integrity checksum, which is its address"),
+    ),
+    responses(
+        (status = 200, description = "The blob", body = String, content_type = "application/octet-stream"),
+        (status = 400, description = "The address is not an integrity checksum", body = ErrorResponse),
+        (status = 404, description = "No blob has that address", body = ErrorResponse),
+    )
+)]
 async fn get_by_content_address(Path(address): Path<String>) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:get");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
 
     let Ok(integrity) = address.parse::<Integrity>() else {
@@ -99,7 +166,6 @@ async fn get_by_content_address(Path(address): Path<String>) -> impl IntoRespons
             log::info!("Serving CAS data; address={}", &address);
             (headers, stream).into_response()
         }
-        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
         Err(e) => {
             log::info!("Error serving CAS data; address={}; error={}", &address, e);
             e.into_response()
@@ -107,10 +173,23 @@ async fn get_by_content_address(Path(address): Path<String>) -> impl IntoRespons
     }
 }
 
+/// Check whether the content-addressed store has a blob.
+#[utoipa::path(
+    head,
+    path = "/v1/storage/data/{address}",
+    tag = "storage",
+    params(
+        ("address" = String, Path, description = "The blob's integrity checksum, which is its address"),
+    ),
+    responses(
+        (status = 200, description = "A blob has that address"),
+        (status = 404, description = "No blob has that address"),
+    )
+)]
 async fn has_content_address(Path(address): Path<String>) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:head");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
 
     let Ok(integrity) = address.parse::<Integrity>() else {
@@ -126,7 +205,6 @@ async fn has_content_address(Path(address): Path<String>) -> impl IntoResponse {
                 StatusCode::NOT_FOUND.into_response()
             }
         }
-        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
         Err(e) => {
             log::info!(
                 "Error serving CAS data head; address={}; error={}",
@@ -138,10 +216,29 @@ async fn has_content_address(Path(address): Path<String>) -> impl IntoResponse {
     }
 }
 
+/// Patch a blob in the content-addressed store, storing the result as a new blob.
+#[utoipa::path(
+    patch,
+    path = "/v1/storage/data/{address}",
+    tag = "storage",
+    params(
+        ("address" = String, Path, description = "The blob's integrity checksum, which is its address"),
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "A binary patch to apply to the blob"
+    ),
+    responses(
+        (status = 201, description = "The patched blob's integrity checksum", body = String),
+        (status = 400, description = "The patch could not be applied", body = ErrorResponse),
+        (status = 404, description = "No blob has that address", body = ErrorResponse),
+    )
+)]
 async fn patch_content_at_address(Path(address): Path<String>, body: Bytes) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:patch");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
 
     let Ok(integrity) = address.parse::<Integrity>() else {
@@ -153,7 +250,7 @@ async fn patch_content_at_address(Path(address): Path<String>, body: Bytes) -> i
         Ok(original) => {
             log::info!("Patching CAS data; address={}", &address);
             let Ok(updated)= apply_patch(&original, &body) else {
-                return ServalError::StorageError("patch could not be applied".to_string()).into_response();
+                return ApiError::bad_request("invalid_patch", "patch could not be applied").into_response();
             };
             match storage.store_by_integrity(&updated).await {
                 Ok(integrity) => {
@@ -168,7 +265,6 @@ async fn patch_content_at_address(Path(address): Path<String>, body: Bytes) -> i
                 Err(e) => e.into_response(),
             }
         }
-        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
         Err(e) => {
             log::info!("Error serving CAS data; address={}; error={}", &address, e);
             e.into_response()
@@ -177,21 +273,50 @@ async fn patch_content_at_address(Path(address): Path<String>, body: Bytes) -> i
 }
 
 /// Fetch an executable by fully-qualified manifest name.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/manifests/{name}/executable/{version}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("version" = String, Path, description = "The version of the job"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The executable",
+            body = String,
+            content_type = "application/octet-stream",
+            headers(("Serval-Integrity" = String, description = "The executable's checksum"))
+        ),
+        (status = 404, description = "No such executable", body = ErrorResponse),
+    )
+)]
 async fn get_executable(
     Path((name, version)): Path<(String, String)>,
+    tenant: Tenant,
     State(_state): State<AppState>,
 ) -> impl IntoResponse {
     metrics::increment_counter!("storage:executable:get");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
+    let name = tenant.scope(&name);
 
     match storage.executable_as_stream(&name, &version).await {
         Ok(stream) => {
-            let headers = [(
+            let mut headers = HeaderMap::new();
+            headers.insert(
                 header::CONTENT_TYPE,
-                String::from("application/octet-stream"),
-            )];
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            // Let whoever fetches this check that the bytes survived the trip.
+            if let Ok(integrity) = storage.executable_integrity(&name, &version).await {
+                if let Ok(value) = HeaderValue::from_str(&integrity.to_string()) {
+                    headers.insert(INTEGRITY_HEADER, value);
+                }
+            }
 
             log::info!("Serving job binary; name={}", &name);
             (headers, stream).into_response()
@@ -203,22 +328,124 @@ async fn get_executable(
     }
 }
 
+/// Report the integrity checksum of an executable in the integrity header, without its bytes.
+#[utoipa::path(
+    head,
+    path = "/v1/storage/manifests/{name}/executable/{version}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("version" = String, Path, description = "The version of the job"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The executable is stored",
+            headers(("Serval-Integrity" = String, description = "The executable's checksum"))
+        ),
+        (status = 404, description = "No such executable"),
+    )
+)]
+async fn has_executable(
+    Path((name, version)): Path<(String, String)>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:executable:head");
+    let Some(storage) = STORAGE.get() else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    let name = tenant.scope(&name);
+
+    match storage.executable_integrity(&name, &version).await {
+        Ok(integrity) => match HeaderValue::from_str(&integrity.to_string()) {
+            Ok(value) => [(INTEGRITY_HEADER, value)].into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Filters for the manifest listing.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ManifestSearch {
+    /// Only list manifests with this tag.
+    tag: Option<String>,
+    /// Only list manifests with this string in their name or description.
+    q: Option<String>,
+}
+
+/// List all stored manifests the caller's tenant can see, optionally filtered by tag and by a
+/// search string. The manifests are returned as a json array.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/manifests",
+    tag = "storage",
+    params(
+        ManifestSearch,
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (status = 200, description = "The matching manifests", body = [Manifest]),
+    )
+)]
+async fn list_manifests(
+    Query(search): Query<ManifestSearch>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:list");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+
+    match storage.manifests(tenant.0.as_deref()).await {
+        Ok(manifests) => {
+            let matching: Vec<Manifest> = manifests
+                .into_iter()
+                .filter(|m| m.matches(search.tag.as_deref(), search.q.as_deref()))
+                .collect();
+            Json(matching).into_response()
+        }
+        Err(e) => {
+            log::warn!("error listing manifests; error={e}");
+            e.into_response()
+        }
+    }
+}
+
 /// Fetch task manifest by name. The manifest is returned as toml.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/manifests/{name}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (status = 200, description = "The manifest", body = String, content_type = "application/toml"),
+        (status = 404, description = "No such manifest", body = ErrorResponse),
+    )
+)]
 async fn get_manifest(
     Path(name): Path<String>,
+    tenant: Tenant,
     State(_state): State<AppState>,
 ) -> impl IntoResponse {
     metrics::increment_counter!("storage:manifest:get");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
+    let name = tenant.scope(&name);
 
     match storage.manifest(&name).await {
         Ok(manifest) => {
             let headers = [(header::CONTENT_TYPE, String::from("application/toml"))];
             (headers, manifest.to_string()).into_response()
         }
-        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
         Err(e) => {
             log::warn!("error reading manifest; name={}; error={}", &name, e);
             e.into_response()
@@ -226,22 +453,202 @@ async fn get_manifest(
     }
 }
 
+/// List the stored versions of the named job's executable, as a json array of strings.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/manifests/{name}/versions",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (status = 200, description = "The stored versions", body = [String]),
+    )
+)]
+async fn list_versions(
+    Path(name): Path<String>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:versions");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+    let name = tenant.scope(&name);
+
+    match storage.versions(&name).await {
+        Ok(versions) => Json(versions).into_response(),
+        Err(e) => {
+            log::warn!("error listing versions; name={}; error={}", &name, e);
+            e.into_response()
+        }
+    }
+}
+
+/// Fetch the manifest stored with one version of a job, as toml.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/manifests/{name}/versions/{version}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("version" = String, Path, description = "The version of the job"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (status = 200, description = "The manifest for that version", body = String, content_type = "application/toml"),
+        (status = 404, description = "No such version", body = ErrorResponse),
+    )
+)]
+async fn get_manifest_version(
+    Path((name, version)): Path<(String, String)>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:version");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+    let name = tenant.scope(&name);
+
+    match storage.manifest_version(&name, &version).await {
+        Ok(manifest) => {
+            let headers = [(header::CONTENT_TYPE, String::from("application/toml"))];
+            (headers, manifest.to_string()).into_response()
+        }
+        Err(e) => {
+            log::warn!("error reading manifest; name={name}@{version}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Look up the version that a version tag points at, as plain text.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/manifests/{name}/tags/{tag}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("tag" = String, Path, description = "The version tag, e.g. `stable`"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (status = 200, description = "The version the tag points at", body = String),
+        (status = 404, description = "No such tag", body = ErrorResponse),
+    )
+)]
+async fn get_version_tag(
+    Path((name, tag)): Path<(String, String)>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:tag:get");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+    let name = tenant.scope(&name);
+
+    match storage.version_tag(&name, &tag).await {
+        Ok(version) => version.into_response(),
+        Err(ServalError::DataNotFound(_)) => {
+            ApiError::not_found("tag_not_found", format!("{name} has no tag {tag}")).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Point a version tag such as `stable` at a stored version of the named job. The body is the
+/// version. Jobs may then be run as `name@tag`.
+#[utoipa::path(
+    put,
+    path = "/v1/storage/manifests/{name}/tags/{tag}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("tag" = String, Path, description = "The version tag, e.g. `stable`"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    request_body(
+        content = String,
+        content_type = "text/plain",
+        description = "The version to point the tag at"
+    ),
+    responses(
+        (status = 200, description = "The version the tag now points at", body = String),
+        (status = 400, description = "The tag is not a valid tag name", body = ErrorResponse),
+    )
+)]
+async fn set_version_tag(
+    Path((name, tag)): Path<(String, String)>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+    body: String,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:tag:put");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+    let name = tenant.scope(&name);
+
+    if !Manifest::is_valid_version_tag(&tag) {
+        return ApiError::bad_request(
+            "invalid_tag",
+            "tags may include only alphanumerics, - and _, up to 64 characters",
+        )
+        .into_response();
+    }
+    let version = body.trim();
+    match storage.set_version_tag(&name, &tag, version).await {
+        Ok(()) => {
+            log::info!("Tagged version; name={name}@{version}; tag={tag}");
+            version.to_string().into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
 /// Store a job with its metadata.
+#[utoipa::path(
+    put,
+    path = "/v1/storage/manifests/{name}/executable/{version}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("version" = String, Path, description = "The version of the job"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "The executable"
+    ),
+    responses(
+        (status = 201, description = "The executable's integrity checksum", body = String),
+        (status = 404, description = "There is no manifest for the job", body = ErrorResponse),
+    )
+)]
 async fn store_executable(
     State(_state): State<AppState>,
     Path((name, version)): Path<(String, String)>,
+    tenant: Tenant,
     body: Bytes,
 ) -> impl IntoResponse {
     metrics::increment_counter!("storage:executable:put");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
+    let name = tenant.scope(&name);
 
     let Ok(manifest) = storage.manifest(&name).await else {
-        return (StatusCode::NOT_FOUND, format!("no manifest of that name found; name={name}")).into_response();
+        return ServalError::ManifestNotFound(name).into_response();
     };
 
     let bytes = body.to_vec();
+    if let Err(e) = check_executable(&manifest, &version, &bytes) {
+        return e.into_response();
+    }
 
     match storage.store_executable(&name, &version, &bytes).await {
         Ok(integrity) => {
@@ -258,12 +665,200 @@ async fn store_executable(
     }
 }
 
+/// Begin a resumable upload of an executable. The executable's pieces are then PUT to the upload,
+/// in any order and as many times as needed, and the upload is finalized with its checksum.
+#[utoipa::path(
+    post,
+    path = "/v1/storage/manifests/{name}/executable/{version}/uploads",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("version" = String, Path, description = "The version of the job"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    request_body(content = StartUpload),
+    responses(
+        (status = 201, description = "Where to send the pieces", body = UploadStarted),
+        (status = 404, description = "There is no manifest for the job", body = ErrorResponse),
+        (status = 413, description = "The executable is too big to upload", body = ErrorResponse),
+        (status = 503, description = "Too many uploads are in progress", body = ErrorResponse),
+    )
+)]
+async fn start_upload(
+    Path((name, version)): Path<(String, String)>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+    Json(request): Json<StartUpload>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:upload:start");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+    let name = tenant.scope(&name);
+
+    if storage.manifest(&name).await.is_err() {
+        return ServalError::ManifestNotFound(name).into_response();
+    }
+
+    let uploads = UPLOADS.get_or_init(Uploads::default);
+    let upload_id = match uploads.start(&name, &version, request.size) {
+        Ok(id) => id,
+        Err(e) => {
+            log::warn!(
+                "Refused upload; name={name}@{version}; size={}; {e}",
+                request.size
+            );
+            return e.into_response();
+        }
+    };
+    log::info!(
+        "Started upload; name={name}@{version}; size={}; upload={upload_id}",
+        request.size
+    );
+    (StatusCode::CREATED, Json(UploadStarted { upload_id })).into_response()
+}
+
+/// Accept one piece of a resumable upload.
+#[utoipa::path(
+    put,
+    path = "/v1/storage/uploads/{id}/chunks/{offset}",
+    tag = "storage",
+    params(
+        ("id" = Uuid, Path, description = "The upload"),
+        ("offset" = u64, Path, description = "Where the piece starts in the executable"),
+    ),
+    request_body(
+        content = String,
+        content_type = "application/octet-stream",
+        description = "One piece of the executable"
+    ),
+    responses(
+        (status = 204, description = "The piece was accepted"),
+        (status = 400, description = "The piece runs past the end of the executable", body = ErrorResponse),
+        (status = 404, description = "No such upload", body = ErrorResponse),
+    )
+)]
+async fn put_upload_chunk(
+    Path((id, offset)): Path<(Uuid, u64)>,
+    State(_state): State<AppState>,
+    body: Bytes,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:upload:chunk");
+    let uploads = UPLOADS.get_or_init(Uploads::default);
+    match uploads.put_chunk(&id, offset, body) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e @ ServalError::StorageError(_)) => {
+            ApiError::bad_request("invalid_chunk", e.to_string()).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Finish a resumable upload. The body is the integrity checksum the client expects the whole
+/// executable to have. If pieces are missing we say which, and the upload can be resumed.
+#[utoipa::path(
+    post,
+    path = "/v1/storage/uploads/{id}/finalize",
+    tag = "storage",
+    params(
+        ("id" = Uuid, Path, description = "The upload"),
+    ),
+    request_body(
+        content = String,
+        content_type = "text/plain",
+        description = "The integrity checksum the whole executable should have"
+    ),
+    responses(
+        (status = 201, description = "The executable was stored; its integrity checksum", body = String),
+        (status = 409, description = "Some pieces are still missing", body = MissingRanges),
+        (status = 404, description = "No such upload", body = ErrorResponse),
+    )
+)]
+async fn finalize_upload(
+    Path(id): Path<Uuid>,
+    State(_state): State<AppState>,
+    body: String,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:upload:finalize");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+
+    let Ok(expected) = body.trim().parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{body} is not a valid sub-resource integrity string"));
+        return e.into_response();
+    };
+
+    let uploads = UPLOADS.get_or_init(Uploads::default);
+    let (name, version, bytes) = match uploads.finish(&id, &expected) {
+        Ok(UploadStatus::Complete {
+            name,
+            version,
+            bytes,
+        }) => (name, version, bytes),
+        Ok(UploadStatus::Incomplete(missing)) => {
+            return (StatusCode::CONFLICT, Json(MissingRanges { missing })).into_response();
+        }
+        Err(e @ ServalError::IntegrityMismatch(_)) => {
+            log::warn!("Upload failed its integrity check; upload={id}; {e}");
+            return e.into_response();
+        }
+        Err(e) => return e.into_response(),
+    };
+    let Ok(manifest) = storage.manifest(&name).await else {
+        return ServalError::ManifestNotFound(name).into_response();
+    };
+    if let Err(e) = check_executable(&manifest, &version, &bytes) {
+        return e.into_response();
+    }
+
+    match storage.store_executable(&name, &version, &bytes).await {
+        Ok(integrity) => {
+            log::info!(
+                "Stored new executable from upload; name={}@{}; executable_hash={}; size={}",
+                name,
+                version,
+                integrity,
+                bytes.len()
+            );
+            (StatusCode::CREATED, integrity.to_string()).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Make sure an executable is the one its manifest's signer vouched for, if this node cares.
+fn check_executable(manifest: &Manifest, version: &str, bytes: &[u8]) -> Result<(), ServalError> {
+    match SIGNING_POLICY.get() {
+        Some(policy) => policy.check_executable(manifest, version, bytes),
+        None => Ok(()),
+    }
+}
+
 /// Returns true if this node has access to the given task type, specified by fully-qualified name.
-async fn has_manifest(Path(name): Path<String>, State(_state): State<AppState>) -> StatusCode {
+#[utoipa::path(
+    head,
+    path = "/v1/storage/manifests/{name}",
+    tag = "storage",
+    params(
+        ("name" = String, Path, description = "The job's fully-qualified name"),
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    responses(
+        (status = 200, description = "The manifest is stored"),
+        (status = 404, description = "No such manifest"),
+    )
+)]
+async fn has_manifest(
+    Path(name): Path<String>,
+    tenant: Tenant,
+    State(_state): State<AppState>,
+) -> StatusCode {
     metrics::increment_counter!("storage:manifest:head");
     let Some(storage) = STORAGE.get() else {
         return StatusCode::SERVICE_UNAVAILABLE;
     };
+    let name = tenant.scope(&name);
 
     match storage.data_exists_by_key(&name).await {
         Ok(exists) => {
@@ -279,16 +874,55 @@ async fn has_manifest(Path(name): Path<String>, State(_state): State<AppState>)
     }
 }
 
-async fn store_manifest(State(_state): State<AppState>, body: String) -> impl IntoResponse {
+/// Store a job's manifest, sent as toml.
+#[utoipa::path(
+    post,
+    path = "/v1/storage/manifests",
+    tag = "storage",
+    params(
+        ("X-Serval-Tenant" = Option<String>, Header, description = "The tenant to act for, if any"),
+    ),
+    request_body(
+        content = String,
+        content_type = "application/toml",
+        description = "The manifest, as toml"
+    ),
+    responses(
+        (status = 201, description = "The stored manifest's integrity checksum", body = String),
+        (status = 400, description = "The manifest is invalid", body = ErrorResponse),
+    )
+)]
+async fn store_manifest(
+    tenant: Tenant,
+    State(_state): State<AppState>,
+    body: String,
+) -> impl IntoResponse {
     metrics::increment_counter!("storage:manifest:post");
     let Some(storage) = STORAGE.get() else {
-        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+        return ApiError::storage_uninitialized().into_response();
     };
 
     match Manifest::from_string(&body) {
         Ok(manifest) => {
+            // Check the signature before flattening, since it covers only what the signer sent.
+            if let Some(policy) = SIGNING_POLICY.get() {
+                if let Err(e) = policy.check_manifest(&manifest) {
+                    log::warn!("Refused manifest; name={}; {e}", manifest.fq_name());
+                    return e.into_response();
+                }
+            }
+            // Store the manifest flattened, so that runners never need to chase its bases. If that
+            // fills anything in, the signature no longer covers it and is dropped.
+            let manifest = match storage.resolve_bases(manifest, tenant.0.as_deref()).await {
+                Ok(manifest) => manifest,
+                Err(e @ ServalError::ManifestNotFound(_)) => {
+                    return ApiError::bad_request("manifest_base_not_found", e.to_string())
+                        .into_response();
+                }
+                Err(e) => return e.into_response(),
+            };
             log::info!("storing manifest for job={}", manifest.fq_name());
-            match storage.store_manifest(&manifest).await {
+            match storage.store_manifest(&manifest, tenant.0.as_deref()).await {
                 Ok(integrity) => {
                     log::info!(
                         "Stored new manifest; name={}; manifest_hash={}",
@@ -300,6 +934,203 @@ async fn store_manifest(State(_state): State<AppState>, body: String) -> impl In
                 Err(e) => e.into_response(),
             }
         }
-        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => ApiError::bad_request("invalid_manifest", e.to_string()).into_response(),
+    }
+}
+
+/// The name of the index file at the front of a storage export.
+const ARCHIVE_INDEX: &str = "index.json";
+
+/// Export every manifest and executable this node stores, for every tenant, as a tar archive. The
+/// first file in the archive is an index of the rest. The archive is assembled in memory.
+#[utoipa::path(
+    get,
+    path = "/v1/storage/export",
+    tag = "storage",
+    responses(
+        (status = 200, description = "Every stored manifest and executable, as a tar archive", body = String, content_type = "application/x-tar"),
+    )
+)]
+async fn export_storage(State(_state): State<AppState>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:export");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+
+    let blobs = match storage.export().await {
+        Ok(blobs) => blobs,
+        Err(e) => {
+            log::warn!("error exporting storage; error={e}");
+            return e.into_response();
+        }
+    };
+
+    let mut index = ArchiveIndex {
+        version: 1,
+        entries: Vec::with_capacity(blobs.len()),
+    };
+    let mut files = Vec::with_capacity(blobs.len() + 1);
+    for (key, bytes) in blobs {
+        let dir = if key.ends_with(".wasm") {
+            "executables"
+        } else if key.ends_with(".tag") {
+            "tags"
+        } else {
+            "manifests"
+        };
+        let path = format!("{dir}/{key}");
+        index.entries.push(ArchiveEntry {
+            path: path.clone(),
+            key,
+            integrity: Integrity::from(&bytes).to_string(),
+        });
+        files.push((path, bytes));
+    }
+    let index_bytes = match serde_json::to_vec_pretty(&index) {
+        Ok(bytes) => bytes,
+        Err(e) => return ServalError::StorageError(e.to_string()).into_response(),
+    };
+    files.insert(0, (ARCHIVE_INDEX.to_string(), index_bytes));
+
+    match write_tar(&files) {
+        Ok(archive) => {
+            log::info!(
+                "Exported storage; entries={}; size={}",
+                index.entries.len(),
+                archive.len()
+            );
+            let headers = [(header::CONTENT_TYPE, String::from("application/x-tar"))];
+            (headers, archive).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Restore a storage export made by any node. Before anything is written, every file is checked
+/// against the integrity in the archive's index, and every manifest and executable against this
+/// node's signing policy. Files we already hold unchanged are skipped.
+#[utoipa::path(
+    post,
+    path = "/v1/storage/import",
+    tag = "storage",
+    request_body(
+        content = String,
+        content_type = "application/x-tar",
+        description = "A storage export"
+    ),
+    responses(
+        (status = 200, description = "What was imported", body = ImportSummary),
+        (status = 400, description = "The archive is damaged", body = ErrorResponse),
+    )
+)]
+async fn import_storage(State(_state): State<AppState>, body: Bytes) -> impl IntoResponse {
+    metrics::increment_counter!("storage:import");
+    let Some(storage) = STORAGE.get() else {
+        return ApiError::storage_uninitialized().into_response();
+    };
+
+    let mut files: HashMap<String, Vec<u8>> = match read_tar(&body) {
+        Ok(files) => files.into_iter().collect(),
+        Err(e) => return ApiError::bad_request("invalid_archive", e.to_string()).into_response(),
+    };
+    let invalid =
+        |message: String| ApiError::bad_request("invalid_archive", message).into_response();
+    let Some(index_bytes) = files.remove(ARCHIVE_INDEX) else {
+        return invalid(format!("archive has no {ARCHIVE_INDEX}"));
+    };
+    let index: ArchiveIndex = match serde_json::from_slice(&index_bytes) {
+        Ok(index) => index,
+        Err(e) => return invalid(format!("unreadable archive index: {e}")),
+    };
+    if index.version != 1 {
+        return invalid(format!("unsupported archive version {}", index.version));
+    }
+
+    // Check the whole archive first, so that a damaged one is rejected without a partial import.
+    for entry in &index.entries {
+        if !Storage::is_exportable(&entry.key) {
+            return invalid(format!(
+                "archive entry has an unexpected key; key={}",
+                entry.key
+            ));
+        }
+        let Some(bytes) = files.get(&entry.path) else {
+            return invalid(format!("archive is missing {}", entry.path));
+        };
+        let Ok(integrity) = entry.integrity.parse::<Integrity>() else {
+            let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", entry.integrity));
+            return e.into_response();
+        };
+        if integrity.check(bytes).is_err() {
+            return ServalError::IntegrityMismatch(entry.path.clone()).into_response();
+        }
+    }
+
+    // Hold what is imported to the same signing policy as what is stored directly. Each
+    // executable is checked against the manifest for its version, or failing that the latest.
+    let mut latest: HashMap<String, Manifest> = HashMap::new();
+    let mut versioned: HashMap<String, Manifest> = HashMap::new();
+    for entry in &index.entries {
+        let (executable_key, is_latest) = match entry.key.strip_suffix(".manifest.toml") {
+            Some(name) => (name.to_string(), true),
+            None => match entry.key.strip_suffix(".manifest") {
+                Some(name_and_version) => (format!("{name_and_version}.wasm"), false),
+                None => continue,
+            },
+        };
+        let text = String::from_utf8_lossy(&files[&entry.path]);
+        let manifest = match toml::from_str::<Manifest>(&text) {
+            Ok(manifest) => manifest,
+            Err(e) => return invalid(format!("unreadable manifest; key={}; {e}", entry.key)),
+        };
+        if let Some(policy) = SIGNING_POLICY.get() {
+            if let Err(e) = policy.check_manifest(&manifest) {
+                log::warn!("Refused storage import; key={}; {e}", entry.key);
+                return e.into_response();
+            }
+        }
+        if is_latest {
+            let key = Manifest::make_executable_key(&executable_key, manifest.version());
+            latest.insert(key, manifest);
+        } else {
+            versioned.insert(executable_key, manifest);
+        }
+    }
+    for entry in index.entries.iter().filter(|e| e.key.ends_with(".wasm")) {
+        let bytes = &files[&entry.path];
+        let result = match versioned.get(&entry.key).or_else(|| latest.get(&entry.key)) {
+            Some(manifest) => check_executable(manifest, manifest.version(), bytes),
+            None => match SIGNING_POLICY.get() {
+                Some(policy) if policy.require_signed() => {
+                    Err(ServalError::ManifestUnsigned(entry.key.clone()))
+                }
+                _ => Ok(()),
+            },
+        };
+        if let Err(e) = result {
+            log::warn!("Refused storage import; key={}; {e}", entry.key);
+            return e.into_response();
+        }
+    }
+
+    let mut summary = ImportSummary {
+        imported: 0,
+        skipped: 0,
+    };
+    for entry in &index.entries {
+        match storage.import(&entry.key, &files[&entry.path]).await {
+            Ok(true) => summary.imported += 1,
+            Ok(false) => summary.skipped += 1,
+            Err(e) => {
+                log::warn!("error importing storage; key={}; error={e}", entry.key);
+                return e.into_response();
+            }
+        }
     }
+    log::info!(
+        "Imported storage; imported={}; skipped={}",
+        summary.imported,
+        summary.skipped
+    );
+    Json(summary).into_response()
 }
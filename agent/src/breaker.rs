@@ -0,0 +1,182 @@
+// A circuit breaker for relaying requests to other nodes, so that when every node with a role is
+// unreachable, requests for it fail at once instead of each waiting out its own timeout.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use utils::mesh::ServalRole;
+use utils::structs::api::{CircuitState, CircuitStatus};
+
+/// The circuit breaker for proxied requests on this node.
+pub static PROXY_BREAKER: OnceCell<CircuitBreaker> = OnceCell::new();
+
+/// Tracks, per role, whether relaying to nodes with that role has been failing. After enough
+/// failures in a row the circuit opens and requests are refused for a cooldown. Once that is up,
+/// one request is let through to probe: if it succeeds the circuit closes, and if not it reopens.
+/// A probe that hasn't reported back within another cooldown is given up on, so a request that
+/// was dropped part way can't hold the circuit open for good.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<ServalRole, Circuit>>,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Open a role's circuit after `failure_threshold` failures in a row, for `cooldown`.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ask whether a request may be relayed to the given role. If not, says how long until it may.
+    pub fn allow(&self, role: &ServalRole) -> Result<(), Duration> {
+        self.allow_at(role, Instant::now())
+    }
+
+    fn allow_at(&self, role: &ServalRole, now: Instant) -> Result<(), Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(role) else {
+            return Ok(());
+        };
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+        let open_for = now.duration_since(opened_at);
+        if open_for < self.cooldown {
+            return Err(self.cooldown - open_for);
+        }
+        if let Some(started) = circuit.probe_started {
+            let probing_for = now.duration_since(started);
+            if probing_for < self.cooldown {
+                // Someone else is already finding out whether the role is back.
+                return Err(Duration::from_secs(1).min(self.cooldown - probing_for));
+            }
+            log::info!("proxy circuit probe never reported back; role={role}");
+        }
+        circuit.probe_started = Some(now);
+        Ok(())
+    }
+
+    /// Note that a request reached a node with the given role.
+    pub fn record_success(&self, role: &ServalRole) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.remove(role) {
+            if circuit.opened_at.is_some() {
+                log::info!("proxy circuit closed; role={role}");
+            }
+        }
+    }
+
+    /// Note that a request could not be relayed to any node with the given role.
+    pub fn record_failure(&self, role: &ServalRole) {
+        self.record_failure_at(role, Instant::now())
+    }
+
+    fn record_failure_at(&self, role: &ServalRole, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(role.clone()).or_default();
+        circuit.consecutive_failures += 1;
+        let probing = circuit.probe_started.is_some();
+        if probing || circuit.consecutive_failures >= self.failure_threshold {
+            if circuit.opened_at.is_none() || probing {
+                log::warn!(
+                    "proxy circuit opened; role={role}; failures={}",
+                    circuit.consecutive_failures
+                );
+                metrics::increment_counter!("proxy:circuit_opened");
+            }
+            circuit.opened_at = Some(now);
+            circuit.probe_started = None;
+        }
+    }
+
+    /// The state of every circuit that has seen a failure since it was last closed.
+    pub fn status(&self) -> Vec<CircuitStatus> {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> Vec<CircuitStatus> {
+        let circuits = self.circuits.lock().unwrap();
+        let mut status: Vec<CircuitStatus> = circuits
+            .iter()
+            .map(|(role, circuit)| CircuitStatus {
+                role: role.clone(),
+                state: match circuit.opened_at {
+                    None => CircuitState::Closed,
+                    Some(opened_at) if now.duration_since(opened_at) < self.cooldown => {
+                        CircuitState::Open
+                    }
+                    Some(_) => CircuitState::HalfOpen,
+                },
+                consecutive_failures: circuit.consecutive_failures,
+            })
+            .collect();
+        status.sort_by_key(|circuit| circuit.role.to_string());
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_repeated_failures_then_probes() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let role = ServalRole::Runner;
+        let start = Instant::now();
+
+        breaker.record_failure_at(&role, start);
+        assert!(breaker.allow_at(&role, start).is_ok());
+        breaker.record_failure_at(&role, start);
+        assert!(breaker.allow_at(&role, start).is_err());
+        assert!(breaker.allow_at(&ServalRole::Storage, start).is_ok());
+        assert_eq!(breaker.status_at(start)[0].state, CircuitState::Open);
+
+        // After the cooldown one probe goes through, and only one.
+        let later = start + Duration::from_secs(31);
+        assert_eq!(breaker.status_at(later)[0].state, CircuitState::HalfOpen);
+        assert!(breaker.allow_at(&role, later).is_ok());
+        assert!(breaker.allow_at(&role, later).is_err());
+
+        // A failed probe reopens the circuit; a successful one closes it.
+        breaker.record_failure_at(&role, later);
+        assert!(breaker.allow_at(&role, later).is_err());
+        let even_later = later + Duration::from_secs(31);
+        assert!(breaker.allow_at(&role, even_later).is_ok());
+        breaker.record_success(&role);
+        assert!(breaker.allow_at(&role, even_later).is_ok());
+        assert!(breaker.status_at(even_later).is_empty());
+    }
+
+    #[test]
+    fn abandoned_probes_are_given_up_on() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let role = ServalRole::Runner;
+        let start = Instant::now();
+
+        breaker.record_failure_at(&role, start);
+        let probe = start + Duration::from_secs(31);
+        assert!(breaker.allow_at(&role, probe).is_ok());
+        // The probe never records how it went, as when its request is dropped.
+        assert!(breaker
+            .allow_at(&role, probe + Duration::from_secs(29))
+            .is_err());
+        let next_probe = probe + Duration::from_secs(31);
+        assert!(breaker.allow_at(&role, next_probe).is_ok());
+        assert!(breaker.allow_at(&role, next_probe).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use ssri::Integrity;
+use utils::structs::Manifest;
+
+/// What we remember about a finished run of a pure job.
+#[derive(Clone, Debug)]
+pub struct CachedResult {
+    pub code: i32,
+    /// The job's stdout, which is what we sent back to the caller.
+    pub output: Vec<u8>,
+}
+
+/// A bounded cache of results for jobs whose manifests declare them pure, keyed by the job's
+/// manifest, executable, and input. It holds at most so many results and so many bytes of output
+/// between them; when either is exceeded, the oldest entries are evicted to make room.
+#[derive(Debug)]
+pub struct ResultCache {
+    capacity: usize,
+    max_bytes: u64,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<String, CachedResult>,
+    order: VecDeque<String>,
+    bytes: u64,
+}
+
+impl ResultCache {
+    /// Create a cache holding at most `capacity` results, with at most `max_bytes` of output
+    /// between them. A capacity of zero disables caching.
+    pub fn new(capacity: usize, max_bytes: u64) -> Self {
+        Self {
+            capacity,
+            max_bytes,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// The key for a job's result: the same manifest, under the same (tenant-scoped) name, with
+    /// the same executable, run on the same input. The manifest alone isn't enough, as a version
+    /// can be stored again with a different executable.
+    pub fn key(name: &str, manifest: &Manifest, executable: &Integrity, input: &[u8]) -> String {
+        let manifest = Integrity::from(manifest.to_string());
+        let input = Integrity::from(input);
+        format!("{name}:{manifest}:{executable}:{input}")
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResult> {
+        self.inner.lock().unwrap().entries.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: String, result: CachedResult) {
+        let size = result.output.len() as u64;
+        if self.capacity == 0 || size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.bytes += size;
+        match inner.entries.insert(key.clone(), result) {
+            Some(replaced) => inner.bytes -= replaced.output.len() as u64,
+            None => inner.order.push_back(key),
+        }
+        while inner.order.len() > self.capacity || inner.bytes > self.max_bytes {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.bytes -= evicted.output.len() as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(code: i32) -> CachedResult {
+        CachedResult {
+            code,
+            output: vec![],
+        }
+    }
+
+    fn sized(size: usize) -> CachedResult {
+        CachedResult {
+            code: 0,
+            output: vec![0; size],
+        }
+    }
+
+    #[test]
+    fn evicts_oldest_first() {
+        let cache = ResultCache::new(2, u64::MAX);
+        cache.insert("a".to_string(), result(1));
+        cache.insert("b".to_string(), result(2));
+        cache.insert("a".to_string(), result(3));
+        cache.insert("c".to_string(), result(4));
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.get("b").unwrap().code, 2);
+        assert_eq!(cache.get("c").unwrap().code, 4);
+
+        let disabled = ResultCache::new(0, u64::MAX);
+        disabled.insert("a".to_string(), result(1));
+        assert!(disabled.get("a").is_none());
+    }
+
+    #[test]
+    fn total_output_is_bounded() {
+        let cache = ResultCache::new(10, 100);
+        cache.insert("a".to_string(), sized(40));
+        cache.insert("b".to_string(), sized(40));
+        cache.insert("c".to_string(), sized(40));
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+        assert!(cache.get("c").is_some());
+
+        // Replacing an entry frees what it held, and nothing too big for the cache is kept.
+        cache.insert("c".to_string(), sized(60));
+        assert!(cache.get("b").is_some());
+        cache.insert("d".to_string(), sized(101));
+        assert!(cache.get("d").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn keys_cover_the_executable() {
+        let manifest = Manifest::ad_hoc("hello");
+        let one = Integrity::from(b"one executable");
+        let other = Integrity::from(b"another executable");
+        assert_ne!(
+            ResultCache::key("hello", &manifest, &one, b"input"),
+            ResultCache::key("hello", &manifest, &other, b"input")
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Where job callbacks may be delivered. A callback URL comes from whoever ran the job, so without
+// a check it would let any caller make this node POST to services on its own private network.
+
+use std::net::{IpAddr, SocketAddr};
+
+use once_cell::sync::OnceCell;
+use reqwest::Url;
+
+/// The callback policy for this node.
+pub static CALLBACK_POLICY: OnceCell<CallbackPolicy> = OnceCell::new();
+
+/// Decides which URLs job callbacks may go to. Only http and https URLs are accepted. If the
+/// operator named the hosts callbacks may go to, those are the only ones allowed, wherever they
+/// are; otherwise any host is allowed so long as it isn't on a loopback, private, or link-local
+/// address.
+#[derive(Debug, Default)]
+pub struct CallbackPolicy {
+    allowed_hosts: Vec<String>,
+}
+
+impl CallbackPolicy {
+    /// Allow callbacks only to the given hosts, or to any public host if the list is empty.
+    pub fn new(allowed_hosts: &[&str]) -> Self {
+        Self {
+            allowed_hosts: allowed_hosts
+                .iter()
+                .map(|host| host.trim().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Check a callback URL before accepting a job that asks for it. This can't see where a host
+    /// name resolves to; `resolve` checks that just before delivery.
+    pub fn check_url(&self, url: &str) -> Result<Url, String> {
+        let url = Url::parse(url).map_err(|e| format!("not a valid URL: {e}"))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!(
+                "the scheme must be http or https, not {}",
+                url.scheme()
+            ));
+        }
+        let Some(host) = url.host_str() else {
+            return Err("the URL has no host".to_string());
+        };
+        if !self.allowed_hosts.is_empty() {
+            if !self.allowed_hosts.iter().any(|allowed| allowed == host) {
+                return Err(format!("{host} is not an allowed callback host"));
+            }
+            return Ok(url);
+        }
+        if host == "localhost" || host.ends_with(".localhost") {
+            return Err(format!("{host} is not a public address"));
+        }
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            if !is_public(ip) {
+                return Err(format!("{host} is not a public address"));
+            }
+        }
+        Ok(url)
+    }
+
+    /// Look up where a callback URL points, refusing it if any of its addresses isn't public and
+    /// the host wasn't explicitly allowed. Deliver to the returned addresses, not a fresh lookup,
+    /// so the name can't be pointed somewhere else in between.
+    pub async fn resolve(&self, url: &Url) -> Result<Vec<SocketAddr>, String> {
+        let host = url.host_str().ok_or("the URL has no host")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("unable to resolve {host}: {e}"))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("{host} has no addresses"));
+        }
+        if self.allowed_hosts.is_empty() {
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                return Err(format!(
+                    "{host} resolves to {}, which is not public",
+                    addr.ip()
+                ));
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+/// Whether an address is one a callback may reach without the operator's say-so.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            // 100.64.0.0/10 is carrier-grade NAT space, which is as private as 10.0.0.0/8 here.
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || shared)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            let unique_local = (first & 0xfe00) == 0xfc00;
+            let link_local = (first & 0xffc0) == 0xfe80;
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || unique_local
+                || link_local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callback_urls() {
+        let policy = CallbackPolicy::default();
+        assert!(policy.check_url("https://hooks.example.com/done").is_ok());
+        assert!(policy.check_url("http://93.184.216.34:8080/").is_ok());
+        assert!(policy.check_url("file:///etc/passwd").is_err());
+        assert!(policy.check_url("gopher://example.com/").is_err());
+        assert!(policy.check_url("http://localhost:8100/").is_err());
+        assert!(policy.check_url("http://127.0.0.1/").is_err());
+        assert!(policy.check_url("http://10.1.2.3/").is_err());
+        assert!(policy
+            .check_url("http://169.254.169.254/latest/meta-data")
+            .is_err());
+        assert!(policy.check_url("http://[::1]/").is_err());
+        assert!(policy.check_url("http://[::ffff:192.168.0.1]/").is_err());
+        assert!(policy.check_url("http://[fd00::1]/").is_err());
+
+        let policy = CallbackPolicy::new(&["hooks.internal", "10.1.2.3"]);
+        assert!(policy.check_url("http://hooks.internal/done").is_ok());
+        assert!(policy.check_url("http://10.1.2.3/").is_ok());
+        assert!(policy.check_url("https://hooks.example.com/done").is_err());
+    }
+}
@@ -7,23 +7,33 @@
     unused_qualifications
 )]
 
+use std::collections::HashMap;
+use std::io::Write;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use axum::body::*;
 use axum::extract::DefaultBodyLimit;
+use axum::http::{header, Extensions, HeaderMap, StatusCode, Version};
 use axum::middleware::{self};
-use axum::routing::get;
+use axum::routing::{any, get};
 use axum::{Router, Server};
 use dotenvy::dotenv_override as dotenv;
+use engine::module_cache::ModuleCache;
 use engine::ServalEngine;
 // TODO: should switch on feature.
+use metrics_exporter_statsd::StatsdBuilder;
 use metrics_exporter_tcp::TcpBuilder;
+use tokio::sync::Semaphore;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate};
+use tower_http::compression::CompressionLayer;
 use utils::mesh::{mesh_interface_and_port, KaboodleMesh, PeerMetadata, ServalMesh, ServalRole};
 use utils::networking::find_nearest_port;
+use utils::signing::SigningPolicy;
 use uuid::Uuid;
 
 mod api;
@@ -32,7 +42,27 @@ use crate::api::*;
 mod structures;
 use crate::structures::*;
 
+mod breaker;
+use crate::breaker::{CircuitBreaker, PROXY_BREAKER};
+
+mod cache;
+
+mod callback;
+use crate::callback::{CallbackPolicy, CALLBACK_POLICY};
+
+mod partition;
+use crate::partition::{MeshWatch, MESH_WATCH};
+
+mod ratelimit;
+use crate::ratelimit::{RateLimiter, RATE_LIMITER};
+
+mod resources;
+
 mod storage;
+use crate::storage::uploads::{
+    Uploads, DEFAULT_MAX_PENDING_UPLOADS, DEFAULT_MAX_UPLOAD_BYTES, UPLOADS,
+};
+use crate::storage::{BlobBackend, SIGNING_POLICY};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -40,7 +70,7 @@ async fn main() -> Result<()> {
     if cfg!(debug_assertions) && !did_find_dotenv {
         println!("Debug-only warning: no .env file found to configure logging; all logging will be disabled. Add RUST_LOG=info to .env to see logging.");
     }
-    env_logger::init();
+    init_logging();
 
     let config = init_config();
     init_metrics();
@@ -49,10 +79,15 @@ async fn main() -> Result<()> {
     let state = Arc::new(
         RunnerState::new(
             config.instance_id,
-            config.blob_path.clone(),
+            config.blob_backend.clone(),
             config.extensions_path.clone(),
             config.should_run_jobs,
             config.should_run_scheduler,
+            config.max_memory_bytes,
+            config.max_duration_ms,
+            config.result_cache_limits,
+            config.shared_data_path.clone(),
+            config.module_cache.clone(),
         )
         .await?,
     );
@@ -63,14 +98,69 @@ async fn main() -> Result<()> {
         state.should_run_scheduler,
     );
 
+    if let Some(max_jobs) = config.max_concurrent_jobs {
+        log::info!("limiting concurrent jobs; max={max_jobs}");
+        JOB_SLOTS.set(Semaphore::new(max_jobs)).unwrap();
+    }
+
+    if let Some((rate, burst)) = config.rate_limit {
+        log::info!("rate limiting job runs; rate={rate}/s; burst={burst}");
+        RATE_LIMITER.set(RateLimiter::new(rate, burst)).unwrap();
+    }
+
+    let (failure_threshold, cooldown) = config.proxy_breaker;
+    log::info!(
+        "proxy circuit breaker; failures={failure_threshold}; cooldown={}s",
+        cooldown.as_secs()
+    );
+    PROXY_BREAKER
+        .set(CircuitBreaker::new(failure_threshold, cooldown))
+        .unwrap();
+
+    let (known_peers, peer_memory) = config.mesh_watch;
+    log::info!(
+        "watching the mesh for partitions; known-peers={}; memory={}s",
+        known_peers.len(),
+        peer_memory.as_secs()
+    );
+    MESH_WATCH
+        .set(MeshWatch::new(known_peers, peer_memory))
+        .unwrap();
+
+    if !config.response_headers.is_empty() {
+        log::info!(
+            "adding headers to every response; count={}",
+            config.response_headers.len()
+        );
+    }
+    RESPONSE_HEADERS.set(config.response_headers).unwrap();
+
+    log::info!(
+        "manifest signing; require-signed={}",
+        config.signing_policy.require_signed()
+    );
+    SIGNING_POLICY.set(config.signing_policy).unwrap();
+    CALLBACK_POLICY.set(config.callback_policy).unwrap();
+
+    let (max_upload_bytes, max_pending_uploads) = config.upload_limits;
+    log::info!("limiting uploads; max-bytes={max_upload_bytes}; max-pending={max_pending_uploads}");
+    UPLOADS
+        .set(Uploads::new(max_upload_bytes, max_pending_uploads))
+        .unwrap();
+
     let app = init_router(&state);
 
     // Start the Axum server; this is in a loop so we can try binding more than once in case our
     // randomly-selected port number ends up conflicting with something else due to a race condition.
     let mut http_addr: SocketAddr;
     let server: Server<_, _> = loop {
-        let host = std::env::var("HOST").unwrap_or_else(|_| "[::]".to_string());
-        let predefined_port = std::env::var("PORT")
+        // API_HOST and API_PORT let the HTTP API listen apart from the mesh; HOST and PORT are
+        // the older names for the same settings.
+        let host = std::env::var("API_HOST")
+            .or_else(|_| std::env::var("HOST"))
+            .unwrap_or_else(|_| "[::]".to_string());
+        let predefined_port = std::env::var("API_PORT")
+            .or_else(|_| std::env::var("PORT"))
             .ok()
             .and_then(|port_str| port_str.parse::<u16>().ok());
         let port = predefined_port.unwrap_or_else(|| find_nearest_port(8100).unwrap());
@@ -83,7 +173,7 @@ async fn main() -> Result<()> {
             }
             continue;
         };
-        break builder.serve(app.into_make_service());
+        break builder.serve(app.into_make_service_with_connect_info::<SocketAddr>());
     };
 
     log::info!("serval agent http will listen on {http_addr}");
@@ -98,12 +188,19 @@ async fn main() -> Result<()> {
     }
 
     let mut roles: Vec<ServalRole> = Vec::new();
-    if let Some(storage_path) = config.blob_path {
-        log::info!(
-            "serval agent blob store mounted; path={}",
-            storage_path.display()
-        );
-        roles.push(ServalRole::Storage);
+    match config.blob_backend {
+        Some(BlobBackend::Filesystem(storage_path)) => {
+            log::info!(
+                "serval agent blob store mounted; path={}",
+                storage_path.display()
+            );
+            roles.push(ServalRole::Storage);
+        }
+        Some(BlobBackend::S3) => {
+            log::info!("serval agent blob store backed by s3 only");
+            roles.push(ServalRole::Storage);
+        }
+        None => {}
     }
     if config.should_run_jobs {
         log::info!("job running enabled");
@@ -119,27 +216,62 @@ async fn main() -> Result<()> {
     }
 
     let (mesh_interface, mesh_port) = mesh_interface_and_port();
-    let metadata = PeerMetadata::new(
+    let mut metadata = PeerMetadata::new(
         Uuid::new_v4().to_string(),
         Some(http_addr.port()),
         roles,
         mesh_interface.ip(),
     );
+    // If the API is bound to one particular address, that's where peers have to reach it.
+    if !http_addr.ip().is_unspecified() {
+        metadata = metadata.with_http_host(http_addr.ip());
+    }
     let mut mesh = ServalMesh::new(metadata, mesh_port, Some(mesh_interface)).await?;
     mesh.start().await?;
     MESH.set(mesh).unwrap();
+    tokio::spawn(partition::watch_mesh());
 
     // And finally, listen on HTTP.
     server.await.unwrap();
     Ok(())
 }
 
+/// Run the engine's self-test job, saying how it went.
+fn engine_passes_self_test(module_cache: Option<&ModuleCache>) -> bool {
+    let result = ServalEngine::with_module_cache(HashMap::new(), module_cache)
+        .and_then(|mut engine| engine.self_test());
+    match result {
+        Ok(()) => {
+            log::info!("engine self-test passed");
+            true
+        }
+        Err(e) => {
+            log::error!("engine self-test failed; not running jobs; error={e}");
+            metrics::increment_counter!("runner:selftest_failed");
+            false
+        }
+    }
+}
+
 struct Config {
     instance_id: Uuid,
     extensions_path: Option<PathBuf>,
     should_run_jobs: bool,
     should_run_scheduler: bool,
-    blob_path: Option<PathBuf>,
+    blob_backend: Option<BlobBackend>,
+    max_memory_bytes: Option<u64>,
+    max_duration_ms: Option<u64>,
+    result_cache_limits: (usize, u64),
+    rate_limit: Option<(f64, u32)>,
+    max_concurrent_jobs: Option<usize>,
+    shared_data_path: Option<PathBuf>,
+    module_cache: Option<ModuleCache>,
+    signing_policy: SigningPolicy,
+    callback_policy: CallbackPolicy,
+    upload_limits: (u64, usize),
+    proxy_breaker: (u32, Duration),
+    response_headers: Vec<(header::HeaderName, header::HeaderValue)>,
+    mesh_watch: (Vec<SocketAddr>, Duration),
 }
 fn init_config() -> Config {
     let storage_role = match &std::env::var("STORAGE_ROLE").unwrap_or_else(|_| "auto".to_string())[..]
@@ -159,12 +291,22 @@ fn init_config() -> Config {
             false
         }
     };
-    let blob_path = if storage_role {
-        Some(
-            std::env::var("BLOB_STORE")
-                .map(PathBuf::from)
-                .unwrap_or_else(|_| std::env::temp_dir().join("serval_storage")),
-        )
+    let blob_backend = if storage_role {
+        match &std::env::var("BLOB_BACKEND").unwrap_or_else(|_| "filesystem".to_string())[..] {
+            "s3" => Some(BlobBackend::S3),
+            other => {
+                if other != "filesystem" {
+                    log::warn!(
+                        "Invalid value for BLOB_BACKEND environment variable; defaulting to 'filesystem'"
+                    );
+                }
+                Some(BlobBackend::Filesystem(
+                    std::env::var("BLOB_STORE")
+                        .map(PathBuf::from)
+                        .unwrap_or_else(|_| std::env::temp_dir().join("serval_storage")),
+                ))
+            }
+        }
     } else {
         None
     };
@@ -201,6 +343,180 @@ fn init_config() -> Config {
 
     let extensions_path = std::env::var("EXTENSIONS_PATH").ok().map(PathBuf::from);
 
+    let max_memory_bytes = std::env::var("MAX_MEMORY_BYTES").ok().map(|bytes_str| {
+        bytes_str
+            .parse::<u64>()
+            .expect("Invalid MAX_MEMORY_BYTES value; must be a number of bytes")
+    });
+
+    // The longest a job may run here, however long its manifest or its caller would let it.
+    let max_duration_ms = std::env::var("MAX_DURATION_MS").ok().map(|ms_str| {
+        ms_str
+            .parse::<u64>()
+            .expect("Invalid MAX_DURATION_MS value; must be a number of milliseconds")
+    });
+
+    // How many results of pure jobs to keep around; zero turns the cache off.
+    let result_cache_size = std::env::var("RESULT_CACHE_SIZE")
+        .ok()
+        .map(|size_str| {
+            size_str
+                .parse::<usize>()
+                .expect("Invalid RESULT_CACHE_SIZE value; must be a number of results")
+        })
+        .unwrap_or(128);
+    // How much output those results may hold between them.
+    let result_cache_bytes = std::env::var("RESULT_CACHE_BYTES")
+        .ok()
+        .map(|size_str| {
+            size_str
+                .parse::<u64>()
+                .expect("Invalid RESULT_CACHE_BYTES value; must be a number of bytes")
+        })
+        .unwrap_or(64 * 1024 * 1024);
+
+    // How many job runs per second each client may start, and how many at once; unset or zero
+    // turns rate limiting off. The burst defaults to one second's worth of runs.
+    let rate_limit = std::env::var("RATE_LIMIT_RPS")
+        .ok()
+        .map(|rate_str| {
+            rate_str
+                .parse::<f64>()
+                .expect("Invalid RATE_LIMIT_RPS value; must be a number of requests per second")
+        })
+        .filter(|rate| *rate > 0.0)
+        .map(|rate| {
+            let burst = std::env::var("RATE_LIMIT_BURST")
+                .ok()
+                .map(|burst_str| {
+                    burst_str
+                        .parse::<u32>()
+                        .expect("Invalid RATE_LIMIT_BURST value; must be a number of requests")
+                })
+                .unwrap_or_else(|| rate.ceil() as u32);
+            (rate, burst)
+        });
+
+    // How many jobs to run at once; runs past that are turned away. Unset or zero for no limit.
+    let max_concurrent_jobs = std::env::var("MAX_CONCURRENT_JOBS")
+        .ok()
+        .map(|max_str| {
+            max_str
+                .parse::<usize>()
+                .expect("Invalid MAX_CONCURRENT_JOBS value; must be a number of jobs")
+        })
+        .filter(|max| *max > 0);
+
+    // A directory of reference data that jobs can ask to read, mounted into each of them as is.
+    // It should be read-only to the agent, since jobs are given whatever access the agent has.
+    let shared_data_path = std::env::var("SHARED_DATA_PATH").ok().map(PathBuf::from);
+    if let Some(path) = &shared_data_path {
+        let metadata = std::fs::metadata(path)
+            .expect("Invalid SHARED_DATA_PATH value; must be an existing directory");
+        assert!(
+            metadata.is_dir(),
+            "Invalid SHARED_DATA_PATH value; must be an existing directory"
+        );
+        if !metadata.permissions().readonly() {
+            log::warn!(
+                "SHARED_DATA_PATH is writable; jobs could change it; path={}",
+                path.display()
+            );
+        }
+    }
+
+    // A directory to keep compiled modules in, so that jobs run again skip compilation. It may be
+    // shared by several agents on one host.
+    let module_cache = std::env::var("MODULE_CACHE").ok().map(|dir| {
+        ModuleCache::new(&PathBuf::from(dir))
+            .expect("Invalid MODULE_CACHE value; must be a directory we can create and write to")
+    });
+
+    // With RUNNER_SELFTEST=true, a node only runs jobs if it can run a tiny one of its own first.
+    let runner_selftest = std::env::var("RUNNER_SELFTEST").map_or(false, |value| value == "true");
+    let should_run_jobs =
+        should_run_jobs && (!runner_selftest || engine_passes_self_test(module_cache.as_ref()));
+
+    // The hex-encoded ed25519 public keys whose manifest signatures this node trusts, separated by
+    // commas. With REQUIRE_SIGNED=true, manifests not signed by one of them are never stored.
+    let trusted_keys = std::env::var("TRUSTED_SIGNING_KEYS").unwrap_or_default();
+    let trusted_keys: Vec<&str> = trusted_keys
+        .split(',')
+        .filter(|key| !key.trim().is_empty())
+        .collect();
+    let require_signed = std::env::var("REQUIRE_SIGNED").map_or(false, |value| value == "true");
+    let signing_policy = SigningPolicy::new(&trusted_keys, require_signed)
+        .expect("Invalid TRUSTED_SIGNING_KEYS value; must be hex-encoded ed25519 public keys");
+
+    // The hosts job callbacks may be sent to, separated by commas. If unset, callbacks may go to
+    // any host that isn't on a loopback, private, or link-local address.
+    let callback_hosts = std::env::var("CALLBACK_ALLOWED_HOSTS").unwrap_or_default();
+    let callback_hosts: Vec<&str> = callback_hosts
+        .split(',')
+        .filter(|host| !host.trim().is_empty())
+        .collect();
+    let callback_policy = CallbackPolicy::new(&callback_hosts);
+
+    // The largest executable that may be uploaded in pieces, and how many such uploads may be in
+    // progress at once. Pieces are held in memory until an upload is finished.
+    let max_upload_bytes = std::env::var("MAX_UPLOAD_BYTES")
+        .ok()
+        .map(|bytes_str| {
+            bytes_str
+                .parse::<u64>()
+                .expect("Invalid MAX_UPLOAD_BYTES value; must be a number of bytes")
+        })
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES);
+    let max_pending_uploads = std::env::var("MAX_PENDING_UPLOADS")
+        .ok()
+        .map(|max_str| {
+            max_str
+                .parse::<usize>()
+                .expect("Invalid MAX_PENDING_UPLOADS value; must be a number of uploads")
+        })
+        .unwrap_or(DEFAULT_MAX_PENDING_UPLOADS);
+
+    // How many relays to another role may fail in a row before we stop trying for a while, and
+    // how long that while is.
+    let proxy_failure_threshold = std::env::var("PROXY_FAILURE_THRESHOLD")
+        .ok()
+        .map(|count_str| {
+            count_str
+                .parse::<u32>()
+                .expect("Invalid PROXY_FAILURE_THRESHOLD value; must be a number of failures")
+        })
+        .unwrap_or(3);
+    let proxy_cooldown = std::env::var("PROXY_COOLDOWN_SECS")
+        .ok()
+        .map(|secs_str| {
+            secs_str
+                .parse::<u64>()
+                .expect("Invalid PROXY_COOLDOWN_SECS value; must be a number of seconds")
+        })
+        .map_or(Duration::from_secs(30), Duration::from_secs);
+
+    // The HTTP addresses of peers we should always be able to see, separated by commas, and how
+    // long to go on expecting any other peer after we last saw it. Missing either suggests the
+    // mesh has split.
+    let known_peers = std::env::var("KNOWN_PEERS").unwrap_or_default();
+    let known_peers: Vec<SocketAddr> = known_peers
+        .split(',')
+        .filter(|addr| !addr.trim().is_empty())
+        .map(|addr| {
+            addr.trim()
+                .parse()
+                .expect("Invalid KNOWN_PEERS value; must be HTTP addresses like 10.0.0.1:8100")
+        })
+        .collect();
+    let peer_memory = std::env::var("PEER_MEMORY_SECS")
+        .ok()
+        .map(|secs_str| {
+            secs_str
+                .parse::<u64>()
+                .expect("Invalid PEER_MEMORY_SECS value; must be a number of seconds")
+        })
+        .map_or(Duration::from_secs(600), Duration::from_secs);
+
     let instance_id: Uuid = std::env::var("INSTANCE_ID")
         .ok()
         .map(|uuid_str| {
@@ -208,26 +524,116 @@ fn init_config() -> Config {
         })
         .unwrap_or_else(Uuid::new_v4);
 
+    // Headers to add to every response, as NAME=VALUE pairs separated by commas. A value may
+    // include {instance_id}, e.g. X-Served-By={instance_id}.
+    let response_headers = parse_response_headers(
+        &std::env::var("RESPONSE_HEADERS").unwrap_or_default(),
+        &instance_id.to_string(),
+    )
+    .unwrap_or_else(|e| panic!("Invalid RESPONSE_HEADERS value; {e}"));
+
     Config {
         instance_id,
         extensions_path,
         should_run_jobs,
         should_run_scheduler,
-        blob_path,
+        blob_backend,
+        max_memory_bytes,
+        max_duration_ms,
+        result_cache_limits: (result_cache_size, result_cache_bytes),
+        rate_limit,
+        max_concurrent_jobs,
+        shared_data_path,
+        module_cache,
+        signing_policy,
+        callback_policy,
+        upload_limits: (max_upload_bytes, max_pending_uploads),
+        proxy_breaker: (proxy_failure_threshold, proxy_cooldown),
+        response_headers,
+        mesh_watch: (known_peers, peer_memory),
     }
 }
 
+/// Set up env_logger as usual, except that log lines written while handling a request are tagged
+/// with that request's id. Set `LOG_FORMAT=json` to write each line as a JSON object instead, for
+/// log collectors that would rather not parse free text.
+fn init_logging() {
+    let json = std::env::var("LOG_FORMAT").map_or(false, |format| format == "json");
+    let mut builder = env_logger::Builder::from_default_env();
+    if json {
+        builder.format(|buf, record| {
+            let mut line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "module": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(id) = current_request_id() {
+                line["request_id"] = serde_json::Value::String(id);
+            }
+            if let Some(id) = current_job_id() {
+                line["job_id"] = serde_json::Value::String(id.to_string());
+            }
+            writeln!(buf, "{line}")
+        });
+    } else {
+        builder.format(|buf, record| {
+            let level = buf.default_styled_level(record.level());
+            let timestamp = buf.timestamp();
+            let mut tags = String::new();
+            if let Some(id) = current_request_id() {
+                tags.push_str(&format!(" [{id}]"));
+            }
+            if let Some(id) = current_job_id() {
+                tags.push_str(&format!(" [job {id}]"));
+            }
+            writeln!(
+                buf,
+                "[{timestamp} {level} {}]{tags} {}",
+                record.target(),
+                record.args()
+            )
+        });
+    }
+    builder.init();
+}
+
 fn init_metrics() {
-    // TODO: This should switch on which set of metrics features we're building with.
-    let metrics_addr = std::env::var("METRICS_ADDR").unwrap_or_else(|_| "[::]:9000".to_string());
-    let addr: SocketAddr = metrics_addr.parse().unwrap();
-    let builder = TcpBuilder::new().listen_address(addr);
-
-    if let Err(err) = builder.install() {
-        if !matches!(err, metrics_exporter_tcp::Error::Io(_)) {
-            log::warn!("failed to install TCP recorder: {err:?}");
+    // Metrics are recorded the same way whichever sink they go to, so names and labels match.
+    let sink = std::env::var("METRICS_SINK").unwrap_or_else(|_| "tcp".to_string());
+    match sink.as_str() {
+        "tcp" => {
+            let metrics_addr =
+                std::env::var("METRICS_ADDR").unwrap_or_else(|_| "[::]:9000".to_string());
+            let addr: SocketAddr = metrics_addr.parse().unwrap();
+            let builder = TcpBuilder::new().listen_address(addr);
+
+            if let Err(err) = builder.install() {
+                if !matches!(err, metrics_exporter_tcp::Error::Io(_)) {
+                    log::warn!("failed to install TCP recorder: {err:?}");
+                }
+            };
         }
-    };
+        "statsd" => {
+            let statsd_addr =
+                std::env::var("STATSD_ADDR").unwrap_or_else(|_| "127.0.0.1:8125".to_string());
+            let (host, port) = statsd_addr
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+                .expect("Invalid STATSD_ADDR value; must be host:port");
+            match StatsdBuilder::from(host, port).build(None) {
+                Ok(recorder) => {
+                    if let Err(err) = metrics::set_boxed_recorder(Box::new(recorder)) {
+                        log::warn!("failed to install StatsD recorder: {err:?}");
+                    } else {
+                        log::info!("sending metrics to StatsD; addr={statsd_addr}");
+                    }
+                }
+                Err(err) => log::warn!("failed to build StatsD recorder: {err:?}"),
+            }
+        }
+        _ => panic!("Invalid METRICS_SINK value; must be tcp or statsd"),
+    }
     metrics::increment_counter!("process:start", "component" => "agent");
 }
 
@@ -236,8 +642,11 @@ fn init_router(state: &Arc<RunnerState>) -> Router {
 
     let mut router: Router<Arc<RunnerState>, Body> = Router::new()
         .route("/monitor/ping", get(ping))
-        .route("/monitor/status", get(monitor_status));
+        .route("/monitor/status", get(monitor_status))
+        .route("/monitor/version", get(monitor_version))
+        .route("/monitor/mesh", get(monitor_mesh));
     router = v1::mesh::mount(router);
+    router = openapi::mount(router);
 
     // NOTE: We have two of these now. If we develop a third, generalize this pattern.
     router = if state.has_storage {
@@ -252,9 +661,122 @@ fn init_router(state: &Arc<RunnerState>) -> Router {
         v1::jobs::mount_proxy(router)
     };
 
+    // Route layers skip the fallback, so it gets the same middleware by way of its own layers.
+    let fallback = any(v1::proxy::proxy_unavailable_services)
+        .layer(middleware::from_fn(rate_limit_jobs))
+        .layer(middleware::from_fn(response_headers))
+        .layer(middleware::from_fn(http_logging))
+        .layer(middleware::from_fn(trace_context))
+        .layer(middleware::from_fn(request_id))
+        .with_state::<()>(state.clone());
+
     router
-        .route_layer(middleware::from_fn(clacks))
+        .route_layer(middleware::from_fn(response_headers))
         .route_layer(middleware::from_fn(http_logging))
+        .route_layer(middleware::from_fn(trace_context))
+        .route_layer(middleware::from_fn(request_id))
+        .fallback_service(fallback)
         .layer(DefaultBodyLimit::max(MAX_BODY_SIZE_BYTES))
+        .layer(CompressionLayer::new().compress_when(DefaultPredicate::new().and(is_json)))
         .with_state(state.clone())
 }
+
+/// Compress only JSON responses, for clients that accept gzip. Listings and statuses shrink a
+/// lot; executables and job output are left as they are, to be streamed untouched.
+fn is_json(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::cache::ResultCache;
+
+    /// A node that only runs the HTTP API: no storage, no runner, no scheduler.
+    fn bare_state() -> Arc<RunnerState> {
+        Arc::new(RunnerState {
+            instance_id: Uuid::new_v4(),
+            extensions: HashMap::new(),
+            should_run_jobs: false,
+            should_run_scheduler: false,
+            has_storage: false,
+            running_jobs: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            max_memory_bytes: None,
+            max_duration_ms: None,
+            result_cache: Arc::new(ResultCache::new(0, 0)),
+            shared_data_path: None,
+            module_cache: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn missing_services_are_proxied_on_any_sub_path() {
+        let app = init_router(&bare_state());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+
+        // There is no mesh here to relay over, so a proxied request fails as unavailable.
+        for path in [
+            "/v1/storage/some/future/endpoint",
+            "/v1/storage",
+            "/v1/jobs",
+            "/v1/running/some-job",
+            "/v1/scheduler/queue",
+        ] {
+            let response = reqwest::get(format!("http://{addr}{path}")).await.unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "{path} was not proxied"
+            );
+            // The fallback goes through the same middleware as every route.
+            assert!(
+                response.headers().contains_key(REQUEST_ID_HEADER),
+                "{path} skipped the middleware"
+            );
+        }
+
+        let response = reqwest::get(format!("http://{addr}/v1/nonsense"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn cancelling_does_not_shadow_running_a_job_named_running() {
+        let mut state = bare_state();
+        Arc::get_mut(&mut state).unwrap().should_run_jobs = true;
+        let app = init_router(&state);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("http://{addr}/v1/jobs/running/run"))
+            .send()
+            .await
+            .unwrap();
+        assert_ne!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = client
+            .delete(format!("http://{addr}/v1/running/{}", Uuid::new_v4()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
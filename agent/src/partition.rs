@@ -0,0 +1,191 @@
+// Watching our view of the mesh for signs that it has split, so that a partition shows up in
+// `/monitor/mesh` and the logs before it shows up as jobs failing.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use utils::mesh::{KaboodleMesh, PeerMetadata};
+use utils::structs::api::{MeshHealth, MeshMember, MissingPeer};
+
+use crate::structures::MESH;
+
+/// The mesh watch for this node.
+pub static MESH_WATCH: OnceCell<MeshWatch> = OnceCell::new();
+
+/// How often to look at the mesh.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Remembers which peers this node has seen, and when, so that it can tell which ones have gone
+/// missing. Peers are told apart by their HTTP addresses, which survive a restart where their
+/// instance ids don't; peers without one are observers, which come and go as they please.
+#[derive(Debug)]
+pub struct MeshWatch {
+    known_peers: Vec<SocketAddr>,
+    memory: Duration,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    visible: Vec<MeshMember>,
+    last_seen: HashMap<SocketAddr, (String, Instant)>,
+}
+
+impl MeshWatch {
+    /// Expect to see the given peers always, and any other peer for `memory` after it was last
+    /// seen.
+    pub fn new(known_peers: Vec<SocketAddr>, memory: Duration) -> Self {
+        Self {
+            known_peers,
+            memory,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Note which peers are visible now, warning if a good share of them vanished since the last
+    /// look.
+    pub fn observe(&self, peers: Vec<PeerMetadata>) {
+        let members: Vec<MeshMember> = peers.into_iter().map(MeshMember::from).collect();
+        self.observe_at(members, Instant::now())
+    }
+
+    fn observe_at(&self, peers: Vec<MeshMember>, now: Instant) {
+        let visible: Vec<MeshMember> = peers
+            .into_iter()
+            .filter(|peer| peer.http_address.is_some())
+            .collect();
+
+        let mut seen = self.seen.lock().unwrap();
+        let previous = seen.visible.len();
+        for peer in &visible {
+            if let Some(addr) = peer.http_address {
+                seen.last_seen.insert(addr, (peer.instance_id.clone(), now));
+            }
+        }
+        seen.last_seen.retain(|addr, (_, at)| {
+            now.duration_since(*at) < self.memory || self.known_peers.contains(addr)
+        });
+
+        // Losing half our peers or more between one look and the next is no ordinary churn.
+        if visible.len() < previous && visible.len() * 2 <= previous {
+            log::warn!(
+                "mesh peer count dropped sharply; was={previous}; now={}",
+                visible.len()
+            );
+            metrics::increment_counter!("mesh:peer_count_drop");
+        }
+        seen.visible = visible;
+    }
+
+    /// Report on the peers visible at the last look, and the ones that should have been.
+    pub fn health(&self) -> MeshHealth {
+        self.health_at(Instant::now())
+    }
+
+    fn health_at(&self, now: Instant) -> MeshHealth {
+        let seen = self.seen.lock().unwrap();
+        let is_visible = |addr: &SocketAddr| {
+            seen.visible
+                .iter()
+                .any(|peer| peer.http_address.as_ref() == Some(addr))
+        };
+
+        let mut missing_peers: Vec<MissingPeer> = seen
+            .last_seen
+            .iter()
+            .filter(|(addr, _)| !is_visible(*addr))
+            .map(|(addr, (instance_id, at))| MissingPeer {
+                http_address: *addr,
+                instance_id: Some(instance_id.clone()),
+                last_seen_secs_ago: Some(now.duration_since(*at).as_secs()),
+                known: self.known_peers.contains(addr),
+            })
+            .collect();
+        missing_peers.extend(
+            self.known_peers
+                .iter()
+                .filter(|addr| !is_visible(*addr) && !seen.last_seen.contains_key(*addr))
+                .map(|addr| MissingPeer {
+                    http_address: *addr,
+                    instance_id: None,
+                    last_seen_secs_ago: None,
+                    known: true,
+                }),
+        );
+        missing_peers.sort_by_key(|peer| peer.http_address);
+
+        MeshHealth {
+            visible_peers: seen.visible.clone(),
+            suspected_partition: !missing_peers.is_empty(),
+            missing_peers,
+        }
+    }
+}
+
+/// Look at the mesh every few seconds for as long as the agent runs.
+pub async fn watch_mesh() {
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let (Some(mesh), Some(watch)) = (MESH.get(), MESH_WATCH.get()) else {
+            continue;
+        };
+        watch.observe(mesh.peers().await);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(port: u16) -> MeshMember {
+        MeshMember {
+            http_address: Some(SocketAddr::from(([10, 0, 0, 1], port))),
+            instance_id: format!("node-{port}"),
+            weight: 1,
+        }
+    }
+
+    #[test]
+    fn reports_peers_that_went_missing() {
+        let known = SocketAddr::from(([10, 0, 0, 1], 8100));
+        let never_seen = SocketAddr::from(([10, 0, 0, 2], 8100));
+        let watch = MeshWatch::new(vec![known, never_seen], Duration::from_secs(60));
+        let start = Instant::now();
+
+        watch.observe_at(vec![member(8100), member(8101), member(8102)], start);
+        let health = watch.health_at(start);
+        assert_eq!(health.visible_peers.len(), 3);
+        assert_eq!(health.missing_peers.len(), 1);
+        assert_eq!(health.missing_peers[0].http_address, never_seen);
+
+        // Two peers vanish; both are missed, the known one for good and the other for a while.
+        let later = start + Duration::from_secs(10);
+        watch.observe_at(vec![member(8101)], later);
+        let health = watch.health_at(later);
+        assert!(health.suspected_partition);
+        let missing: Vec<_> = health
+            .missing_peers
+            .iter()
+            .map(|peer| (peer.http_address, peer.last_seen_secs_ago, peer.known))
+            .collect();
+        assert_eq!(
+            missing,
+            vec![
+                (known, Some(10), true),
+                (member(8102).http_address.unwrap(), Some(10), false),
+                (never_seen, None, true),
+            ]
+        );
+
+        // Peers nobody asked us to expect are forgotten eventually.
+        let much_later = start + Duration::from_secs(120);
+        watch.observe_at(vec![member(8101)], much_later);
+        let health = watch.health_at(much_later);
+        assert_eq!(health.missing_peers.len(), 2);
+        assert!(health.missing_peers.iter().all(|peer| peer.known));
+    }
+}
@@ -0,0 +1,96 @@
+// Per-client rate limiting for the endpoints that start work, so that one misbehaving client
+// can't flood a shared mesh with jobs.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+
+/// The rate limiter for this node, if `RATE_LIMIT_RPS` turned one on.
+pub static RATE_LIMITER: OnceCell<RateLimiter> = OnceCell::new();
+
+/// How many clients we track before forgetting the ones whose buckets have refilled.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// A token bucket per client address. Each request takes a token; tokens come back at a steady
+/// rate, up to the burst size.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allow each client `rate` requests per second on average, and up to `burst` at once.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for the given client. If it has none left, says how long until it will.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| bucket.refilled(now, rate) < burst);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64) -> f64 {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_client_separately() {
+        let limiter = RateLimiter::new(1.0, 2);
+        let (alice, bob): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check_at(alice, start).is_ok());
+        assert!(limiter.check_at(alice, start).is_ok());
+        let retry_after = limiter.check_at(alice, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+        assert!(limiter.check_at(bob, start).is_ok());
+
+        let later = start + Duration::from_millis(1500);
+        assert!(limiter.check_at(alice, later).is_ok());
+        assert!(limiter.check_at(alice, later).is_err());
+    }
+}
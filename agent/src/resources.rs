@@ -0,0 +1,41 @@
+// Cheap readings of how loaded this machine is, for reporting via `/monitor/status`. These come
+// straight from procfs, so on platforms without it we simply report nothing.
+
+/// The one-minute load average.
+pub fn load_average() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    parse_loadavg(&loadavg)
+}
+
+/// Memory available for starting new work without swapping, in bytes.
+pub fn available_memory_bytes() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    parse_meminfo_available(&meminfo)
+}
+
+fn parse_loadavg(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+fn parse_meminfo_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_procfs() {
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/467 12345\n"), Some(0.52));
+        assert_eq!(parse_loadavg(""), None);
+
+        let meminfo = "MemTotal:       16326364 kB\nMemFree:         1104456 kB\nMemAvailable:    9170436 kB\n";
+        assert_eq!(parse_meminfo_available(meminfo), Some(9170436 * 1024));
+        assert_eq!(parse_meminfo_available("MemTotal: 1 kB\n"), None);
+    }
+}
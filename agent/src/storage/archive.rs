@@ -0,0 +1,116 @@
+// Just enough of the ustar format to write and read back the archives made by storage export.
+// Every entry is a plain file; anything else in an archive we're handed is skipped.
+
+use utils::errors::{ServalError, ServalResult};
+
+const BLOCK: usize = 512;
+
+/// Write the given files, in order, as a tar archive.
+pub fn write_tar(entries: &[(String, Vec<u8>)]) -> ServalResult<Vec<u8>> {
+    let mut out = Vec::new();
+    for (path, data) in entries {
+        if path.len() > 100 {
+            return Err(ServalError::StorageError(format!(
+                "archive path is longer than tar allows; path={path}"
+            )));
+        }
+        let mut header = [0u8; BLOCK];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // The checksum is taken with its own field filled with spaces.
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        out.extend_from_slice(&header);
+        out.extend_from_slice(data);
+        out.resize(out.len() + padding(data.len()), 0);
+    }
+    // Two empty blocks mark the end of the archive.
+    out.resize(out.len() + 2 * BLOCK, 0);
+    Ok(out)
+}
+
+/// Read every plain file out of a tar archive, as (path, contents) pairs.
+pub fn read_tar(bytes: &[u8]) -> ServalResult<Vec<(String, Vec<u8>)>> {
+    let invalid = |why: &str| ServalError::StorageError(format!("not a usable tar archive: {why}"));
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK <= bytes.len() {
+        let header = &bytes[offset..offset + BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let stored = octal(&header[148..156]).ok_or_else(|| invalid("bad checksum field"))?;
+        let actual: u64 = header[..148]
+            .iter()
+            .chain([b' '; 8].iter())
+            .chain(header[156..].iter())
+            .map(|&b| b as u64)
+            .sum();
+        if stored != actual {
+            return Err(invalid("header checksum mismatch"));
+        }
+
+        let name_end = header[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        let path = String::from_utf8(header[..name_end].to_vec())
+            .map_err(|_| invalid("path is not utf-8"))?;
+        let size = octal(&header[124..136]).ok_or_else(|| invalid("bad size field"))? as usize;
+        let start = offset + BLOCK;
+        if start + size > bytes.len() {
+            return Err(invalid("entry runs past the end"));
+        }
+        if header[156] == b'0' || header[156] == 0 {
+            entries.push((path, bytes[start..start + size].to_vec()));
+        }
+        offset = start + size + padding(size);
+    }
+    Ok(entries)
+}
+
+fn padding(len: usize) -> usize {
+    (BLOCK - len % BLOCK) % BLOCK
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits: String = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ')
+        .map(|&b| b as char)
+        .collect();
+    u64::from_str_radix(&digits, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tar_roundtrip() {
+        let entries = vec![
+            ("index.json".to_string(), b"{}".to_vec()),
+            (
+                "executables/sh.serval.loudify.1.0.0.wasm".to_string(),
+                vec![7u8; 1000],
+            ),
+            ("empty".to_string(), vec![]),
+        ];
+        let archive = write_tar(&entries).unwrap();
+        assert_eq!(archive.len() % BLOCK, 0);
+        assert_eq!(read_tar(&archive).unwrap(), entries);
+
+        let mut corrupted = archive.clone();
+        corrupted[0] = b'X';
+        assert!(read_tar(&corrupted).is_err());
+    }
+}
@@ -61,6 +61,13 @@ impl BlobStore {
         Ok(stream)
     }
 
+    /// Read a whole blob from the content store by its content address. Content stored this way
+    /// has no key, so this is the only way to read it without streaming it.
+    pub async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        let bytes = cacache::read_hash(&self.location, integrity).await?;
+        Ok(bytes)
+    }
+
     /// Checks if the given blob is in the content store, by its SRI string.
     pub async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
         Ok(cacache::exists(&self.location, integrity).await)
@@ -74,6 +81,14 @@ impl BlobStore {
         }
     }
 
+    /// Look up the integrity checksum that data was stored under, by key.
+    pub async fn integrity_by_key(&self, key: &str) -> ServalResult<Integrity> {
+        match cacache::metadata(&self.location, key).await? {
+            Some(metadata) => Ok(metadata.integrity),
+            None => Err(ServalError::DataNotFound(key.to_string())),
+        }
+    }
+
     /// A non-streaming way to retrieve a stored data blob.. Prefer stream_by_key() if you do not
     /// need the bytes in memory.
     pub async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
@@ -89,6 +104,15 @@ impl BlobStore {
         Ok(stream)
     }
 
+    /// List every key in the store.
+    pub fn keys(&self) -> ServalResult<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in cacache::list_sync(&self.location) {
+            keys.push(entry?.key);
+        }
+        Ok(keys)
+    }
+
     /// Store data in our blob store by key. Returns the integrity checksum.
     pub async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
         let sri = cacache::write(&self.location, key, bytes).await?;
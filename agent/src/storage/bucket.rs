@@ -12,8 +12,22 @@ pub struct S3Storage {
 }
 
 impl S3Storage {
-    pub fn new(bucket_name: &str, config: aws_config::SdkConfig) -> ServalResult<Self> {
-        let client = s3::Client::new(&config);
+    /// Create a client for the named bucket. Pass an endpoint to use an S3-compatible service
+    /// other than AWS; those generally want path-style addressing, so we switch it on too.
+    pub fn new(
+        bucket_name: &str,
+        config: aws_config::SdkConfig,
+        endpoint: Option<String>,
+    ) -> ServalResult<Self> {
+        let client = if let Some(endpoint) = endpoint {
+            let s3_config = s3::config::Builder::from(&config)
+                .endpoint_url(endpoint)
+                .force_path_style(true)
+                .build();
+            s3::Client::from_conf(s3_config)
+        } else {
+            s3::Client::new(&config)
+        };
 
         Ok(S3Storage {
             client,
@@ -21,6 +35,23 @@ impl S3Storage {
         })
     }
 
+    /// A client for a bucket on a local S3 stand-in, with made-up credentials.
+    #[cfg(test)]
+    pub fn for_testing(endpoint: &str) -> Self {
+        let config = s3::config::Builder::new()
+            .region(s3::config::Region::new("us-east-1"))
+            .credentials_provider(s3::config::Credentials::new(
+                "test", "test", None, None, "test",
+            ))
+            .endpoint_url(endpoint)
+            .force_path_style(true)
+            .build();
+        S3Storage {
+            client: s3::Client::from_conf(config),
+            bucket: "serval-test".to_string(),
+        }
+    }
+
     /// Check if the given data blob is present in our data store, by integrity hash. Returns a stream.
     pub async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<ByteStream> {
         let object = self
@@ -34,6 +65,33 @@ impl S3Storage {
         Ok(object.body)
     }
 
+    /// Fetch a whole data blob by its integrity hash. Data blobs are stored under their address
+    /// with no key file, so this is how they are read back. The bytes aren't checked here: an
+    /// encrypted blob is filed under the address of what it decrypts to, not of itself.
+    pub async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(encode(&integrity.to_string()))
+            .send()
+            .await?;
+        let bytes = object.body.collect().await?.into_bytes().to_vec();
+        Ok(bytes)
+    }
+
+    /// Check if a data blob is present in the bucket, by integrity hash.
+    pub async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(encode(&integrity.to_string()))
+            .send()
+            .await;
+        Ok(result.is_ok())
+    }
+
     pub async fn store_by_integrity(
         &self,
         integrity: &Integrity,
@@ -76,18 +134,22 @@ impl S3Storage {
         Ok(result.is_ok())
     }
 
-    /// Fetch data from the store by key. Returns a vec of u8.
+    /// Fetch data from the store by key. Returns a vec of u8. The data is checked against the
+    /// integrity checksum it was stored under.
     pub async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
-        let integrity = self.lookup_integrity(key).await?;
+        let integrity = self.integrity_by_key(key).await?;
         let object = self
             .client
             .get_object()
             .bucket(&self.bucket)
-            .key(&integrity)
+            .key(encode(&integrity.to_string()))
             .send()
             .await?;
-        let chunks = object.body.collect().await?;
-        Ok(chunks.into_bytes().to_vec())
+        let bytes = object.body.collect().await?.into_bytes().to_vec();
+        if integrity.check(&bytes).is_err() {
+            return Err(ServalError::IntegrityMismatch(key.to_string()));
+        }
+        Ok(bytes)
     }
 
     /// Fetch data by key as a readable byte stream.
@@ -104,9 +166,46 @@ impl S3Storage {
         Ok(object.body)
     }
 
+    /// List every key stored in the bucket, not including the integrity key files themselves.
+    pub async fn keys(&self) -> ServalResult<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let output = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .map_err(|e| {
+                    ServalError::StorageError(format!(
+                        "unable to list bucket contents; error={}",
+                        e.message().unwrap_or("cannot get error message from AWS")
+                    ))
+                })?;
+            for object in output.contents().unwrap_or_default() {
+                if let Some(key) = object.key().and_then(|k| k.strip_suffix(".integrity")) {
+                    keys.push(key.to_string());
+                }
+            }
+            match output.next_continuation_token() {
+                Some(token) if output.is_truncated() => continuation_token = Some(token.to_string()),
+                _ => break,
+            }
+        }
+        Ok(keys)
+    }
+
     /// Look up an integrity checksum for a given key. Url-encodes the integrity string.
-    /// Really cheap index. Feel free to replace.
     async fn lookup_integrity(&self, key: &str) -> ServalResult<String> {
+        let integrity = self.integrity_by_key(key).await?;
+        Ok(encode(&integrity.to_string()).to_string())
+    }
+
+    /// Look up the integrity checksum that data was stored under, by key.
+    /// Really cheap index. Feel free to replace.
+    pub async fn integrity_by_key(&self, key: &str) -> ServalResult<Integrity> {
         let keyfile = format!("{key}.integrity");
         match self
             .client
@@ -120,8 +219,7 @@ impl S3Storage {
                 let chunks = object.body.collect().await?;
                 let bytes = chunks.into_bytes().to_vec();
                 let integrity_string = String::from_utf8(bytes)?;
-                let encoded = encode(&integrity_string);
-                Ok(encoded.to_string())
+                Ok(integrity_string.parse()?)
             }
             Err(e) => {
                 log::info!(
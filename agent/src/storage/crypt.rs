@@ -0,0 +1,113 @@
+// Encrypting the data blobs a storage node holds, so that job inputs and outputs can't be read by
+// whoever has access to its disk or its bucket.
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+
+/// Marks a blob as sealed by a `BlobCipher`, and says how it was sealed.
+const MAGIC: &[u8] = b"serval-sealed-v1\n";
+const NONCE_LEN: usize = 12;
+/// A data key once wrapped: the key itself, plus the tag that authenticates it.
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+
+/// Seals data blobs with envelope encryption. Each blob gets a fresh random data key, which is
+/// itself sealed with a key-encrypting key derived from the node secret and stored with the blob.
+///
+/// Blobs are still addressed by the integrity of their plaintext, so addresses don't change when
+/// encryption is turned on. Each blob is bound to its address, so sealed bytes can't be passed off
+/// as some other blob.
+#[derive(Clone)]
+pub struct BlobCipher {
+    kek: Aes256Gcm,
+}
+
+impl std::fmt::Debug for BlobCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlobCipher").finish_non_exhaustive()
+    }
+}
+
+impl BlobCipher {
+    /// Derive the key-encrypting key from a node secret, such as `STORAGE_ENCRYPTION_KEY`. Every
+    /// storage node sharing a bucket must be given the same secret.
+    pub fn new(secret: &[u8]) -> Self {
+        let mut kek = [0u8; 32];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(b"serval storage key-encrypting key", &mut kek)
+            .expect("32 bytes is a valid length for HKDF-SHA256 output");
+        Self {
+            kek: Aes256Gcm::new(&kek.into()),
+        }
+    }
+
+    /// Returns true if the bytes look like a blob sealed by a `BlobCipher`.
+    pub fn is_sealed(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Seal a blob that is to be stored under the given integrity.
+    pub fn seal(&self, integrity: &Integrity, plaintext: &[u8]) -> ServalResult<Vec<u8>> {
+        let address = integrity.to_string();
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let key_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let wrapped_key = self
+            .kek
+            .encrypt(&key_nonce, aad(&data_key, &address))
+            .map_err(|_| cipher_failed("seal", &address))?;
+        let data_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(&data_nonce, aad(plaintext, &address))
+            .map_err(|_| cipher_failed("seal", &address))?;
+
+        let mut sealed =
+            Vec::with_capacity(MAGIC.len() + 2 * NONCE_LEN + WRAPPED_KEY_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&key_nonce);
+        sealed.extend_from_slice(&wrapped_key);
+        sealed.extend_from_slice(&data_nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Open a blob sealed under the given integrity, checking that what comes out matches it.
+    pub fn open(&self, integrity: &Integrity, sealed: &[u8]) -> ServalResult<Vec<u8>> {
+        let address = integrity.to_string();
+        let rest = sealed
+            .strip_prefix(MAGIC)
+            .filter(|rest| rest.len() >= 2 * NONCE_LEN + WRAPPED_KEY_LEN)
+            .ok_or_else(|| cipher_failed("open", &address))?;
+        let (key_nonce, rest) = rest.split_at(NONCE_LEN);
+        let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+        let (data_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let data_key = self
+            .kek
+            .decrypt(Nonce::from_slice(key_nonce), aad(wrapped_key, &address))
+            .map_err(|_| cipher_failed("open", &address))?;
+        let plaintext = Aes256Gcm::new_from_slice(&data_key)
+            .map_err(|_| cipher_failed("open", &address))?
+            .decrypt(Nonce::from_slice(data_nonce), aad(ciphertext, &address))
+            .map_err(|_| cipher_failed("open", &address))?;
+        if integrity.check(&plaintext).is_err() {
+            return Err(cipher_failed("open", &address));
+        }
+        Ok(plaintext)
+    }
+}
+
+fn aad<'a>(msg: &'a [u8], address: &'a str) -> Payload<'a, 'a> {
+    Payload {
+        msg,
+        aad: address.as_bytes(),
+    }
+}
+
+fn cipher_failed(action: &str, address: &str) -> ServalError {
+    ServalError::StorageError(format!(
+        "unable to {action} encrypted blob; integrity={address}; is STORAGE_ENCRYPTION_KEY the same on every storage node?"
+    ))
+}
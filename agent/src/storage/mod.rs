@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::path::PathBuf;
 use std::pin::Pin;
 
@@ -12,14 +13,22 @@ use tokio::io::AsyncRead;
 use tokio_util::io::{ReaderStream, StreamReader};
 use utils::errors::{ServalError, ServalResult};
 use utils::mesh::ServalRole;
+use utils::signing::SigningPolicy;
 use utils::structs::Manifest;
 
 pub mod blobs;
 pub use blobs::*;
 
+pub mod archive;
+
 pub mod bucket;
 pub use bucket::S3Storage;
 
+pub mod crypt;
+pub use crypt::BlobCipher;
+
+pub mod uploads;
+
 use crate::structures::MESH;
 
 // A convenient alias for an often-used stream type.
@@ -28,8 +37,24 @@ type SendableStream = Pin<Box<dyn AsyncRead + Send + 'static>>;
 /// Our fully-configured storage object, with all of its details hidden.
 pub static STORAGE: OnceCell<Storage> = OnceCell::new();
 
+/// Which manifest signatures this node trusts, and whether it stores unsigned manifests.
+pub static SIGNING_POLICY: OnceCell<SigningPolicy> = OnceCell::new();
+
+/// Where a storage node keeps its blobs. Selected with the `BLOB_BACKEND` env var.
+#[derive(Debug, Clone)]
+pub enum BlobBackend {
+    /// A cacache store on local disk. If `STORAGE_BUCKET` is also set, writes are mirrored to it.
+    Filesystem(PathBuf),
+    /// An S3-compatible bucket and nothing else; for storage nodes on ephemeral instances.
+    S3,
+}
+
 /// Initialize our local storage and a proxy option if we have no storage ourselves.
-pub async fn initialize(path: Option<PathBuf>) -> ServalResult<()> {
+pub async fn initialize(backend: Option<BlobBackend>) -> ServalResult<()> {
+    let path = match &backend {
+        Some(BlobBackend::Filesystem(path)) => Some(path),
+        _ => None,
+    };
     let local = if let Some(blobpath) = path {
         match BlobStore::new(&blobpath) {
             Ok(v) => Some(v),
@@ -52,14 +77,32 @@ pub async fn initialize(path: Option<PathBuf>) -> ServalResult<()> {
         .or_default_provider()
         .or_else(Region::new("us-east-2"));
         let config = aws_config::from_env().region(region_provider).load().await;
-        let bucket = S3Storage::new(&bucket_name, config)?;
+        // Set this to talk to an S3-compatible service such as MinIO rather than AWS itself.
+        let endpoint = std::env::var("STORAGE_ENDPOINT").ok();
+        let bucket = S3Storage::new(&bucket_name, config, endpoint)?;
         log::info!("s3 storage bucket enabled at {bucket_name}");
         Some(bucket)
     } else {
         None
     };
 
-    let store = Storage::new(bucket, local);
+    if matches!(backend, Some(BlobBackend::S3)) && bucket.is_none() {
+        return Err(ServalError::StorageError(
+            "BLOB_BACKEND is s3, but no STORAGE_BUCKET was configured".to_string(),
+        ));
+    }
+
+    let mut store = Storage::new(bucket, local);
+    // Set this to keep the data blobs holding job inputs and outputs encrypted at rest.
+    if let Ok(secret) = std::env::var("STORAGE_ENCRYPTION_KEY") {
+        if secret.is_empty() {
+            return Err(ServalError::StorageError(
+                "STORAGE_ENCRYPTION_KEY is set, but empty".to_string(),
+            ));
+        }
+        log::info!("data blobs will be encrypted at rest");
+        store = store.with_cipher(BlobCipher::new(secret.as_bytes()));
+    }
     STORAGE.set(store).unwrap();
     Ok(())
 }
@@ -72,15 +115,30 @@ pub async fn initialize(path: Option<PathBuf>) -> ServalResult<()> {
 ///
 /// If the operation is a read operation, it tries local storage first then falls back to s3 storage
 /// if that is available. If it's a write operation, it will always try all configured options.
+///
+/// Given a cipher, it encrypts data blobs on their way in and decrypts them on their way out.
+/// Manifests, executables, and tags are stored as they are.
 #[derive(Debug, Clone)]
 pub struct Storage {
     bucket: Option<S3Storage>,
     local: Option<BlobStore>,
+    cipher: Option<BlobCipher>,
 }
 
 impl Storage {
     pub fn new(bucket: Option<S3Storage>, local: Option<BlobStore>) -> Self {
-        Self { bucket, local }
+        Self {
+            bucket,
+            local,
+            cipher: None,
+        }
+    }
+
+    /// Encrypt data blobs at rest with the given cipher. Blobs stored before encryption was
+    /// turned on are still found by their content address and read as they are.
+    pub fn with_cipher(mut self, cipher: BlobCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
     }
 
     fn has_storage(&self) -> bool {
@@ -98,15 +156,27 @@ impl Storage {
             return proxy.store_by_integrity(bytes.to_vec()).await;
         }
 
-        let local_result = if let Some(local) = &self.local {
-            Some(local.store_by_integrity(bytes).await)
-        } else {
-            None
+        let integrity = Integrity::from(bytes);
+        let sealed = match &self.cipher {
+            Some(cipher) => Some(cipher.seal(&integrity, bytes)?),
+            None => None,
+        };
+
+        let local_result = match (&self.local, &sealed) {
+            // Sealed bytes don't hash to the blob's integrity, so they're filed under it instead.
+            (Some(local), Some(sealed)) => Some(
+                local
+                    .store_by_key(&integrity.to_string(), sealed)
+                    .await
+                    .map(|_| integrity.clone()),
+            ),
+            (Some(local), None) => Some(local.store_by_integrity(bytes).await),
+            (None, _) => None,
         };
 
         let bucket_result = if let Some(bucket) = &self.bucket {
-            let integrity = Integrity::from(bytes);
-            Some(bucket.store_by_integrity(&integrity, bytes).await)
+            let stored = sealed.as_deref().unwrap_or(bytes);
+            Some(bucket.store_by_integrity(&integrity, stored).await)
         } else {
             None
         };
@@ -116,7 +186,6 @@ impl Storage {
         } else if let Some(result) = bucket_result {
             result
         } else {
-            let integrity = Integrity::from(bytes);
             Err(ServalError::StorageError(format!(
                 "all storage attempts failed for data blob; len={}; calculated integrity={integrity}",
                 bytes.len()
@@ -135,6 +204,13 @@ impl Storage {
             return Ok(StreamBody::new(reader));
         }
 
+        // Sealed blobs have to be opened whole before any of them can be sent.
+        if self.cipher.is_some() {
+            let bytes = self.data_by_integrity(integrity).await?;
+            let reader = ReaderStream::new(vec_to_byte_stream(bytes));
+            return Ok(StreamBody::new(reader));
+        }
+
         if let Some(local) = &self.local {
             if let Ok(v) = local.stream_by_integrity(&integrity).await {
                 log::info!("serving from local blobs; {integrity}");
@@ -165,35 +241,61 @@ impl Storage {
             return Ok(bytes);
         }
 
+        // Plain blobs are stored by content address, with no key to look them up by.
         if let Some(local) = &self.local {
-            if let Ok(bytes) = local.data_by_key(&integrity_string).await {
+            if let Ok(bytes) = local.data_by_integrity(&integrity).await {
                 log::info!("serving from local blobs; {integrity}");
                 return Ok(bytes);
             }
+            if let Ok(bytes) = local.data_by_key(&integrity_string).await {
+                log::info!("serving from local blobs; {integrity}");
+                return self.unseal(&integrity, bytes);
+            }
         }
 
         if let Some(bucket) = &self.bucket {
-            if let Ok(bytes) = bucket.data_by_key(&integrity_string).await {
+            if let Ok(bytes) = bucket.data_by_integrity(&integrity).await {
                 log::info!("serving from s3 bucket; {integrity}");
-                return Ok(bytes);
+                return self.unseal(&integrity, bytes);
             }
         }
 
         Err(ServalError::DataNotFound(integrity.to_string()))
     }
 
+    // Decrypt a data blob if it was stored encrypted; check it against its address if it wasn't.
+    // Opening a sealed blob checks it already, and its own bytes don't hash to the address.
+    fn unseal(&self, integrity: &Integrity, bytes: Vec<u8>) -> ServalResult<Vec<u8>> {
+        if !BlobCipher::is_sealed(&bytes) {
+            if integrity.check(&bytes).is_err() {
+                return Err(ServalError::IntegrityMismatch(integrity.to_string()));
+            }
+            return Ok(bytes);
+        }
+        match &self.cipher {
+            Some(cipher) => cipher.open(integrity, &bytes),
+            None => Err(ServalError::StorageError(format!(
+                "blob is encrypted, but this node has no STORAGE_ENCRYPTION_KEY; integrity={integrity}"
+            ))),
+        }
+    }
+
     /// Check if the given manifest is present in our store, using the fully-qualified name.
     ///
     /// Never checks a proxy; this is intended to be a local check.
     pub async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        // Sealed blobs are filed under their address as a key, plain ones under the address itself.
         if let Some(local) = &self.local {
-            if let Ok(_v) = local.data_exists_by_integrity(integrity).await {
+            if let Ok(true) = local.data_exists_by_integrity(integrity).await {
+                return Ok(true);
+            }
+            if let Ok(true) = local.data_exists_by_key(&integrity.to_string()).await {
                 return Ok(true);
             }
         }
 
         if let Some(bucket) = &self.bucket {
-            if let Ok(_v) = bucket.data_exists_by_key(&integrity.to_string()).await {
+            if let Ok(true) = bucket.data_exists_by_integrity(integrity).await {
                 return Ok(true);
             }
         }
@@ -226,7 +328,7 @@ impl Storage {
         Ok(false)
     }
 
-    /// Fetch a manifest by its fully-qualified name.
+    /// Fetch a manifest by its fully-qualified name, scoped to its tenant if it has one.
     pub async fn manifest(&self, fq_name: &str) -> ServalResult<Manifest> {
         if !self.has_storage() {
             let proxy = make_proxy_client().await?;
@@ -256,15 +358,307 @@ impl Storage {
         Err(ServalError::ManifestNotFound(fq_name.to_string()))
     }
 
-    /// Store a Wasm manifest. Returns the integrity checksum.
-    pub async fn store_manifest(&self, manifest: &Manifest) -> ServalResult<Integrity> {
+    /// Fetch the manifest that was stored with the given version of the named job. Versions stored
+    /// before manifests were kept per version can only be found if they are still the latest.
+    pub async fn manifest_version(&self, fq_name: &str, version: &str) -> ServalResult<Manifest> {
         if !self.has_storage() {
             let proxy = make_proxy_client().await?;
+            return proxy.get_manifest_version(fq_name, version).await;
+        }
+
+        let key = Manifest::make_versioned_manifest_key(fq_name, version);
+        if let Ok(bytes) = self.data_by_key(&key).await {
+            return Ok(toml::from_str(&String::from_utf8(bytes)?)?);
+        }
+        let name = format!("{fq_name}@{version}");
+        match self.manifest(fq_name).await {
+            Ok(manifest) if manifest.version() == version => Ok(manifest),
+            _ => Err(ServalError::ManifestNotFound(name)),
+        }
+    }
+
+    /// List every stored manifest, across all of our storage options. Given a tenant, only that
+    /// tenant's manifests are listed.
+    pub async fn manifests(&self, tenant: Option<&str>) -> ServalResult<Vec<Manifest>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?.with_tenant(tenant);
+            return proxy.list_manifests(None, None).await;
+        }
+
+        // Every key starts with the empty prefix, so without a tenant, leave out tenants' keys.
+        let prefix = Manifest::scoped_name(tenant, "");
+        let keys = self.keys().await?;
+        let mut names: Vec<&str> = keys
+            .iter()
+            .filter(|key| key.starts_with(&prefix))
+            .filter(|key| tenant.is_some() || !key.contains(':'))
+            .filter_map(|key| key.strip_suffix(".manifest.toml"))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut manifests = Vec::with_capacity(names.len());
+        for name in names {
+            match self.manifest(name).await {
+                Ok(manifest) => manifests.push(manifest),
+                Err(e) => log::warn!("unable to read listed manifest; name={name}; error={e}"),
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// List every stored version of the named executable, oldest first. Versions that are valid
+    /// semver are ordered as such, and sort before any that are not.
+    pub async fn versions(&self, fq_name: &str) -> ServalResult<Vec<String>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.manifest_versions(fq_name).await;
+        }
+
+        let prefix = format!("{fq_name}.");
+        let keys = self.keys().await?;
+        let mut candidates: Vec<&str> = keys
+            .iter()
+            .filter_map(|key| key.strip_prefix(&prefix)?.strip_suffix(".wasm"))
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        // Keys don't say where the name ends: `a.b.c.1.0.0.wasm` could be version 1.0.0 of
+        // `a.b.c` or version c.1.0.0 of `a.b`. Only count a version whose manifest agrees.
+        let mut versions = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            match self.manifest_version(fq_name, candidate).await {
+                Ok(manifest) if manifest.version() == candidate => versions.push(candidate),
+                _ => {}
+            }
+        }
+        versions.sort_unstable_by(|a, b| {
+            match (semver::Version::parse(a), semver::Version::parse(b)) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => a.cmp(b),
+            }
+        });
+        Ok(versions.into_iter().map(str::to_string).collect())
+    }
+
+    /// Look up the version that a version tag such as `stable` points at for the named job.
+    pub async fn version_tag(&self, fq_name: &str, tag: &str) -> ServalResult<String> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.get_version_tag(fq_name, tag).await;
+        }
+
+        let bytes = self
+            .data_by_key(&Manifest::make_version_tag_key(fq_name, tag))
+            .await?;
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    /// Point a version tag for the named job at one of its stored versions, replacing whatever it
+    /// pointed at before. Fails if there is no executable stored for that version.
+    pub async fn set_version_tag(
+        &self,
+        fq_name: &str,
+        tag: &str,
+        version: &str,
+    ) -> ServalResult<()> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.set_version_tag(fq_name, tag, version).await;
+        }
+
+        if !self.versions(fq_name).await?.iter().any(|v| v == version) {
+            return Err(ServalError::ExecutableNotFound(format!(
+                "{fq_name}@{version}"
+            )));
+        }
+        let key = Manifest::make_version_tag_key(fq_name, tag);
+        if let Some(local) = &self.local {
+            local.store_by_key(&key, version.as_bytes()).await?;
+        }
+        if let Some(bucket) = &self.bucket {
+            bucket.store_by_key(&key, version.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Work out which stored version of the named job a reference like `name@1.2.0` or
+    /// `name@stable` means: a stored version by that name, or else the version a tag points at.
+    pub async fn resolve_version(&self, fq_name: &str, reference: &str) -> ServalResult<String> {
+        if self.versions(fq_name).await?.iter().any(|v| v == reference) {
+            return Ok(reference.to_string());
+        }
+        if Manifest::is_valid_version_tag(reference) {
+            if let Ok(version) = self.version_tag(fq_name, reference).await {
+                return Ok(version);
+            }
+        }
+        Err(ServalError::ExecutableNotFound(format!(
+            "{fq_name}@{reference}"
+        )))
+    }
+
+    /// Fetch the manifest that was stored with the version a reference like `name@1.2.0` or
+    /// `name@stable` means, so that the job runs with that version's settings and not the latest.
+    pub async fn manifest_for_reference(
+        &self,
+        fq_name: &str,
+        reference: &str,
+    ) -> ServalResult<Manifest> {
+        let version = self.resolve_version(fq_name, reference).await?;
+        self.manifest_version(fq_name, &version).await
+    }
+
+    // All keys in all of our storage options; there may be duplicates.
+    async fn keys(&self) -> ServalResult<Vec<String>> {
+        let mut keys = Vec::new();
+        if let Some(local) = &self.local {
+            keys.extend(local.keys()?);
+        }
+        if let Some(bucket) = &self.bucket {
+            keys.extend(bucket.keys().await?);
+        }
+        Ok(keys)
+    }
+
+    /// Read every stored manifest, executable, and version tag, for every tenant, as (key, bytes)
+    /// pairs sorted by key. Never proxies; a node without storage has nothing to export.
+    pub async fn export(&self) -> ServalResult<Vec<(String, Vec<u8>)>> {
+        if !self.has_storage() {
+            return Err(ServalError::StorageError(
+                "this node has no storage to export".to_string(),
+            ));
+        }
+
+        let mut keys: Vec<String> = self
+            .keys()
+            .await?
+            .into_iter()
+            .filter(|key| Storage::is_exportable(key))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut blobs = Vec::with_capacity(keys.len());
+        for key in keys {
+            let bytes = self.data_by_key(&key).await?;
+            blobs.push((key, bytes));
+        }
+        Ok(blobs)
+    }
+
+    /// Store a blob exported from some node under its original key, unless we already hold
+    /// exactly those bytes under that key. Returns whether anything was written.
+    pub async fn import(&self, key: &str, bytes: &[u8]) -> ServalResult<bool> {
+        if !self.has_storage() {
+            return Err(ServalError::StorageError(
+                "this node has no storage to import into".to_string(),
+            ));
+        }
+
+        let integrity = Integrity::from(bytes);
+        if let Ok(existing) = self.integrity_by_key(key).await {
+            if existing.matches(&integrity).is_some() {
+                return Ok(false);
+            }
+        }
+
+        if let Some(local) = &self.local {
+            local.store_by_key(key, bytes).await?;
+        }
+        if let Some(bucket) = &self.bucket {
+            bucket.store_by_key(key, bytes).await?;
+        }
+        Ok(true)
+    }
+
+    /// Returns true if the key is for something that storage export includes.
+    pub fn is_exportable(key: &str) -> bool {
+        key.ends_with(".manifest.toml")
+            || key.ends_with(".manifest")
+            || key.ends_with(".wasm")
+            || key.ends_with(".tag")
+    }
+
+    // Read a blob by key from whichever of our storage options has it.
+    async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
+        if let Some(local) = &self.local {
+            if let Ok(bytes) = local.data_by_key(key).await {
+                return Ok(bytes);
+            }
+        }
+        if let Some(bucket) = &self.bucket {
+            return bucket.data_by_key(key).await;
+        }
+        Err(ServalError::DataNotFound(key.to_string()))
+    }
+
+    // The integrity checksum a key was stored under, from whichever storage option has it.
+    async fn integrity_by_key(&self, key: &str) -> ServalResult<Integrity> {
+        if let Some(local) = &self.local {
+            if let Ok(integrity) = local.integrity_by_key(key).await {
+                return Ok(integrity);
+            }
+        }
+        if let Some(bucket) = &self.bucket {
+            return bucket.integrity_by_key(key).await;
+        }
+        Err(ServalError::DataNotFound(key.to_string()))
+    }
+
+    /// Flatten a manifest by filling in whatever it leaves unset from its chain of base manifests,
+    /// nearest base first. Bases are looked up in the given tenant's namespace. Fails if a base is
+    /// missing or if the chain loops back on itself.
+    pub async fn resolve_bases(
+        &self,
+        mut manifest: Manifest,
+        tenant: Option<&str>,
+    ) -> ServalResult<Manifest> {
+        let mut seen = vec![manifest.fq_name()];
+        let mut next = manifest.base().map(str::to_string);
+        while let Some(base_name) = next {
+            if seen.contains(&base_name) {
+                seen.push(base_name);
+                return Err(ServalError::ManifestBaseCycle(seen.join(" -> ")));
+            }
+            let base = self.manifest(&Manifest::scoped_name(tenant, &base_name)).await?;
+            manifest.inherit_from(&base);
+            next = base.base().map(str::to_string);
+            seen.push(base_name);
+        }
+        Ok(manifest)
+    }
+
+    /// Store a Wasm manifest in the given tenant's namespace. Returns the integrity checksum.
+    pub async fn store_manifest(
+        &self,
+        manifest: &Manifest,
+        tenant: Option<&str>,
+    ) -> ServalResult<Integrity> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?.with_tenant(tenant);
             return proxy.store_manifest(manifest).await;
         }
 
         let toml = toml::to_string(manifest)?;
-        let key = manifest.manifest_key();
+        let scoped_name = Manifest::scoped_name(tenant, &manifest.fq_name());
+        let key = Manifest::make_manifest_key(&scoped_name);
+
+        // Keep each version's manifest as well as the latest, so that versions can be compared.
+        let versioned_key = Manifest::make_versioned_manifest_key(&scoped_name, manifest.version());
+        if let Some(local) = &self.local {
+            if let Err(e) = local.store_by_key(&versioned_key, toml.as_bytes()).await {
+                log::warn!("unable to keep versioned manifest; key={versioned_key}; {e}");
+            }
+        }
+        if let Some(bucket) = &self.bucket {
+            if let Err(e) = bucket.store_by_key(&versioned_key, toml.as_bytes()).await {
+                log::warn!("unable to keep versioned manifest; key={versioned_key}; {e}");
+            }
+        }
 
         let local_result = if let Some(local) = &self.local {
             Some(local.store_by_key(&key, toml.as_bytes()).await)
@@ -337,7 +731,29 @@ impl Storage {
         Err(ServalError::ExecutableNotFound(format!("{name}@{version}")))
     }
 
-    /// Fetch the bytes of the named executable so we can run it.
+    /// Look up the integrity checksum the named executable was stored under.
+    ///
+    /// Never checks a proxy; this is intended to be a local check.
+    pub async fn executable_integrity(&self, name: &str, version: &str) -> ServalResult<Integrity> {
+        let key = Manifest::make_executable_key(name, version);
+
+        if let Some(local) = &self.local {
+            if let Ok(integrity) = local.integrity_by_key(&key).await {
+                return Ok(integrity);
+            }
+        }
+
+        if let Some(bucket) = &self.bucket {
+            if let Ok(integrity) = bucket.integrity_by_key(&key).await {
+                return Ok(integrity);
+            }
+        }
+
+        Err(ServalError::ExecutableNotFound(format!("{name}@{version}")))
+    }
+
+    /// Fetch the bytes of the named executable so we can run it. Whichever route the bytes take
+    /// to get here, they are checked against the integrity checksum they were stored under.
     pub async fn executable_as_bytes(&self, name: &str, version: &str) -> ServalResult<Vec<u8>> {
         if !self.has_storage() {
             let proxy = make_proxy_client().await?;
@@ -346,6 +762,7 @@ impl Storage {
 
         let key = Manifest::make_executable_key(name, version);
 
+        // cacache verifies integrity on every read.
         if let Some(local) = &self.local {
             if let Ok(v) = local.data_by_key(&key).await {
                 return Ok(v);
@@ -353,8 +770,10 @@ impl Storage {
         }
 
         if let Some(bucket) = &self.bucket {
-            if let Ok(v) = bucket.data_by_key(&key).await {
-                return Ok(v);
+            match bucket.data_by_key(&key).await {
+                Ok(v) => return Ok(v),
+                Err(e @ ServalError::IntegrityMismatch(_)) => return Err(e),
+                Err(_) => {}
             }
         }
 
@@ -403,13 +822,10 @@ impl Storage {
 // Convenience function to make a proxy client for a freshly-selected peer.
 async fn make_proxy_client() -> ServalResult<ServalApiClient> {
     let mesh = MESH.get().expect("Peer network not initialized!"); // yes, we crash in this case
-    let peers = mesh.peers_with_role(&ServalRole::Storage).await;
-    let iter = peers.iter();
-    for peer in iter {
-        if let Some(addr) = peer.http_address() {
-            let proxy = ServalApiClient::new_with_version(1, addr.to_string());
-            return Ok(proxy);
-        }
+    let peer = mesh.weighted_peer_with_role(&ServalRole::Storage).await;
+    if let Some(addr) = peer.and_then(|peer| peer.http_address()) {
+        let proxy = ServalApiClient::new_with_version(1, addr.to_string());
+        return Ok(proxy);
     }
     // If we get here we have utterly failed and cannot continue, but crashing might not be right.
     Err(ServalError::StorageError(
@@ -428,3 +844,271 @@ fn vec_to_byte_stream(bytes: Vec<u8>) -> SendableStream {
 
     Box::pin(sr)
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn manifest(namespace: &str, name: &str, version: &str) -> Manifest {
+        toml::from_str(&format!(
+            "name = \"{name}\"\nnamespace = \"{namespace}\"\nversion = \"{version}\"\n\
+             binary = \"{name}.wasm\"\ndescription = \"\"\n"
+        ))
+        .unwrap()
+    }
+
+    async fn store_version(storage: &Storage, manifest: &Manifest, executable: &[u8]) {
+        storage.store_manifest(manifest, None).await.unwrap();
+        storage
+            .store_executable(&manifest.fq_name(), manifest.version(), executable)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn version_tags_resolve_to_stored_versions() {
+        let path = std::env::temp_dir().join(format!("serval-tags-{}", Uuid::new_v4()));
+        let storage = Storage::new(None, Some(BlobStore::new(&path).unwrap()));
+        let name = "sh.serval.loudify";
+        store_version(&storage, &manifest("sh.serval", "loudify", "1.0.0"), b"one").await;
+        store_version(&storage, &manifest("sh.serval", "loudify", "1.1.0"), b"two").await;
+
+        assert!(storage
+            .set_version_tag(name, "stable", "2.0.0")
+            .await
+            .is_err());
+        storage
+            .set_version_tag(name, "stable", "1.0.0")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.resolve_version(name, "stable").await.unwrap(),
+            "1.0.0"
+        );
+        assert_eq!(
+            storage.resolve_version(name, "1.1.0").await.unwrap(),
+            "1.1.0"
+        );
+        assert!(storage.resolve_version(name, "latest").await.is_err());
+
+        // Moving a tag changes what it means from then on.
+        storage
+            .set_version_tag(name, "stable", "1.1.0")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.resolve_version(name, "stable").await.unwrap(),
+            "1.1.0"
+        );
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn references_run_with_their_own_version_manifest() {
+        let path = std::env::temp_dir().join(format!("serval-refs-{}", Uuid::new_v4()));
+        let storage = Storage::new(None, Some(BlobStore::new(&path).unwrap()));
+        let name = "sh.serval.loudify";
+        for (version, limit) in [("1.0.0", 10), ("1.1.0", 20)] {
+            let manifest: Manifest = toml::from_str(&format!(
+                "name = \"loudify\"\nnamespace = \"sh.serval\"\nversion = \"{version}\"\n\
+                 binary = \"loudify.wasm\"\ndescription = \"\"\nmax_input_bytes = {limit}\n"
+            ))
+            .unwrap();
+            store_version(&storage, &manifest, version.as_bytes()).await;
+        }
+        storage
+            .set_version_tag(name, "stable", "1.0.0")
+            .await
+            .unwrap();
+
+        let latest = storage.manifest(name).await.unwrap();
+        assert_eq!(latest.max_input_bytes(), Some(20));
+        for reference in ["1.0.0", "stable"] {
+            let pinned = storage
+                .manifest_for_reference(name, reference)
+                .await
+                .unwrap();
+            assert_eq!(pinned.version(), "1.0.0");
+            assert_eq!(pinned.max_input_bytes(), Some(10));
+        }
+        assert!(storage.manifest_for_reference(name, "2.0.0").await.is_err());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn versions_belong_to_exactly_one_name() {
+        let path = std::env::temp_dir().join(format!("serval-prefix-{}", Uuid::new_v4()));
+        let storage = Storage::new(None, Some(BlobStore::new(&path).unwrap()));
+        // The first name is a prefix of the second, so their keys look alike.
+        store_version(&storage, &manifest("sh.serval", "loud", "1.0.0"), b"one").await;
+        store_version(&storage, &manifest("sh.serval.loud", "er", "2.0.0"), b"two").await;
+
+        assert_eq!(storage.versions("sh.serval.loud").await.unwrap(), ["1.0.0"]);
+        assert_eq!(
+            storage.versions("sh.serval.loud.er").await.unwrap(),
+            ["2.0.0"]
+        );
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn manifests_are_listed_per_tenant() {
+        let path = std::env::temp_dir().join(format!("serval-tenants-{}", Uuid::new_v4()));
+        let storage = Storage::new(None, Some(BlobStore::new(&path).unwrap()));
+        let shared = manifest("sh.serval", "loudify", "1.0.0");
+        let private = manifest("sh.serval", "quietify", "1.0.0");
+        storage.store_manifest(&shared, None).await.unwrap();
+        storage
+            .store_manifest(&private, Some("acme"))
+            .await
+            .unwrap();
+
+        let untenanted = storage.manifests(None).await.unwrap();
+        assert_eq!(untenanted, [shared]);
+        let tenanted = storage.manifests(Some("acme")).await.unwrap();
+        assert_eq!(tenanted, [private]);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_version_keeps_its_manifest() {
+        let path = std::env::temp_dir().join(format!("serval-versions-{}", Uuid::new_v4()));
+        let storage = Storage::new(None, Some(BlobStore::new(&path).unwrap()));
+        let mut manifest = Manifest::new(&PathBuf::from("/tmp/loudify.wasm"));
+        manifest.set_version("1.0.0");
+        storage.store_manifest(&manifest, None).await.unwrap();
+        manifest.set_version("1.1.0");
+        storage.store_manifest(&manifest, None).await.unwrap();
+
+        let name = manifest.fq_name();
+        let old = storage.manifest_version(&name, "1.0.0").await.unwrap();
+        assert_eq!(old.version(), "1.0.0");
+        assert_eq!(storage.manifest(&name).await.unwrap().version(), "1.1.0");
+        assert!(storage.manifest_version(&name, "2.0.0").await.is_err());
+
+        // Versioned manifests aren't listed as manifests of their own.
+        assert_eq!(storage.manifests(None).await.unwrap().len(), 1);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn data_blobs_are_encrypted_at_rest() {
+        let path = std::env::temp_dir().join(format!("serval-sealed-{}", Uuid::new_v4()));
+        let local = BlobStore::new(&path).unwrap();
+        let storage = Storage::new(None, Some(local.clone()))
+            .with_cipher(BlobCipher::new(b"correct horse battery staple"));
+        let input = b"the launch codes are 0000".to_vec();
+
+        let integrity = storage.store_by_integrity(&input).await.unwrap();
+        assert_eq!(integrity, Integrity::from(&input));
+        let on_disk = local.data_by_key(&integrity.to_string()).await.unwrap();
+        assert!(BlobCipher::is_sealed(&on_disk));
+        assert!(!on_disk.windows(input.len()).any(|window| window == input));
+        assert_eq!(
+            storage.data_by_integrity(integrity.clone()).await.unwrap(),
+            input
+        );
+
+        // Another key can't open it, and neither can a node with no key at all.
+        let stranger =
+            Storage::new(None, Some(local.clone())).with_cipher(BlobCipher::new(b"guess"));
+        assert!(stranger.data_by_integrity(integrity.clone()).await.is_err());
+        let keyless = Storage::new(None, Some(local));
+        assert!(keyless.data_by_integrity(integrity).await.is_err());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn plain_blobs_stay_readable_once_encryption_is_on() {
+        let path = std::env::temp_dir().join(format!("serval-plain-{}", Uuid::new_v4()));
+        let local = BlobStore::new(&path).unwrap();
+        let input = b"stored before there was a key".to_vec();
+        let integrity = Storage::new(None, Some(local.clone()))
+            .store_by_integrity(&input)
+            .await
+            .unwrap();
+
+        let storage =
+            Storage::new(None, Some(local)).with_cipher(BlobCipher::new(b"a brand new key"));
+        assert!(storage.data_exists_by_integrity(&integrity).await.unwrap());
+        assert_eq!(
+            storage.data_by_integrity(integrity.clone()).await.unwrap(),
+            input
+        );
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    // Just enough of S3 to put and get objects by path.
+    async fn fake_s3() -> String {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        use axum::extract::State;
+        use axum::http::{Method, StatusCode, Uri};
+        use axum::response::IntoResponse;
+
+        type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+        async fn object(
+            State(objects): State<Objects>,
+            method: Method,
+            uri: Uri,
+            body: Bytes,
+        ) -> axum::response::Response {
+            let mut objects = objects.lock().unwrap();
+            if method == Method::PUT {
+                objects.insert(uri.path().to_string(), body.to_vec());
+                return StatusCode::OK.into_response();
+            }
+            match objects.get(uri.path()) {
+                Some(bytes) => bytes.clone().into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+
+        let app = axum::Router::new()
+            .fallback(object)
+            .with_state(Objects::default());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn bucket_blobs_are_read_by_their_address() {
+        let bucket = S3Storage::for_testing(&fake_s3().await);
+        let input = b"the launch codes are 0000".to_vec();
+
+        let plain = Storage::new(Some(bucket.clone()), None);
+        let integrity = plain.store_by_integrity(&input).await.unwrap();
+        assert!(plain.data_exists_by_integrity(&integrity).await.unwrap());
+        assert_eq!(
+            plain.data_by_integrity(integrity.clone()).await.unwrap(),
+            input
+        );
+
+        // Turning encryption on later leaves the plain blob readable, and new blobs are sealed.
+        let sealed =
+            Storage::new(Some(bucket.clone()), None).with_cipher(BlobCipher::new(b"bucket key"));
+        assert_eq!(
+            sealed.data_by_integrity(integrity.clone()).await.unwrap(),
+            input
+        );
+        let secret = b"not for the bucket's eyes".to_vec();
+        let integrity = sealed.store_by_integrity(&secret).await.unwrap();
+        let at_rest = bucket.data_by_integrity(&integrity).await.unwrap();
+        assert!(BlobCipher::is_sealed(&at_rest));
+        assert!(sealed.data_exists_by_integrity(&integrity).await.unwrap());
+        assert_eq!(
+            sealed.data_by_integrity(integrity.clone()).await.unwrap(),
+            secret
+        );
+        assert!(plain.data_by_integrity(integrity).await.is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+use uuid::Uuid;
+
+/// Executable uploads that are arriving in pieces. There is only ever one of these per process.
+pub static UPLOADS: OnceCell<Uploads> = OnceCell::new();
+
+/// Uploads that nobody has touched in this long are abandoned and get thrown away.
+const ABANDONED_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The largest executable that may be uploaded, unless the operator says otherwise.
+pub const DEFAULT_MAX_UPLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// How many uploads may be in progress at once, unless the operator says otherwise.
+pub const DEFAULT_MAX_PENDING_UPLOADS: usize = 16;
+
+/// Executable uploads that arrive in pieces, in any order, and are held in memory until they are
+/// finalized. A piece that is lost in transit can simply be sent again. Since they are held in
+/// memory, both the size of each upload and the number in progress are limited.
+#[derive(Debug)]
+pub struct Uploads {
+    max_size: u64,
+    max_pending: usize,
+    pending: Mutex<HashMap<Uuid, PendingUpload>>,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_UPLOAD_BYTES, DEFAULT_MAX_PENDING_UPLOADS)
+    }
+}
+
+#[derive(Debug)]
+struct PendingUpload {
+    name: String,
+    version: String,
+    size: u64,
+    /// The pieces received so far, keyed by their offset into the executable. They never
+    /// overlap, so between them they hold at most `size` bytes.
+    chunks: BTreeMap<u64, Bytes>,
+    touched: Instant,
+}
+
+/// Where an upload stands when the client asks to finalize it.
+#[derive(Debug)]
+pub enum UploadStatus {
+    /// Every byte has arrived; here is the executable, ready to be stored.
+    Complete {
+        name: String,
+        version: String,
+        bytes: Vec<u8>,
+    },
+    /// These half-open byte ranges are still missing.
+    Incomplete(Vec<(u64, u64)>),
+}
+
+impl Uploads {
+    /// Accept uploads of up to `max_size` bytes, and up to `max_pending` of them at a time.
+    pub fn new(max_size: u64, max_pending: usize) -> Self {
+        Self {
+            max_size,
+            max_pending,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Begin an upload of an executable of the given size, returning the id to send pieces to.
+    /// Fails if the executable is too big, or if too many uploads are already in progress.
+    pub fn start(&self, name: &str, version: &str, size: u64) -> ServalResult<Uuid> {
+        if size > self.max_size {
+            return Err(ServalError::UploadTooLarge(size, self.max_size));
+        }
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, upload| upload.touched.elapsed() < ABANDONED_AFTER);
+        if pending.len() >= self.max_pending {
+            return Err(ServalError::TooManyUploads(self.max_pending));
+        }
+
+        let id = Uuid::new_v4();
+        pending.insert(
+            id,
+            PendingUpload {
+                name: name.to_string(),
+                version: version.to_string(),
+                size,
+                chunks: BTreeMap::new(),
+                touched: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Accept one piece of an upload, starting at the given offset. Only the parts of it we don't
+    /// already hold are kept, as a client resending a range may well overlap what arrived before.
+    pub fn put_chunk(&self, id: &Uuid, offset: u64, bytes: Bytes) -> ServalResult<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(upload) = pending.get_mut(id) else {
+            return Err(ServalError::UploadNotFound(id.to_string()));
+        };
+        let end = match offset.checked_add(bytes.len() as u64) {
+            Some(end) if end <= upload.size => end,
+            _ => {
+                return Err(ServalError::StorageError(format!(
+                    "chunk at offset {offset} with length {} runs past the end of a {}-byte upload",
+                    bytes.len(),
+                    upload.size
+                )))
+            }
+        };
+
+        let mut gaps = Vec::new();
+        let mut cursor = offset;
+        for (&held, chunk) in upload.chunks.range(..end) {
+            let held_end = held + chunk.len() as u64;
+            if held_end <= cursor {
+                continue;
+            }
+            if held > cursor {
+                gaps.push((cursor, held));
+            }
+            cursor = held_end;
+        }
+        if cursor < end {
+            gaps.push((cursor, end));
+        }
+        for (from, to) in gaps {
+            let piece = bytes.slice((from - offset) as usize..(to - offset) as usize);
+            upload.chunks.insert(from, piece);
+        }
+        upload.touched = Instant::now();
+        Ok(())
+    }
+
+    /// Try to finish an upload, which the client expects to have the given integrity. If it is
+    /// complete and matches, it is removed and handed back whole. If pieces are missing, or what
+    /// arrived doesn't match, it stays put so that the client can fix it and try again.
+    pub fn finish(&self, id: &Uuid, expected: &Integrity) -> ServalResult<UploadStatus> {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(upload) = pending.get_mut(id) else {
+            return Err(ServalError::UploadNotFound(id.to_string()));
+        };
+        upload.touched = Instant::now();
+
+        let (bytes, missing) = assemble(upload.size, &upload.chunks);
+        if !missing.is_empty() {
+            return Ok(UploadStatus::Incomplete(missing));
+        }
+        if expected.check(&bytes).is_err() {
+            return Err(ServalError::IntegrityMismatch(format!(
+                "{}@{}",
+                upload.name, upload.version
+            )));
+        }
+
+        let upload = pending.remove(id).unwrap();
+        Ok(UploadStatus::Complete {
+            name: upload.name,
+            version: upload.version,
+            bytes,
+        })
+    }
+}
+
+/// Stitch the received pieces together in offset order, noting any gaps.
+fn assemble(size: u64, chunks: &BTreeMap<u64, Bytes>) -> (Vec<u8>, Vec<(u64, u64)>) {
+    let mut bytes = Vec::with_capacity(size as usize);
+    let mut missing = Vec::new();
+    let mut cursor = 0;
+    for (&offset, chunk) in chunks {
+        if offset > cursor {
+            missing.push((cursor, offset));
+        }
+        bytes.extend_from_slice(chunk);
+        cursor = offset + chunk.len() as u64;
+    }
+    if cursor < size {
+        missing.push((cursor, size));
+    }
+    (bytes, missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_report_gaps_until_complete() {
+        let uploads = Uploads::default();
+        let whole = Integrity::from(b"0123456789");
+        let id = uploads.start("sh.serval.loudify", "1.0.0", 10).unwrap();
+
+        uploads.put_chunk(&id, 6, Bytes::from_static(b"6789")).unwrap();
+        uploads.put_chunk(&id, 0, Bytes::from_static(b"012")).unwrap();
+        assert!(uploads.put_chunk(&id, 8, Bytes::from_static(b"89X")).is_err());
+        assert!(uploads
+            .put_chunk(&id, u64::MAX, Bytes::from_static(b"9"))
+            .is_err());
+
+        match uploads.finish(&id, &whole).unwrap() {
+            UploadStatus::Incomplete(missing) => assert_eq!(missing, vec![(3, 6)]),
+            UploadStatus::Complete { .. } => panic!("upload should not be complete yet"),
+        }
+
+        uploads.put_chunk(&id, 2, Bytes::from_static(b"2345")).unwrap();
+        match uploads.finish(&id, &whole).unwrap() {
+            UploadStatus::Complete { bytes, version, .. } => {
+                assert_eq!(bytes, b"0123456789");
+                assert_eq!(version, "1.0.0");
+            }
+            UploadStatus::Incomplete(missing) => panic!("still missing {missing:?}"),
+        }
+
+        assert!(matches!(
+            uploads.finish(&id, &whole),
+            Err(ServalError::UploadNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn overlapping_chunks_are_trimmed() {
+        let uploads = Uploads::default();
+        let id = uploads.start("sh.serval.loudify", "1.0.0", 10).unwrap();
+
+        uploads
+            .put_chunk(&id, 2, Bytes::from_static(b"23"))
+            .unwrap();
+        uploads
+            .put_chunk(&id, 6, Bytes::from_static(b"67"))
+            .unwrap();
+        for _ in 0..3 {
+            uploads
+                .put_chunk(&id, 0, Bytes::from_static(b"0123456789"))
+                .unwrap();
+        }
+        let held: usize = {
+            let pending = uploads.pending.lock().unwrap();
+            pending[&id].chunks.values().map(|chunk| chunk.len()).sum()
+        };
+        assert_eq!(held, 10);
+
+        match uploads
+            .finish(&id, &Integrity::from(b"0123456789"))
+            .unwrap()
+        {
+            UploadStatus::Complete { bytes, .. } => assert_eq!(bytes, b"0123456789"),
+            UploadStatus::Incomplete(missing) => panic!("still missing {missing:?}"),
+        }
+    }
+
+    #[test]
+    fn mismatched_uploads_are_kept() {
+        let uploads = Uploads::default();
+        let id = uploads.start("sh.serval.loudify", "1.0.0", 4).unwrap();
+        uploads
+            .put_chunk(&id, 0, Bytes::from_static(b"0123"))
+            .unwrap();
+
+        assert!(matches!(
+            uploads.finish(&id, &Integrity::from(b"3210")),
+            Err(ServalError::IntegrityMismatch(_))
+        ));
+        assert!(matches!(
+            uploads.finish(&id, &Integrity::from(b"0123")),
+            Ok(UploadStatus::Complete { .. })
+        ));
+    }
+
+    #[test]
+    fn uploads_are_limited() {
+        let uploads = Uploads::new(10, 2);
+        assert!(matches!(
+            uploads.start("sh.serval.loudify", "1.0.0", 11),
+            Err(ServalError::UploadTooLarge(11, 10))
+        ));
+
+        let first = uploads.start("sh.serval.loudify", "1.0.0", 10).unwrap();
+        uploads.start("sh.serval.loudify", "1.1.0", 10).unwrap();
+        assert!(matches!(
+            uploads.start("sh.serval.loudify", "1.2.0", 10),
+            Err(ServalError::TooManyUploads(2))
+        ));
+
+        // Finishing an upload makes room for another.
+        uploads
+            .put_chunk(&first, 0, Bytes::from_static(b"0123456789"))
+            .unwrap();
+        uploads
+            .finish(&first, &Integrity::from(b"0123456789"))
+            .unwrap();
+        uploads.start("sh.serval.loudify", "1.2.0", 10).unwrap();
+    }
+}
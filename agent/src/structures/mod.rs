@@ -1,18 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::Result;
 use engine::extensions::{load_extensions, ServalExtension};
+use engine::module_cache::ModuleCache;
+use engine::CancelHandle;
 use once_cell::sync::OnceCell;
+use tokio::sync::Semaphore;
 use utils::errors::ServalError;
 use utils::mesh::ServalMesh;
 use uuid::Uuid;
 
+use crate::cache::ResultCache;
+use crate::storage::BlobBackend;
+
 pub static MESH: OnceCell<ServalMesh> = OnceCell::new();
 
+/// One permit per job this node may run at once, if `MAX_CONCURRENT_JOBS` sets a limit.
+pub static JOB_SLOTS: OnceCell<Semaphore> = OnceCell::new();
+
 pub type ServalRouter = axum::Router<Arc<RunnerState>, hyper::Body>;
 
+/// A job this node is running right now.
+#[derive(Debug, Clone)]
+pub struct InFlightJob {
+    /// The fully-qualified name of the job.
+    pub name: String,
+    pub started_at: SystemTime,
+    pub input_bytes: u64,
+    pub labels: BTreeMap<String, String>,
+    /// Stops the job.
+    pub cancel: CancelHandle,
+}
+
 /// Our application state. Fields are public for now but we'll want to fix that.
 #[derive(Debug, Clone)]
 pub struct RunnerState {
@@ -21,18 +44,39 @@ pub struct RunnerState {
     pub should_run_jobs: bool,
     pub should_run_scheduler: bool,
     pub has_storage: bool,
+    /// How many jobs this node is running right now.
+    pub running_jobs: Arc<AtomicUsize>,
+    /// The jobs this node is running right now, by id, with the means to stop them.
+    pub in_flight: Arc<Mutex<HashMap<Uuid, InFlightJob>>>,
+    /// The memory cap for jobs whose manifests don't declare their own.
+    pub max_memory_bytes: Option<u64>,
+    /// The longest any job may run on this node, in milliseconds, whatever its manifest or its
+    /// run's overrides ask for; also the limit for jobs that don't ask for one.
+    pub max_duration_ms: Option<u64>,
+    /// Results of pure jobs, so that repeat runs can skip the engine.
+    pub result_cache: Arc<ResultCache>,
+    /// The read-only dataset that jobs may ask to have mounted, if this node has one.
+    pub shared_data_path: Option<PathBuf>,
+    /// Where compiled modules are kept between runs, if anywhere.
+    pub module_cache: Option<ModuleCache>,
 }
 
 impl RunnerState {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         instance_id: Uuid,
-        blob_path: Option<PathBuf>,
+        blob_backend: Option<BlobBackend>,
         extensions_path: Option<PathBuf>,
         should_run_jobs: bool,
         should_run_scheduler: bool,
+        max_memory_bytes: Option<u64>,
+        max_duration_ms: Option<u64>,
+        result_cache_limits: (usize, u64),
+        shared_data_path: Option<PathBuf>,
+        module_cache: Option<ModuleCache>,
     ) -> Result<Self, ServalError> {
-        let has_storage = blob_path.is_some();
-        crate::storage::initialize(blob_path).await?;
+        let has_storage = blob_backend.is_some();
+        crate::storage::initialize(blob_backend).await?;
 
         let extensions = extensions_path
             .and_then(|extensions_path| {
@@ -53,6 +97,16 @@ impl RunnerState {
             should_run_jobs,
             should_run_scheduler,
             has_storage,
+            running_jobs: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            max_memory_bytes,
+            max_duration_ms,
+            result_cache: Arc::new(ResultCache::new(
+                result_cache_limits.0,
+                result_cache_limits.1,
+            )),
+            shared_data_path,
+            module_cache,
         })
     }
 }
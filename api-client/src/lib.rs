@@ -7,22 +7,41 @@
     unused_qualifications
 )]
 
+use std::collections::BTreeMap;
 use std::time::Duration;
 
-use reqwest::{Response, StatusCode};
+use reqwest::multipart::{Form, Part};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use ssri::Integrity;
 use utils::errors::ServalError;
 use utils::mesh::{PeerMetadata, ServalRole};
-use utils::structs::Manifest;
+use utils::structs::api::{
+    BuildInfo, ErrorResponse, ImportSummary, MissingRanges, NodeStatus, RunningJob, StartUpload,
+    UploadStarted, INTEGRITY_HEADER, TENANT_HEADER,
+};
+use utils::structs::{Manifest, ManifestOverrides};
 
 type ApiResult<T> = Result<T, ServalError>;
-type JsonObject = serde_json::Map<String, serde_json::Value>;
+
+/// How to run a job, beyond what to run and on what input.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Where to post the job's result once it finishes.
+    pub callback_url: Option<String>,
+    /// Limits to run the job under in place of its manifest's.
+    pub overrides: Option<ManifestOverrides>,
+    /// Hand back output even if it does not match the manifest's output schema.
+    pub skip_output_schema: bool,
+    /// Labels to attach to this run of the job.
+    pub labels: BTreeMap<String, String>,
+}
 
 /// A client for the Serval API.
 #[derive(Debug, Clone)]
 pub struct ServalApiClient {
     version: u8,
     socket_addr: String,
+    tenant: Option<String>,
 }
 
 impl ServalApiClient {
@@ -31,6 +50,7 @@ impl ServalApiClient {
         Self {
             version: 1, // magic number, yes it is
             socket_addr,
+            tenant: None,
         }
     }
 
@@ -39,9 +59,21 @@ impl ServalApiClient {
         Self {
             version,
             socket_addr,
+            tenant: None,
         }
     }
 
+    /// Make every request from this client on behalf of the given tenant, if any.
+    pub fn with_tenant(mut self, tenant: Option<&str>) -> Self {
+        self.tenant = tenant.map(str::to_string);
+        self
+    }
+
+    /// The address of the node this client talks to.
+    pub fn socket_addr(&self) -> &str {
+        &self.socket_addr
+    }
+
     /// Ping whichever node we're pointing to.
     pub async fn ping(&self) -> ApiResult<String> {
         // This url is not versioned.
@@ -53,38 +85,114 @@ impl ServalApiClient {
     }
 
     /// Get monitoring status from whatever node we're pointing to.
-    pub async fn monitor_status(&self) -> ApiResult<JsonObject> {
+    pub async fn monitor_status(&self) -> ApiResult<NodeStatus> {
         // This url is not versioned.
         let url = format!("http://{}/monitor/status", self.socket_addr);
         let response = reqwest::get(&url).await?;
-        let body: serde_json::Map<String, serde_json::Value> = response.json().await?;
+        let body: NodeStatus = response.json().await?;
 
         Ok(body)
     }
 
-    /// List all running jobs.
-    pub async fn list_jobs(&self) -> ApiResult<JsonObject> {
-        let url = self.build_url("jobs");
+    /// Ask whatever node we're pointing to which build of the agent it is running.
+    pub async fn monitor_version(&self) -> ApiResult<BuildInfo> {
+        // This url is not versioned.
+        let url = format!("http://{}/monitor/version", self.socket_addr);
         let response = reqwest::get(&url).await?;
-        let body: JsonObject = response.json().await?;
+        let body: BuildInfo = response.json().await?;
+
+        Ok(body)
+    }
+
+    /// List the jobs the node is running right now, optionally only those with the given label,
+    /// written as `key=value`.
+    pub async fn list_jobs(&self, label: Option<&str>) -> ApiResult<Vec<RunningJob>> {
+        let url = self.build_url("jobs");
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(label) = label {
+            request = request.query(&[("label", label)]);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(api_failure(response).await);
+        }
+        let body: Vec<RunningJob> = response.json().await?;
 
         Ok(body)
     }
 
     /// Run a previously-stored Wasm job by its fully-qualified name. If the job
     /// needs input, send it in as a vec of bytes. Pass a zero-length vec if the
-    /// job doesn't need input.
-    pub async fn run_job(&self, name: &str, input: Vec<u8>) -> ApiResult<Response> {
+    /// job doesn't need input. If a callback url is given, the runner will POST the job's outcome
+    /// to it once the job has finished.
+    pub async fn run_job(
+        &self,
+        name: &str,
+        input: Vec<u8>,
+        options: &RunOptions,
+    ) -> ApiResult<Response> {
         let url = self.build_url(&format!("jobs/{name}/run"));
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(120))
             .build()?;
         // TODO: this is a cop-out for the moment, because the cli does a lot with the response object.
         // We *should* respond with WasmResult.
-        let response = client.post(url).body(input).send().await?;
+        let mut request = self.tenanted(client.post(url).body(input));
+        if let Some(callback_url) = &options.callback_url {
+            request = request.query(&[("callback_url", callback_url)]);
+        }
+        if let Some(overrides) = &options.overrides {
+            let overrides =
+                serde_json::to_string(overrides).expect("overrides are only ever numbers");
+            request = request.query(&[("overrides", overrides)]);
+        }
+        if options.skip_output_schema {
+            request = request.query(&[("skip_output_schema", "true")]);
+        }
+        if !options.labels.is_empty() {
+            let labels = serde_json::to_string(&options.labels).expect("labels are only strings");
+            request = request.query(&[("labels", labels)]);
+        }
+        let response = request.send().await?;
         Ok(response)
     }
 
+    /// Run a Wasm executable that isn't stored on the mesh, on the given input. Nothing about it
+    /// is kept; it runs under the node's default limits. The name is only used to label the run.
+    pub async fn run_adhoc_job(
+        &self,
+        name: &str,
+        executable: Vec<u8>,
+        input: Vec<u8>,
+    ) -> ApiResult<Response> {
+        let url = self.build_url("jobs/run-adhoc");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(120))
+            .build()?;
+        let form = Form::new()
+            .text("name", name.to_string())
+            .part("executable", Part::bytes(executable))
+            .part("input", Part::bytes(input));
+        let response = self
+            .tenanted(client.post(url).multipart(form))
+            .send()
+            .await?;
+        Ok(response)
+    }
+
+    /// Stop a job that is running right now, by its id.
+    pub async fn cancel_job(&self, id: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("running/{id}"));
+        let response = reqwest::Client::new().delete(url).send().await?;
+        if response.status().is_success() {
+            Ok(())
+        } else if response.status() == StatusCode::NOT_FOUND {
+            Err(ServalError::JobNotFound(id.to_string()))
+        } else {
+            Err(api_failure(response).await)
+        }
+    }
+
     /// Get a list of all peers the node is aware of.
     pub async fn all_peers(&self) -> ApiResult<Vec<PeerMetadata>> {
         let url = self.build_url("mesh/peers");
@@ -109,7 +217,10 @@ impl ServalApiClient {
             .timeout(Duration::from_secs(60))
             .build()?;
         let url = self.build_url("storage/manifests");
-        let response = client.post(url).body(manifest.to_string()).send().await?;
+        let response = self
+            .tenanted(client.post(url).body(manifest.to_string()))
+            .send()
+            .await?;
 
         // StatusCode.CREATED  + ssri string
         if response.status().is_success() {
@@ -117,7 +228,34 @@ impl ServalApiClient {
             let integrity: Integrity = body.parse()?;
             Ok(integrity)
         } else {
-            Err(ServalError::StorageError(response.text().await?))
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// List stored manifests, optionally only those with the given tag and those whose name or
+    /// description contains the given search string.
+    pub async fn list_manifests(
+        &self,
+        tag: Option<&str>,
+        search: Option<&str>,
+    ) -> ApiResult<Vec<Manifest>> {
+        let url = self.build_url("storage/manifests");
+        let mut query = Vec::new();
+        if let Some(tag) = tag {
+            query.push(("tag", tag));
+        }
+        if let Some(search) = search {
+            query.push(("q", search));
+        }
+        let response = self
+            .tenanted(reqwest::Client::new().get(url).query(&query))
+            .send()
+            .await?;
+        if response.status().is_success() {
+            let manifests: Vec<Manifest> = response.json().await?;
+            Ok(manifests)
+        } else {
+            Err(api_failure(response).await)
         }
     }
 
@@ -125,13 +263,78 @@ impl ServalApiClient {
     /// as you might expect, because manifests are canonically stored as toml.
     pub async fn get_manifest(&self, name: &str) -> ApiResult<Manifest> {
         let url = self.build_url(&format!("storage/manifests/{name}"));
-        let response = reqwest::get(&url).await?;
+        let response = self
+            .tenanted(reqwest::Client::new().get(&url))
+            .send()
+            .await?;
         if response.status().is_success() {
             let text = response.text().await?;
             let manifest = Manifest::from_string(&text)?;
             Ok(manifest)
+        } else if response.status() == StatusCode::NOT_FOUND {
+            Err(ServalError::ManifestNotFound(name.to_string()))
         } else {
-            Err(ServalError::ManifestNotFound(response.text().await?))
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// List the stored versions of the named job, oldest first.
+    pub async fn manifest_versions(&self, name: &str) -> ApiResult<Vec<String>> {
+        let url = self.build_url(&format!("storage/manifests/{name}/versions"));
+        let response = self
+            .tenanted(reqwest::Client::new().get(&url))
+            .send()
+            .await?;
+        if response.status().is_success() {
+            let versions: Vec<String> = response.json().await?;
+            Ok(versions)
+        } else {
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// Fetch the manifest that was stored with one version of the named job.
+    pub async fn get_manifest_version(&self, name: &str, version: &str) -> ApiResult<Manifest> {
+        let url = self.build_url(&format!("storage/manifests/{name}/versions/{version}"));
+        let response = self
+            .tenanted(reqwest::Client::new().get(&url))
+            .send()
+            .await?;
+        if response.status().is_success() {
+            let text = response.text().await?;
+            Ok(Manifest::from_string(&text)?)
+        } else if response.status() == StatusCode::NOT_FOUND {
+            Err(ServalError::ManifestNotFound(format!("{name}@{version}")))
+        } else {
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// Look up the version that a version tag such as `stable` points at for the named job.
+    pub async fn get_version_tag(&self, name: &str, tag: &str) -> ApiResult<String> {
+        let url = self.build_url(&format!("storage/manifests/{name}/tags/{tag}"));
+        let response = self
+            .tenanted(reqwest::Client::new().get(&url))
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(response.text().await?)
+        } else {
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// Point a version tag for the named job at one of its stored versions.
+    pub async fn set_version_tag(&self, name: &str, tag: &str, version: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/manifests/{name}/tags/{tag}"));
+        let response = self
+            .tenanted(reqwest::Client::new().put(&url).body(version.to_string()))
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(api_failure(response).await)
         }
     }
 
@@ -142,7 +345,7 @@ impl ServalApiClient {
             .timeout(Duration::from_secs(60))
             .build()?;
 
-        let response = client.head(&url).send().await?;
+        let response = self.tenanted(client.head(&url)).send().await?;
         let found = matches!(response.status(), StatusCode::OK);
         Ok(found)
     }
@@ -160,28 +363,134 @@ impl ServalApiClient {
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?;
-        let response = client.put(url).body(executable).send().await?;
+        let response = self
+            .tenanted(client.put(url).body(executable))
+            .send()
+            .await?;
         if response.status().is_success() {
             let body = response.text().await?;
             let integrity: Integrity = body.parse()?;
             Ok(integrity)
         } else {
-            Err(ServalError::StorageError(response.text().await?))
+            Err(api_failure(response).await)
         }
     }
 
+    /// Store a Wasm executable in pieces of the given size. Pieces that fail to send are retried,
+    /// and the server tells us about any it still lacks when we finalize, so a flaky connection
+    /// costs us only the pieces it drops rather than the whole upload.
+    pub async fn store_executable_resumable(
+        &self,
+        name: &str,
+        version: &str,
+        executable: Vec<u8>,
+        chunk_size: usize,
+    ) -> ApiResult<Integrity> {
+        const ATTEMPTS: usize = 3;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        let url = self.build_url(&format!(
+            "storage/manifests/{name}/executable/{version}/uploads"
+        ));
+        let request = StartUpload {
+            size: executable.len() as u64,
+        };
+        let response = self
+            .tenanted(client.post(url).json(&request))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_failure(response).await);
+        }
+        let UploadStarted { upload_id } = response.json().await?;
+
+        let integrity = Integrity::from(&executable);
+        let mut missing = vec![(0, executable.len() as u64)];
+        for _ in 0..ATTEMPTS {
+            for (start, end) in missing {
+                for offset in (start..end).step_by(chunk_size) {
+                    let chunk_end = end.min(offset + chunk_size as u64);
+                    let chunk = executable[offset as usize..chunk_end as usize].to_vec();
+                    let url =
+                        self.build_url(&format!("storage/uploads/{upload_id}/chunks/{offset}"));
+                    // A piece that still fails here will be reported missing when we finalize.
+                    for _ in 0..ATTEMPTS {
+                        match client.put(&url).body(chunk.clone()).send().await {
+                            Ok(response) if response.status().is_success() => break,
+                            _ => continue,
+                        }
+                    }
+                }
+            }
+
+            let url = self.build_url(&format!("storage/uploads/{upload_id}/finalize"));
+            let response = client.post(url).body(integrity.to_string()).send().await?;
+            if response.status() == StatusCode::CONFLICT {
+                let ranges: MissingRanges = response.json().await?;
+                missing = ranges.missing;
+                continue;
+            }
+            if response.status().is_success() {
+                let body = response.text().await?;
+                let integrity: Integrity = body.parse()?;
+                return Ok(integrity);
+            }
+            return Err(api_failure(response).await);
+        }
+
+        Err(ServalError::StorageError(format!(
+            "upload of {name}@{version} was still incomplete after {ATTEMPTS} attempts"
+        )))
+    }
+
     /// Fetch the bytes for the named Wasm executable.
     pub async fn get_executable(&self, name: &str, version: &str) -> ApiResult<Vec<u8>> {
         let url = self.build_url(&format!("storage/manifests/{name}/executable/{version}"));
-        let response = reqwest::get(&url).await?;
+        let response = self
+            .tenanted(reqwest::Client::new().get(&url))
+            .send()
+            .await?;
         if response.status().is_success() {
+            let integrity = response
+                .headers()
+                .get(INTEGRITY_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<Integrity>().ok());
             let executable = response.bytes().await?;
+            if let Some(integrity) = integrity {
+                if integrity.check(&executable).is_err() {
+                    return Err(ServalError::IntegrityMismatch(format!("{name}@{version}")));
+                }
+            }
             Ok(executable.to_vec())
         } else {
-            Err(ServalError::StorageError(response.text().await?))
+            Err(api_failure(response).await)
         }
     }
 
+    /// Look up the integrity checksum of the named Wasm executable, without fetching it.
+    pub async fn executable_integrity(&self, name: &str, version: &str) -> ApiResult<Integrity> {
+        let url = self.build_url(&format!("storage/manifests/{name}/executable/{version}"));
+        let response = self
+            .tenanted(reqwest::Client::new().head(&url))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(ServalError::ExecutableNotFound(format!("{name}@{version}")));
+        }
+        if !response.status().is_success() {
+            return Err(api_failure(response).await);
+        }
+        let integrity = response
+            .headers()
+            .get(INTEGRITY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| ServalError::ExecutableNotFound(format!("{name}@{version}")))?;
+        Ok(integrity.parse()?)
+    }
+
     pub async fn stream_by_integrity(&self, address: &str) -> ApiResult<Vec<u8>> {
         let url = self.build_url(&format!("storage/data/{address}"));
         let response = reqwest::get(&url).await?;
@@ -189,7 +498,7 @@ impl ServalApiClient {
             let bytes = response.bytes().await?;
             Ok(bytes.to_vec())
         } else {
-            Err(ServalError::StorageError(response.text().await?))
+            Err(api_failure(response).await)
         }
     }
 
@@ -205,7 +514,47 @@ impl ServalApiClient {
             let integrity: Integrity = body.parse()?;
             Ok(integrity)
         } else {
-            Err(ServalError::StorageError(response.text().await?))
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// Fetch a tar archive of every manifest and executable the targeted peer stores.
+    pub async fn export_storage(&self) -> ApiResult<Vec<u8>> {
+        let url = self.build_url("storage/export");
+        let response = reqwest::get(&url).await?;
+        if response.status().is_success() {
+            let bytes = response.bytes().await?;
+            Ok(bytes.to_vec())
+        } else {
+            Err(api_failure(response).await)
+        }
+    }
+
+    /// Send an archive made by `export_storage()` to the targeted peer to be restored.
+    pub async fn import_storage(&self, archive: Vec<u8>) -> ApiResult<ImportSummary> {
+        let url = self.build_url("storage/import");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(300))
+            .build()?;
+        let response = client
+            .post(url)
+            .header("content-type", "application/x-tar")
+            .body(archive)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            let summary: ImportSummary = response.json().await?;
+            Ok(summary)
+        } else {
+            Err(api_failure(response).await)
+        }
+    }
+
+    // Convenience function to mark a request as made for our tenant, if we have one.
+    fn tenanted(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.tenant {
+            Some(tenant) => request.header(TENANT_HEADER, tenant),
+            None => request,
         }
     }
 
@@ -215,5 +564,27 @@ impl ServalApiClient {
     }
 }
 
+/// Turn an unsuccessful response into an error, keeping the code and message from its error body.
+/// Anything that doesn't send a structured error body gets its body passed along as the message.
+pub async fn api_failure(response: Response) -> ServalError {
+    let status = response.status().as_u16();
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(e) => return e.into(),
+    };
+    match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(ErrorResponse { error }) => ServalError::ApiFailure {
+            status,
+            code: error.code,
+            message: error.message,
+        },
+        Err(_) => ServalError::ApiFailure {
+            status,
+            code: "unknown".to_string(),
+            message: body,
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {}
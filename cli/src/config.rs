@@ -0,0 +1,137 @@
+// Pounce's settings, read once at startup from its config file and the environment, so that they
+// needn't be set in the environment for every invocation.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::OutputFormat;
+
+static CONFIG: OnceCell<CliConfig> = OnceCell::new();
+
+/// Pounce's settings: whatever its config file says, overridden by anything set in the
+/// environment. Command-line flags override both.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CliConfig {
+    /// The node to talk to, as an ip:port pair; `SERVAL_NODE_URL`. Without one, pounce talks to
+    /// whichever node discovery finds.
+    pub node_url: Option<String>,
+    /// The tenant to act for; `SERVAL_TENANT`.
+    pub tenant: Option<String>,
+    /// How `run` shows a job's output when `--as` isn't given.
+    pub output_format: Option<OutputFormat>,
+    /// The mesh namespace to look for nodes in; `MESH_NAMESPACE`.
+    pub mesh_namespace: Option<String>,
+    /// The port the mesh talks on; `MESH_PORT`.
+    pub mesh_port: Option<u16>,
+    /// How long to wait for a node to answer discovery, in seconds; `DISCOVERY_TIMEOUT`.
+    pub discovery_timeout: Option<f64>,
+    /// How many times to look for another node when the one we found stops answering. Defaults
+    /// to 3; 0 gives up at once.
+    pub rediscovery_attempts: Option<u32>,
+}
+
+impl CliConfig {
+    /// Read the config file at the given path, or at the default path if none is given. Only a
+    /// file that was asked for by name has to exist.
+    fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if !required && e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(anyhow!("unable to read {}: {e}", path.display())),
+        };
+        toml::from_str(&text).map_err(|e| anyhow!("invalid config file {}: {e}", path.display()))
+    }
+
+    // Let whatever is set in the environment win over the file.
+    fn apply_env(&mut self) {
+        if let Ok(node_url) = std::env::var("SERVAL_NODE_URL") {
+            self.node_url = Some(node_url);
+        }
+        if let Ok(tenant) = std::env::var("SERVAL_TENANT") {
+            self.tenant = Some(tenant);
+        }
+    }
+
+    // The mesh settings are read from the environment wherever they're needed, so hand the file's
+    // on to it, unless the environment has its own.
+    fn export_mesh_settings(&self) {
+        let settings = [
+            ("MESH_NAMESPACE", self.mesh_namespace.clone()),
+            ("MESH_PORT", self.mesh_port.map(|port| port.to_string())),
+            (
+                "DISCOVERY_TIMEOUT",
+                self.discovery_timeout.map(|secs| secs.to_string()),
+            ),
+        ];
+        for (var, value) in settings {
+            if let Some(value) = value {
+                if std::env::var_os(var).is_none() {
+                    std::env::set_var(var, value);
+                }
+            }
+        }
+    }
+}
+
+/// Load pounce's settings from the given config file, or from the default one if there is one.
+/// Call this once, at startup.
+pub fn init(path: Option<&Path>) -> Result<()> {
+    let mut config = CliConfig::load(path)?;
+    config.apply_env();
+    config.export_mesh_settings();
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow!("pounce's settings were loaded twice"))
+}
+
+/// Pounce's settings, as loaded at startup.
+pub fn config() -> &'static CliConfig {
+    CONFIG.get_or_init(CliConfig::default)
+}
+
+/// `$XDG_CONFIG_HOME/serval/config.toml`, falling back to `~/.config/serval/config.toml`.
+fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("serval").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_parsing() {
+        let config: CliConfig = toml::from_str(
+            r#"
+node_url = "127.0.0.1:8100"
+tenant = "acme"
+output_format = "json"
+mesh_port = 8181
+discovery_timeout = 2.5
+rediscovery_attempts = 5
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.node_url.as_deref(), Some("127.0.0.1:8100"));
+        assert_eq!(config.output_format, Some(OutputFormat::Json));
+        assert_eq!(config.mesh_port, Some(8181));
+        assert_eq!(config.rediscovery_attempts, Some(5));
+
+        assert!(toml::from_str::<CliConfig>("auth_token = \"hunter2\"").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+// Checking that this machine can find and use a serval mesh, and saying what to do if it can't.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use owo_colors::OwoColorize;
+use serval_client::ServalApiClient;
+use utils::mesh::{PeerMetadata, ServalRole};
+
+use crate::config::config;
+
+/// How a single check went.
+enum Outcome {
+    Pass(String),
+    /// Not a problem, but worth knowing.
+    Note(String),
+    /// What went wrong, and what to try.
+    Fail(String, &'static str),
+}
+
+fn report(check: &str, outcome: &Outcome) {
+    match outcome {
+        Outcome::Pass(detail) => println!("{} {check}: {detail}", "PASS".green().bold()),
+        Outcome::Note(detail) => println!("{} {check}: {detail}", "NOTE".yellow().bold()),
+        Outcome::Fail(detail, hint) => {
+            println!("{} {check}: {detail}", "FAIL".red().bold());
+            println!("     {} {hint}", "hint:".bold());
+        }
+    }
+}
+
+/// Look for mesh members the same way every other command does.
+async fn check_discovery() -> (Outcome, Vec<PeerMetadata>) {
+    let peers = utils::mesh::discover_all(None, Duration::from_secs(3)).await;
+    let outcome = if peers.is_empty() {
+        Outcome::Fail(
+            "no nodes answered on the mesh".to_string(),
+            "start an agent on this network, and check that MESH_PORT, MESH_INTERFACE, and MESH_NAMESPACE match its settings",
+        )
+    } else {
+        Outcome::Pass(format!("found {} node(s)", peers.len()))
+    };
+    (outcome, peers)
+}
+
+/// If SERVAL_NODE_URL, or node_url in the config file, names a node, make sure it's a usable
+/// address.
+fn check_node_url() -> (Outcome, Option<SocketAddr>) {
+    match &config().node_url {
+        None => (
+            Outcome::Note("not set; pounce will use whichever node discovery finds".to_string()),
+            None,
+        ),
+        Some(value) => match value.parse::<SocketAddr>() {
+            Ok(addr) => (Outcome::Pass(format!("set to {addr}")), Some(addr)),
+            Err(_) => (
+                Outcome::Fail(
+                    format!("{value:?} is not an address"),
+                    "set SERVAL_NODE_URL or node_url to an ip:port pair, such as 127.0.0.1:8100, or unset it",
+                ),
+                None,
+            ),
+        },
+    }
+}
+
+/// Ping a node and ask it how it's doing, through the same endpoints monitoring uses.
+async fn check_reachable(addr: SocketAddr) -> Outcome {
+    let client = ServalApiClient::new(addr.to_string());
+    if let Err(e) = client.ping().await {
+        return Outcome::Fail(
+            format!("{addr} did not answer a ping: {e}"),
+            "check that the agent is running and that its API_HOST and API_PORT are reachable from here",
+        );
+    }
+    match client.monitor_status().await {
+        Ok(status) => Outcome::Pass(format!(
+            "{addr} is up; instance {}, running {} job(s)",
+            status.instance_id, status.running_jobs
+        )),
+        Err(e) => Outcome::Fail(
+            format!("{addr} answered a ping but not a status request: {e}"),
+            "the node may be running an older agent; upgrade it",
+        ),
+    }
+}
+
+/// Find a node that will run jobs. Agents only take the runner role when our Wasm engine supports
+/// their platform, so a runner is also proof of a working engine.
+async fn check_runner(addr: SocketAddr, discovered: &[PeerMetadata]) -> Outcome {
+    let runners = match ServalApiClient::new(addr.to_string())
+        .peers_with_role(ServalRole::Runner)
+        .await
+    {
+        Ok(runners) => runners,
+        Err(e) => {
+            return Outcome::Fail(
+                format!("{addr} could not list runners: {e}"),
+                "check the node's logs for mesh errors",
+            )
+        }
+    };
+    let node_is_runner = discovered.iter().any(|peer| {
+        peer.http_address() == Some(addr) && peer.roles().contains(&ServalRole::Runner)
+    });
+
+    if node_is_runner {
+        Outcome::Pass(format!("{addr} runs jobs itself"))
+    } else if !runners.is_empty() {
+        Outcome::Pass(format!(
+            "{addr} will relay jobs to {} runner(s)",
+            runners.len()
+        ))
+    } else {
+        Outcome::Fail(
+            "no node on the mesh has the runner role".to_string(),
+            "start an agent with RUNNER_ROLE=always on a platform the Wasm engine supports; it will refuse to start if the engine is unavailable",
+        )
+    }
+}
+
+/// Run every check and print a report. Fails if any check did.
+pub async fn doctor() -> Result<()> {
+    let mut failures = 0;
+    let mut record = |check: &str, outcome: Outcome| {
+        if matches!(outcome, Outcome::Fail(..)) {
+            failures += 1;
+        }
+        report(check, &outcome);
+    };
+
+    let (outcome, discovered) = check_discovery().await;
+    record("mesh discovery", outcome);
+    let (outcome, configured) = check_node_url();
+    record("SERVAL_NODE_URL", outcome);
+
+    let node = configured.or_else(|| discovered.iter().find_map(|peer| peer.http_address()));
+    match node {
+        Some(addr) => {
+            record("node reachable", check_reachable(addr).await);
+            record(
+                "runner with a Wasm engine",
+                check_runner(addr, &discovered).await,
+            );
+        }
+        None => record(
+            "node reachable",
+            Outcome::Fail(
+                "there is no node to check".to_string(),
+                "fix the checks above first",
+            ),
+        ),
+    }
+
+    if failures > 0 {
+        return Err(anyhow!("{failures} check(s) failed"));
+    }
+    println!("Everything looks good.");
+    Ok(())
+}
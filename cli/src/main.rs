@@ -6,26 +6,42 @@
     trivial_casts,
     unused_qualifications
 )]
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Pounce is a CLI tool that interacts with a running serval agent daemon via
 /// its HTTP API. It discovers running agents via mDNS advertisement.
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenvy::dotenv;
 use humansize::{format_size, BINARY};
 use owo_colors::OwoColorize;
 use prettytable::{row, Table};
+use serde::Deserialize;
+use serval_client::RunOptions;
+use utils::errors::ServalError;
 use utils::mesh::ServalRole;
+use utils::signing::{SigningKey, SIGNING_KEY_ENV};
 
+mod config;
+mod doctor;
 mod mesh;
 mod peers;
+mod repeat;
+mod scaffold;
 
-use peers::api_client;
-use utils::structs::Manifest;
+use peers::{api_client, with_rediscovery};
+use utils::structs::api::{
+    ErrorResponse, JobFailureResponse, EXIT_CODE_HEADER, OUTPUT_LOCATION_HEADER,
+    STORAGE_POINTER_SCHEME,
+};
+use utils::structs::{Job, Manifest, ManifestOverrides};
 
 #[derive(Parser, Debug)]
 #[clap(name = "pounce 🐈", version)]
@@ -37,6 +53,10 @@ struct Args {
         help = "Pass -v or -vv to increase verbosity"
     )]
     verbose: u8,
+    /// Read settings from this file rather than from ~/.config/serval/config.toml. Settings in
+    /// the environment take precedence over those in the file.
+    #[clap(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -46,18 +66,109 @@ pub enum Command {
     /// Store the given Wasm task type in the mesh.
     #[clap(display_order = 1)]
     Store {
-        /// Path to the task manifest file.
+        /// Path to the task manifest file, or `-` to read the manifest from stdin.
         manifest: PathBuf,
+        /// Path to the executable to store, instead of the one the manifest names. The manifest's
+        /// own path is relative to the manifest file, or to this directory for a manifest on stdin.
+        #[clap(long)]
+        binary: Option<PathBuf>,
+        /// The format of the executable the manifest points to; inferred from its file extension
+        /// if omitted. WebAssembly text is assembled to binary before it is stored.
+        #[clap(long, value_enum)]
+        format: Option<WasmFormat>,
     },
     /// Run the specified Wasm binary.
     #[clap(display_order = 2)]
     Run {
-        /// The name of the previously-stored job to run.
+        /// The name of the previously-stored job to run. Add `@version` or `@tag` to run a
+        /// particular stored version, e.g. `loudify@stable`.
         name: String,
         /// Path to a file to pass to the binary; omit to read from stdin (if present)
         input_file: Option<PathBuf>,
         /// Path to write the output of the job; omit to write to stdout
         output_file: Option<PathBuf>,
+        /// A URL for the runner to POST the job's outcome to once it has finished.
+        #[clap(long)]
+        callback: Option<String>,
+        /// Change a resource limit in the stored manifest for this run only, e.g.
+        /// `--override max_memory_bytes=67108864`. May be given more than once.
+        #[clap(long = "override", value_name = "FIELD=VALUE")]
+        overrides: Vec<String>,
+        /// Let the job run for up to this many seconds, rather than its manifest's limit. Nodes
+        /// may still stop it sooner.
+        #[clap(long, value_name = "SECS")]
+        timeout: Option<u64>,
+        /// Accept the job's output even if it doesn't match the manifest's output schema.
+        #[clap(long)]
+        skip_output_schema: bool,
+        /// Show the job's output another way: as UTF-8 text, as pretty-printed JSON, or encoded
+        /// as hex or base64. Output is written as it is by default.
+        #[clap(long = "as", value_enum, value_name = "FORMAT")]
+        output_format: Option<OutputFormat>,
+        /// Attach a label to this run, e.g. `--label team=birds`. May be given more than once.
+        #[clap(long = "label", value_name = "KEY=VALUE")]
+        labels: Vec<String>,
+        /// Run this Wasm executable, without storing it, instead of a stored job; the name is
+        /// then only a label for it. It runs under the node's default limits.
+        #[clap(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = [
+                "callback",
+                "overrides",
+                "timeout",
+                "skip_output_schema",
+                "labels"
+            ]
+        )]
+        wasm: Option<PathBuf>,
+        /// Run the job this many times on the same input and summarize the results, rather than
+        /// printing the output. Fails if any run fails or if the runs give different outputs.
+        #[clap(
+            long,
+            conflicts_with_all = [
+                "output_file",
+                "callback",
+                "overrides",
+                "timeout",
+                "skip_output_schema",
+                "output_format",
+                "labels",
+                "wasm"
+            ]
+        )]
+        repeat: Option<u32>,
+        /// With --repeat, start every run at once instead of one after another.
+        #[clap(long, requires = "repeat")]
+        parallel: bool,
+    },
+    /// Write a manifest skeleton for a Wasm module, next to the module.
+    #[clap(display_order = 2)]
+    InitManifest {
+        /// Path to the Wasm module to describe.
+        binary: PathBuf,
+        /// The namespace to put the job type in.
+        #[clap(long, default_value = "local")]
+        namespace: String,
+    },
+    /// List the jobs a node is running right now.
+    #[clap(display_order = 2)]
+    Running {
+        /// Ask every runner on the mesh, not just one.
+        #[clap(long)]
+        all: bool,
+        /// Only list jobs with this label.
+        #[clap(long, value_name = "KEY=VALUE")]
+        label: Option<String>,
+        /// How to show the jobs: as a table, or one short line per job for scanning many.
+        #[clap(long, value_enum, default_value = "table")]
+        output: ListingFormat,
+    },
+    /// Stop a job that is running right now.
+    #[clap(display_order = 2)]
+    Cancel {
+        /// The id of the running job, as logged by the node running it.
+        id: String,
     },
     /// Get the manifest for a stored job type.
     #[clap(display_order = 3)]
@@ -65,6 +176,54 @@ pub enum Command {
         /// The name of the stored job.
         name: String,
     },
+    /// List the stored versions of a job type.
+    #[clap(display_order = 3)]
+    Versions {
+        /// The name of the stored job.
+        name: String,
+    },
+    /// Show what changed in a job type's manifest and executable between two stored versions.
+    #[clap(display_order = 3)]
+    Diff {
+        /// The name of the stored job.
+        name: String,
+        /// The version to compare from.
+        from: String,
+        /// The version to compare to.
+        to: String,
+    },
+    /// Point a version tag such as `stable` at a stored version of a job type.
+    #[clap(display_order = 3)]
+    Tag {
+        /// The name of the stored job.
+        name: String,
+        /// The tag to set.
+        tag: String,
+        /// The stored version the tag should point at.
+        version: String,
+    },
+    /// List stored job types.
+    #[clap(display_order = 4)]
+    List {
+        /// Only list job types with this tag.
+        #[clap(long)]
+        tag: Option<String>,
+        /// Only list job types with this string in their name or description.
+        #[clap(long)]
+        search: Option<String>,
+    },
+    /// Save every stored manifest and executable on the mesh to a tar archive, as a backup.
+    #[clap(display_order = 4)]
+    Export {
+        /// Where to write the archive.
+        file: PathBuf,
+    },
+    /// Restore manifests and executables from an archive written by `export`.
+    #[clap(display_order = 4)]
+    Import {
+        /// The archive to restore.
+        file: PathBuf,
+    },
     /// List all known peers of this node.
     #[clap(display_order = 4)]
     Peers,
@@ -75,24 +234,155 @@ pub enum Command {
         role: ServalRole,
     },
     NodeStatus,
+    /// Show how loaded every node on the mesh is.
+    Nodes,
     /// Liveness check: ping at least one node on the mesh.
     Ping,
+    /// Show which version of pounce this is, and which build of the agent a node is running.
+    Version {
+        /// The instance id of the node to ask; omit to ask whichever node we find first.
+        #[clap(long)]
+        node: Option<String>,
+    },
+    /// Check that this machine can find a mesh and run jobs on it, with hints for fixing problems.
+    Doctor,
     /// Monitor a mesh: print out new peers and departing peers as we learn about them.
     Monitor,
 }
 
-async fn upload_manifest(manifest_path: PathBuf) -> Result<()> {
-    println!("Reading manifest: {}", manifest_path.display());
-    let manifest = Manifest::from_file(&manifest_path)?;
+/// The formats a Wasm executable can be read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WasmFormat {
+    /// Binary WebAssembly.
+    Wasm,
+    /// WebAssembly text format.
+    Wat,
+}
+
+impl WasmFormat {
+    /// Guess the format of a Wasm executable from its file extension, defaulting to binary.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("wat") | Some("wast") => WasmFormat::Wat,
+            _ => WasmFormat::Wasm,
+        }
+    }
+}
+
+/// The ways `run` can show a job's output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The bytes exactly as the job wrote them.
+    Raw,
+    /// UTF-8 text, with anything that isn't valid UTF-8 replaced.
+    Text,
+    /// JSON, pretty-printed; fails if the output isn't JSON.
+    Json,
+    /// Lowercase hex.
+    Hex,
+    /// Standard base64.
+    Base64,
+}
+
+impl OutputFormat {
+    /// Transform a job's output into this format.
+    fn apply(self, output: Vec<u8>) -> Result<Vec<u8>> {
+        let formatted = match self {
+            OutputFormat::Raw => return Ok(output),
+            OutputFormat::Text => String::from_utf8_lossy(&output).into_owned(),
+            OutputFormat::Json => {
+                let value: serde_json::Value = serde_json::from_slice(&output)
+                    .map_err(|e| anyhow!("the job's output is not JSON: {e}"))?;
+                serde_json::to_string_pretty(&value)?
+            }
+            OutputFormat::Hex => output.iter().map(|byte| format!("{byte:02x}")).collect(),
+            OutputFormat::Base64 => BASE64.encode(&output),
+        };
+        Ok(format!("{formatted}\n").into_bytes())
+    }
+}
+
+/// The ways to show a list of jobs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ListingFormat {
+    /// A table with a column for everything we know about each job.
+    Table,
+    /// One line per job: short id, name, status, and how long it has been going.
+    Compact,
+}
 
-    let mut wasmpath = manifest_path
-        .parent()
-        .unwrap_or_else(|| Path::new("."))
-        .to_path_buf();
-    wasmpath.push(manifest.binary());
+/// Work out where a manifest's executable is, and point the manifest at it by absolute path, as
+/// storage nodes only accept manifests that name their binary that way.
+fn resolve_binary(
+    manifest: &mut Manifest,
+    manifest_dir: &Path,
+    binary: Option<PathBuf>,
+) -> Result<PathBuf> {
+    let wasmpath = binary.unwrap_or_else(|| manifest_dir.join(manifest.binary()));
+    let absolute = std::fs::canonicalize(&wasmpath)
+        .map_err(|e| anyhow!("unable to find {}: {e}", wasmpath.display()))?;
+    manifest.set_binary(absolute);
+    Ok(wasmpath)
+}
+
+async fn upload_manifest(
+    manifest_path: PathBuf,
+    binary: Option<PathBuf>,
+    format: Option<WasmFormat>,
+) -> Result<()> {
+    // Paths in the manifest are relative to the manifest file, if there is one.
+    let (mut manifest, manifest_dir) = if manifest_path == Path::new("-") {
+        if atty::is(atty::Stream::Stdin) {
+            return Err(anyhow!("expected a manifest on stdin"));
+        }
+        println!("Reading manifest from stdin");
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        let manifest: Manifest =
+            toml::from_str(&text).map_err(|e| anyhow!("invalid manifest on stdin: {e}"))?;
+        (manifest, PathBuf::from("."))
+    } else {
+        println!("Reading manifest: {}", manifest_path.display());
+        let manifest = Manifest::from_file(&manifest_path)?;
+        let dir = manifest_path.parent().unwrap_or_else(|| Path::new("."));
+        (manifest, dir.to_path_buf())
+    };
+
+    let wasmpath = resolve_binary(&mut manifest, &manifest_dir, binary)?;
 
     println!("Reading Wasm executable:{}", wasmpath.display());
-    let executable = read_file(wasmpath)?;
+    let format = format.unwrap_or_else(|| WasmFormat::from_path(&wasmpath));
+    let mut executable = read_file(wasmpath)?;
+    if format == WasmFormat::Wat {
+        // Runners only ever see binary Wasm, so assemble text modules here.
+        executable = wat::parse_bytes(&executable)?.into_owned();
+    }
+
+    // A schema named by path goes into the blob store, and the manifest points at it there.
+    let mut schema_address = None;
+    if let Some(schema) = manifest.output_schema() {
+        let schema_path = manifest_dir.join(schema);
+        if schema_path.is_file() {
+            println!("Reading output schema: {}", schema_path.display());
+            let schema = read_file(schema_path)?;
+            serde_json::from_slice::<serde_json::Value>(&schema)
+                .map_err(|e| anyhow!("the output schema is not JSON: {e}"))?;
+            let address = api_client().await.store_by_integrity(schema).await?;
+            manifest.set_output_schema(Some(address.to_string()));
+            schema_address = Some(address);
+        }
+    }
+
+    // Sign the manifest and executable together, for storage nodes that check publishers.
+    let signer = match std::env::var(SIGNING_KEY_ENV) {
+        Ok(seed) => {
+            let key = SigningKey::from_hex(&seed)?;
+            key.sign(&mut manifest, &executable)?;
+            Some(key.public_key_hex())
+        }
+        Err(_) => None,
+    };
 
     let serval = api_client().await;
 
@@ -102,6 +392,12 @@ async fn upload_manifest(manifest_path: PathBuf) -> Result<()> {
     table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
     table.add_row(row!["Wasm task name:", manifest.fq_name()]);
     table.add_row(row!["Version:", manifest.version()]);
+    if let Some(address) = schema_address {
+        table.add_row(row!["Output schema:", address]);
+    }
+    if let Some(signer) = signer {
+        table.add_row(row!["Signed by:", signer]);
+    }
 
     let manifest_resp = serval.store_manifest(&manifest).await;
     let Ok(manifest_integrity) = manifest_resp else {
@@ -115,9 +411,23 @@ async fn upload_manifest(manifest_path: PathBuf) -> Result<()> {
 
     table.add_row(row!["Manifest integrity:", manifest_integrity]);
 
-    let exec_resp = serval
-        .store_executable(&manifest.fq_name(), manifest.version(), executable)
-        .await;
+    // Big executables go up in pieces, so that a dropped connection doesn't mean starting over.
+    const RESUMABLE_THRESHOLD: usize = 8 * 1024 * 1024;
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    let exec_resp = if executable.len() > RESUMABLE_THRESHOLD {
+        serval
+            .store_executable_resumable(
+                &manifest.fq_name(),
+                manifest.version(),
+                executable,
+                CHUNK_SIZE,
+            )
+            .await
+    } else {
+        serval
+            .store_executable(&manifest.fq_name(), manifest.version(), executable)
+            .await
+    };
     if let Ok(wasm_integrity) = exec_resp {
         table.add_row(row!["Wasm integrity:", wasm_integrity]);
         table.add_row(row![
@@ -163,11 +473,49 @@ fn read_file(path: PathBuf) -> Result<Vec<u8>, anyhow::Error> {
     Ok(buf)
 }
 
+/// Turn `field=value` pairs from the command line, and any `--timeout`, into manifest overrides,
+/// checking them against the fields a run may override before we bother sending anything.
+fn parse_overrides(pairs: &[String], timeout: Option<u64>) -> Result<Option<ManifestOverrides>> {
+    if pairs.is_empty() && timeout.is_none() {
+        return Ok(None);
+    }
+    let mut fields = serde_json::Map::new();
+    for pair in pairs {
+        let Some((field, value)) = pair.split_once('=') else {
+            return Err(anyhow!("override {pair} should look like FIELD=VALUE"));
+        };
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        fields.insert(field.to_string(), value);
+    }
+    if let Some(secs) = timeout {
+        fields.insert("max_duration_ms".to_string(), (secs * 1000).into());
+    }
+    let overrides = serde_json::from_value(serde_json::Value::Object(fields))
+        .map_err(|e| anyhow!("invalid override: {e}"))?;
+    Ok(Some(overrides))
+}
+
+/// Turn `key=value` pairs from the command line into job labels, checking them as a runner would.
+fn parse_labels(pairs: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut labels = BTreeMap::new();
+    for pair in pairs {
+        let Some((key, value)) = pair.split_once('=') else {
+            return Err(anyhow!("label {pair} should look like KEY=VALUE"));
+        };
+        labels.insert(key.to_string(), value.to_string());
+    }
+    Job::check_labels(&labels)?;
+    Ok(labels)
+}
+
 /// Request that an available agent run a stored job, with optional input.
 async fn run(
     name: String,
     maybe_input: Option<PathBuf>,
     maybe_output: Option<PathBuf>,
+    options: RunOptions,
+    output_format: Option<OutputFormat>,
 ) -> Result<()> {
     let input_bytes = read_file_or_stdin(maybe_input)?;
 
@@ -177,17 +525,121 @@ async fn run(
         format_size(input_bytes.len(), BINARY),
     );
 
-    let serval = api_client().await;
-    let response = serval.run_job(&name, input_bytes).await?;
+    // A run that couldn't connect never reached a node, so it's safe to send again elsewhere.
+    let response = with_rediscovery(|serval| {
+        let (name, input, options) = (name.clone(), input_bytes.clone(), options.clone());
+        async move { serval.run_job(&name, input, &options).await }
+    })
+    .await?;
+    show_run_response(response, maybe_output, output_format).await
+}
 
+/// Run a Wasm executable from a local file without storing it first, under the node's default
+/// limits. The name is only for logs and output.
+async fn run_adhoc(
+    name: String,
+    wasm: PathBuf,
+    maybe_input: Option<PathBuf>,
+    maybe_output: Option<PathBuf>,
+    output_format: Option<OutputFormat>,
+) -> Result<()> {
+    let mut executable = read_file(wasm.clone())?;
+    if WasmFormat::from_path(&wasm) == WasmFormat::Wat {
+        executable = wat::parse_bytes(&executable)?.into_owned();
+    }
+    let input_bytes = read_file_or_stdin(maybe_input)?;
+
+    println!(
+        "Sending {} executable {} with {} payload to serval agent...",
+        format_size(executable.len(), BINARY),
+        name.blue().bold(),
+        format_size(input_bytes.len(), BINARY),
+    );
+
+    let response = with_rediscovery(|serval| {
+        let (name, executable, input) = (name.clone(), executable.clone(), input_bytes.clone());
+        async move { serval.run_adhoc_job(&name, executable, input).await }
+    })
+    .await?;
+    show_run_response(response, maybe_output, output_format).await
+}
+
+/// Report how a run went: why it failed, or its output, shown the way it was asked for.
+async fn show_run_response(
+    response: reqwest::Response,
+    maybe_output: Option<PathBuf>,
+    output_format: Option<OutputFormat>,
+) -> Result<()> {
     if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await?;
         println!("Running the Wasm failed!");
-        println!("{} {}", response.status(), response.text().await?);
+        match serde_json::from_str::<JobFailureResponse>(&body) {
+            Ok(JobFailureResponse {
+                failure,
+                stdout,
+                stderr,
+            }) => {
+                println!("{status} {}: {}", failure.kind.bold(), failure.message);
+                if let Some(code) = failure.exit_code {
+                    println!("exit code: {code}");
+                }
+                if let Some(backtrace) = failure.backtrace {
+                    eprintln!("{}", "guest backtrace:".bold());
+                    eprintln!("{backtrace}");
+                }
+                if !stdout.is_empty() {
+                    println!("{}", "output before the job was stopped:".bold());
+                    println!("{stdout}");
+                }
+                if !stderr.is_empty() {
+                    eprintln!("----------");
+                    eprintln!("{stderr}");
+                    eprintln!("----------");
+                }
+            }
+            Err(_) => match serde_json::from_str::<ErrorResponse>(&body) {
+                Ok(ErrorResponse { error }) => {
+                    print_api_error(status.as_u16(), &error.code, &error.message)
+                }
+                Err(_) => println!("{status} {body}"),
+            },
+        }
         return Ok(());
     }
 
-    let response_body = response.bytes().await?;
+    let exit_code = response
+        .headers()
+        .get(EXIT_CODE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or_default();
+    if exit_code != 0 {
+        eprintln!("Job exited with code {exit_code}, which its manifest accepts.");
+    }
+
+    let stored_at = response
+        .headers()
+        .get(OUTPUT_LOCATION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut response_body = response.bytes().await?.to_vec();
     log::info!("response body read; length={}", response_body.len());
+    // Jobs with big outputs leave them in the blob store for us to fetch.
+    if let Some(pointer) = stored_at {
+        let integrity = pointer
+            .strip_prefix(STORAGE_POINTER_SCHEME)
+            .ok_or_else(|| anyhow!("unrecognized output location {pointer}"))?;
+        eprintln!(
+            "Job output was stored at {}; fetching it...",
+            pointer.bold()
+        );
+        response_body = api_client().await.stream_by_integrity(integrity).await?;
+    }
+    let output_format = output_format
+        .or(config::config().output_format)
+        .unwrap_or(OutputFormat::Raw);
+    response_body = output_format.apply(response_body)?;
     match maybe_output {
         Some(outputpath) => {
             eprintln!("Writing output to {outputpath:?}");
@@ -195,7 +647,10 @@ async fn run(
             f.write_all(&response_body)?;
         }
         None => {
-            if atty::is(atty::Stream::Stdin) && String::from_utf8(response_body.to_vec()).is_err() {
+            if output_format == OutputFormat::Raw
+                && atty::is(atty::Stream::Stdin)
+                && String::from_utf8(response_body.to_vec()).is_err()
+            {
                 eprintln!("Response is non-printable binary data; redirect output to a file or provide an output filename to retrieve it.");
             } else {
                 eprintln!("----------");
@@ -208,12 +663,231 @@ async fn run(
     Ok(())
 }
 
+/// Show the jobs running on one node, or on every runner we can find, as a table.
+async fn list_running(all: bool, label: Option<String>, output: ListingFormat) -> Result<()> {
+    let clients = if all {
+        let peers =
+            utils::mesh::discover_all(Some(&ServalRole::Runner), Duration::from_secs(3)).await;
+        peers
+            .iter()
+            .filter_map(|peer| peer.http_address())
+            .map(|addr| serval_client::ServalApiClient::new(addr.to_string()))
+            .collect()
+    } else {
+        vec![api_client().await]
+    };
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Id".bold(),
+        "Name".bold(),
+        "Running for".bold(),
+        "Input".bold(),
+        "Labels".bold(),
+        "Node".bold()
+    ]);
+    let mut count = 0;
+    for client in clients {
+        let jobs = match client.list_jobs(label.as_deref()).await {
+            Ok(jobs) => jobs,
+            // One unreachable node shouldn't hide what the others are doing.
+            Err(err) if all => {
+                log::info!(
+                    "failed to list running jobs; peer={}; err={err}",
+                    client.socket_addr()
+                );
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        for job in jobs {
+            count += 1;
+            if output == ListingFormat::Compact {
+                // Every job listed is running; finished and failed jobs aren't kept to list.
+                println!(
+                    "{} {} {} {:.1}s",
+                    job.id.to_string()[..8].dimmed(),
+                    job.name,
+                    "running".yellow(),
+                    job.elapsed_ms as f64 / 1000.0
+                );
+                continue;
+            }
+            table.add_row(row![
+                job.id,
+                job.name,
+                format!("{:.1}s", job.elapsed_ms as f64 / 1000.0),
+                format_size(job.input_bytes, BINARY),
+                job.labels
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                client.socket_addr()
+            ]);
+        }
+    }
+
+    if count == 0 {
+        println!("No jobs running.");
+    } else if output == ListingFormat::Table {
+        println!("{table}");
+    }
+    Ok(())
+}
+
+/// Ask the node running a job to stop it.
+async fn cancel(id: String) -> Result<()> {
+    api_client().await.cancel_job(&id).await?;
+    println!("Job {id} is being cancelled.");
+    Ok(())
+}
+
 async fn get_manifest(name: String) -> Result<()> {
     let manifest = api_client().await.get_manifest(&name).await?;
     println!("{}", serde_json::to_string_pretty(&manifest)?);
     Ok(())
 }
 
+async fn list_manifests(tag: Option<String>, search: Option<String>) -> Result<()> {
+    let manifests = api_client()
+        .await
+        .list_manifests(tag.as_deref(), search.as_deref())
+        .await?;
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Name".bold(),
+        "Version".bold(),
+        "Tags".bold(),
+        "Description".bold()
+    ]);
+    for manifest in manifests {
+        table.add_row(row![
+            manifest.fq_name(),
+            manifest.version(),
+            manifest.tags().join(", "),
+            manifest.description()
+        ]);
+    }
+    println!("{table}");
+    Ok(())
+}
+
+async fn list_versions(name: String) -> Result<()> {
+    let versions = api_client().await.manifest_versions(&name).await?;
+    if versions.is_empty() {
+        println!("No versions of {} are stored.", name.bold());
+    }
+    for version in versions {
+        println!("{version}");
+    }
+    Ok(())
+}
+
+/// Print every manifest field that differs between two versions of a job, then whether the
+/// executable itself changed.
+async fn diff_versions(name: String, from: String, to: String) -> Result<()> {
+    let serval = api_client().await;
+    let old = serval.get_manifest_version(&name, &from).await?;
+    let new = serval.get_manifest_version(&name, &to).await?;
+
+    println!("{} {} -> {}", name.bold(), from.red(), to.green());
+    let (serde_json::Value::Object(old), serde_json::Value::Object(new)) =
+        (serde_json::to_value(&old)?, serde_json::to_value(&new)?)
+    else {
+        return Err(anyhow!("manifests did not serialize as objects"));
+    };
+    let mut fields: Vec<&String> = old.keys().chain(new.keys()).collect();
+    fields.sort();
+    fields.dedup();
+
+    let mut changed = 0;
+    for field in fields {
+        if field == "version" {
+            continue;
+        }
+        let (before, after) = (old.get(field), new.get(field));
+        if before == after {
+            continue;
+        }
+        changed += 1;
+        let show = |value: Option<&serde_json::Value>| {
+            value.map_or_else(|| "(unset)".to_string(), |v| v.to_string())
+        };
+        println!(
+            "  {field}: {} -> {}",
+            show(before).red(),
+            show(after).green()
+        );
+    }
+    if changed == 0 {
+        println!("  manifests are the same apart from the version");
+    }
+
+    let old_exe = serval.executable_integrity(&name, &from).await;
+    let new_exe = serval.executable_integrity(&name, &to).await;
+    match (old_exe, new_exe) {
+        (Ok(old_exe), Ok(new_exe)) if old_exe == new_exe => {
+            println!("  executable: unchanged ({old_exe})");
+        }
+        (Ok(old_exe), Ok(new_exe)) => {
+            println!(
+                "  {} {} -> {}",
+                "executable changed:".yellow().bold(),
+                old_exe.to_string().red(),
+                new_exe.to_string().green()
+            );
+        }
+        (old_exe, new_exe) => {
+            let show = |exe: Result<ssri::Integrity, ServalError>| {
+                exe.map_or_else(|_| "(not stored)".to_string(), |i| i.to_string())
+            };
+            println!("  executable: {} -> {}", show(old_exe), show(new_exe));
+        }
+    }
+    Ok(())
+}
+
+/// Point a version tag at a stored version of a job.
+async fn tag_version(name: String, tag: String, version: String) -> Result<()> {
+    api_client()
+        .await
+        .set_version_tag(&name, &tag, &version)
+        .await?;
+    println!(
+        "{} now runs version {}",
+        format!("{name}@{tag}").bold(),
+        version.bold()
+    );
+    Ok(())
+}
+
+/// Write a backup of the mesh's storage to a file.
+async fn export_storage(file: PathBuf) -> Result<()> {
+    let archive = api_client().await.export_storage().await?;
+    std::fs::write(&file, &archive)?;
+    println!(
+        "Wrote {} bytes of manifests and executables to {}",
+        archive.len(),
+        file.display().bold()
+    );
+    Ok(())
+}
+
+/// Restore a backup written by `export_storage()`.
+async fn import_storage(file: PathBuf) -> Result<()> {
+    let archive = std::fs::read(&file)?;
+    let summary = api_client().await.import_storage(archive).await?;
+    println!(
+        "Imported {} manifests and executables; {} were already stored.",
+        summary.imported, summary.skipped
+    );
+    Ok(())
+}
+
 async fn list_peers() -> Result<()> {
     let body = api_client().await.all_peers().await?;
     println!("{}", serde_json::to_string_pretty(&body)?);
@@ -234,6 +908,95 @@ async fn monitor_status() -> Result<()> {
     Ok(())
 }
 
+/// Ask every node we know of how busy it is, and render the answers as a table.
+async fn list_nodes() -> Result<()> {
+    let peers = utils::mesh::discover_all(None, Duration::from_secs(3)).await;
+    let addrs = peers.iter().filter_map(|peer| peer.http_address());
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Instance".bold(),
+        "Address".bold(),
+        "Load".bold(),
+        "Available memory".bold(),
+        "Running jobs".bold(),
+        "Runtimes".bold(),
+        "Version".bold()
+    ]);
+    for addr in addrs {
+        let client = serval_client::ServalApiClient::new(addr.to_string());
+        match client.monitor_status().await {
+            Ok(status) => {
+                table.add_row(row![
+                    status.instance_id,
+                    addr,
+                    status
+                        .load_average
+                        .map(|load| format!("{load:.2}"))
+                        .unwrap_or_else(|| "-".to_string()),
+                    status
+                        .available_memory_bytes
+                        .map(|bytes| format_size(bytes, BINARY))
+                        .unwrap_or_else(|| "-".to_string()),
+                    status.running_jobs,
+                    status
+                        .runtimes
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    status
+                        .build
+                        .map(|build| match build.git_sha {
+                            Some(sha) => format!("{} ({sha})", build.version),
+                            None => build.version,
+                        })
+                        .unwrap_or_else(|| "?".to_string())
+                ]);
+            }
+            Err(err) => {
+                log::info!("failed to get status; peer={addr}; err={err}");
+                table.add_row(row!["?", addr, "unreachable".red()]);
+            }
+        }
+    }
+    println!("{table}");
+
+    Ok(())
+}
+
+/// Print this CLI's version and the build of the agent on the given node, or on any node.
+async fn version(node: Option<String>) -> Result<()> {
+    println!("pounce {}", env!("CARGO_PKG_VERSION"));
+
+    let client = match node {
+        Some(instance_id) => {
+            let peers = utils::mesh::discover_all(None, Duration::from_secs(3)).await;
+            let Some(addr) = peers
+                .iter()
+                .find(|peer| peer.instance_id() == instance_id)
+                .and_then(|peer| peer.http_address())
+            else {
+                return Err(anyhow!("no node with instance id {instance_id} answered"));
+            };
+            serval_client::ServalApiClient::new(addr.to_string())
+        }
+        None => api_client().await,
+    };
+    let build = client.monitor_version().await?;
+    println!(
+        "agent {}{} built {}",
+        build.version,
+        build
+            .git_sha
+            .map(|sha| format!(" ({sha})"))
+            .unwrap_or_default(),
+        build.built_at
+    );
+    Ok(())
+}
+
 /// Ping whichever node we've discovered.
 async fn ping() -> Result<()> {
     let body = api_client().await.ping().await?;
@@ -242,6 +1005,15 @@ async fn ping() -> Result<()> {
     Ok(())
 }
 
+/// Print an error response from a node the same way wherever it turns up.
+fn print_api_error(status: u16, code: &str, message: &str) {
+    eprintln!(
+        "{} {status} {}: {message}",
+        "error:".red().bold(),
+        code.bold()
+    );
+}
+
 /// Parse command-line arguments and act.
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -255,26 +1027,112 @@ async fn main() -> Result<()> {
         .colors(true)
         .init()
         .unwrap();
+    config::init(args.config.as_deref())?;
 
-    match args.cmd {
-        Command::Store { manifest } => upload_manifest(manifest).await?,
+    let result = match args.cmd {
+        Command::Store {
+            manifest,
+            binary,
+            format,
+        } => upload_manifest(manifest, binary, format).await,
+        Command::InitManifest { binary, namespace } => scaffold::init_manifest(binary, namespace),
         Command::Run {
             name,
             input_file,
             output_file,
+            callback,
+            overrides,
+            timeout,
+            skip_output_schema,
+            output_format,
+            labels,
+            wasm,
+            repeat,
+            parallel,
         } => {
             // If people provide - as the filename, interpret that as stdin/stdout
             let input_file = input_file.filter(|p| p != &PathBuf::from("-"));
             let output_file = output_file.filter(|p| p != &PathBuf::from("-"));
-            run(name, input_file, output_file).await?;
+            match (repeat, wasm) {
+                (Some(times), _) => match read_file_or_stdin(input_file) {
+                    Ok(input) => repeat::repeat(name, input, times, parallel).await,
+                    Err(e) => Err(e),
+                },
+                (None, Some(wasm)) => {
+                    run_adhoc(name, wasm, input_file, output_file, output_format).await
+                }
+                (None, None) => {
+                    let parsed = (parse_overrides(&overrides, timeout), parse_labels(&labels));
+                    match parsed {
+                        (Ok(overrides), Ok(labels)) => {
+                            let options = RunOptions {
+                                callback_url: callback,
+                                overrides,
+                                skip_output_schema,
+                                labels,
+                            };
+                            run(name, input_file, output_file, options, output_format).await
+                        }
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                    }
+                }
+            }
         }
-        Command::NodeStatus => monitor_status().await?,
-        Command::Ping => ping().await?,
-        Command::Monitor => mesh::monitor_mesh().await?,
-        Command::Manifest { name } => get_manifest(name).await?,
-        Command::Peers => list_peers().await?,
-        Command::PeersWithRole { role } => peers_with_role(role).await?,
+        Command::NodeStatus => monitor_status().await,
+        Command::Nodes => list_nodes().await,
+        Command::Ping => ping().await,
+        Command::Version { node } => version(node).await,
+        Command::Doctor => doctor::doctor().await,
+        Command::Monitor => mesh::monitor_mesh().await,
+        Command::Running { all, label, output } => list_running(all, label, output).await,
+        Command::Cancel { id } => cancel(id).await,
+        Command::Manifest { name } => get_manifest(name).await,
+        Command::List { tag, search } => list_manifests(tag, search).await,
+        Command::Versions { name } => list_versions(name).await,
+        Command::Diff { name, from, to } => diff_versions(name, from, to).await,
+        Command::Tag { name, tag, version } => tag_version(name, tag, version).await,
+        Command::Export { file } => export_storage(file).await,
+        Command::Import { file } => import_storage(file).await,
+        Command::Peers => list_peers().await,
+        Command::PeersWithRole { role } => peers_with_role(role).await,
     };
 
-    Ok(())
+    // Errors from a node carry a code worth showing; everything else is reported as usual.
+    if let Err(err) = &result {
+        if let Some(ServalError::ApiFailure {
+            status,
+            code,
+            message,
+        }) = err.downcast_ref::<ServalError>()
+        {
+            print_api_error(*status, code, message);
+            std::process::exit(1);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_from_stdin_name_their_binary_absolutely() {
+        let dir = std::env::temp_dir().join(format!("pounce-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hello.wasm"), b"\0asm").unwrap();
+        let mut manifest: Manifest = toml::from_str(
+            "name = \"hello\"\nnamespace = \"sh.serval\"\nversion = \"1.0.0\"\n\
+             binary = \"hello.wasm\"\ndescription = \"\"\n",
+        )
+        .unwrap();
+
+        let wasmpath = resolve_binary(&mut manifest, &dir, None).unwrap();
+        assert_eq!(wasmpath, dir.join("hello.wasm"));
+        assert!(manifest.binary().is_absolute());
+        // This is the check a storage node makes on the manifest it's sent.
+        assert!(Manifest::from_string(&toml::to_string(&manifest).unwrap()).is_ok());
+        assert!(resolve_binary(&mut manifest, &dir, Some(dir.join("missing.wasm"))).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -4,7 +4,7 @@ use std::time::Duration;
 
 use owo_colors::OwoColorize;
 use tokio::time::sleep;
-use utils::mesh::{KaboodlePeer, PeerMetadata};
+use utils::mesh::{is_trusted_identity, KaboodlePeer, PeerMetadata};
 
 pub async fn monitor_mesh() -> anyhow::Result<()> {
     println!(
@@ -22,7 +22,21 @@ pub async fn monitor_mesh() -> anyhow::Result<()> {
 
     loop {
         while let Ok((addr, identity)) = discover_rx.try_recv() {
-            let peer = PeerMetadata::from_identity(addr.ip(), identity.to_vec());
+            let Some(peer) = PeerMetadata::from_identity(addr.ip(), identity.to_vec()) else {
+                println!("⚠️  ignoring a peer @ {addr} whose identity we can't read");
+                continue;
+            };
+            if !mesh.shares_namespace(&peer) {
+                continue;
+            }
+            if !is_trusted_identity(addr.ip(), &identity) {
+                println!(
+                    "⚠️  {} {} @ {addr} is not signed with this mesh's MESH_SECRET; ignored by its peers",
+                    "UNTRUSTED:".yellow(),
+                    peer.instance_id()
+                );
+                continue;
+            }
             print!("✅ {} {} @ {addr}", "JOINED:".blue(), peer.instance_id(),);
             if !peer.roles().is_empty() {
                 print!(
@@ -1,28 +1,71 @@
-// Finding a peer at most once, so we can build urls.
+// Finding a peer once and sticking with it, so we can build urls, until it stops answering.
 
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::Mutex;
 
-use anyhow::Result;
-use async_once_cell::OnceCell;
+use anyhow::{anyhow, Result};
 use serval_client::ServalApiClient;
+use utils::errors::ServalError;
 use utils::mesh::{KaboodleMesh, PeerMetadata, ServalMesh, ServalRole};
 
-static SERVAL_NODE_ADDR: OnceCell<SocketAddr> = async_once_cell::OnceCell::new();
+use crate::config::config;
+
+/// How many times to look for another node, when the config file doesn't say.
+const DEFAULT_REDISCOVERY_ATTEMPTS: u32 = 3;
+
+static SERVAL_NODE_ADDR: Mutex<Option<SocketAddr>> = Mutex::new(None);
 
 async fn peer_http_addr() -> SocketAddr {
-    *SERVAL_NODE_ADDR
-        .get_or_init(async {
-            maybe_find_peer("SERVAL_NODE_URL")
-                .await
-                .expect("unable to find any mesh peers!")
-        })
+    let cached = *SERVAL_NODE_ADDR.lock().unwrap();
+    if let Some(addr) = cached {
+        return addr;
+    }
+    let addr = maybe_find_peer()
         .await
+        .expect("unable to find any mesh peers!");
+    *SERVAL_NODE_ADDR.lock().unwrap() = Some(addr);
+    addr
+}
+
+/// Forget the node we found, so that the next request looks for one again.
+fn forget_peer() {
+    SERVAL_NODE_ADDR.lock().unwrap().take();
+}
+
+/// Make a request of the node we found. If it can't be reached, look for another node and try
+/// again there, up to `rediscovery_attempts` times. Only use this for requests that are safe to
+/// send twice; a request that never connected never reached a node, but one that timed out might
+/// have.
+pub async fn with_rediscovery<T, F, Fut>(mut request: F) -> Result<T, ServalError>
+where
+    F: FnMut(ServalApiClient) -> Fut,
+    Fut: Future<Output = Result<T, ServalError>>,
+{
+    let attempts = config()
+        .rediscovery_attempts
+        .unwrap_or(DEFAULT_REDISCOVERY_ATTEMPTS);
+    let mut attempt = 0;
+    loop {
+        match request(api_client().await).await {
+            Err(ServalError::ReqwestError(err)) if err.is_connect() && attempt < attempts => {
+                attempt += 1;
+                log::info!(
+                    "node stopped answering; looking for another; attempt={attempt}; err={err}"
+                );
+                forget_peer();
+            }
+            result => return result,
+        }
+    }
 }
 
 pub async fn api_client() -> ServalApiClient {
     let addr = peer_http_addr().await;
 
-    ServalApiClient::new_with_version(1, addr.to_string())
+    // Set a tenant to work within one tenant's namespace on a shared mesh.
+    let tenant = config().tenant.as_deref();
+    ServalApiClient::new_with_version(1, addr.to_string()).with_tenant(tenant)
 }
 
 async fn discover_peer() -> Result<PeerMetadata> {
@@ -46,19 +89,31 @@ pub async fn create_mesh_peer() -> Result<ServalMesh> {
     Ok(mesh)
 }
 
-async fn maybe_find_peer(override_var: &str) -> Result<SocketAddr> {
-    if let Some(override_addr) = std::env::var(override_var)
-        .ok()
+async fn maybe_find_peer() -> Result<SocketAddr> {
+    if let Some(override_addr) = config()
+        .node_url
+        .as_ref()
         .and_then(|override_url| override_url.parse::<SocketAddr>().ok())
     {
         return Ok(override_addr);
     }
 
     log::info!("Looking for any node on the peer network...");
-    loop {
-        let peer = discover_peer().await?; // todo: perhaps discover_peer() should not return Observers?
-        if let Some(addr) = peer.http_address() {
-            return Ok(addr);
+    // Discovery returns as soon as a peer answers, so this only bounds how long we wait for nobody.
+    let timeout = utils::mesh::discovery_timeout();
+    let found = tokio::time::timeout(timeout, async {
+        loop {
+            let peer = discover_peer().await?; // todo: perhaps discover_peer() should not return Observers?
+            if let Some(addr) = peer.http_address() {
+                return Ok(addr);
+            }
         }
-    }
+    })
+    .await;
+    found.unwrap_or_else(|_| {
+        Err(anyhow!(
+            "no serval nodes answered within {}s; is the mesh up? (set DISCOVERY_TIMEOUT to wait longer)",
+            timeout.as_secs_f64()
+        ))
+    })
 }
@@ -0,0 +1,105 @@
+// Running one job many times over and reporting whether it behaved the same way every time.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use owo_colors::OwoColorize;
+use serval_client::RunOptions;
+use ssri::Integrity;
+use tokio::task::JoinSet;
+use utils::structs::api::{
+    ErrorResponse, JobFailureResponse, OUTPUT_LOCATION_HEADER, STORAGE_POINTER_SCHEME,
+};
+
+use crate::peers::with_rediscovery;
+
+/// How one run went: the integrity checksum of its output, or why it failed.
+type Outcome = Result<String, String>;
+
+/// Run a job once, reducing whatever came back to an outcome we can compare with other runs.
+async fn run_once(name: String, input: Vec<u8>) -> Outcome {
+    let response = with_rediscovery(|serval| {
+        let (name, input) = (name.clone(), input.clone());
+        async move { serval.run_job(&name, input, &RunOptions::default()).await }
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let status = response.status();
+
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        if let Ok(JobFailureResponse { failure, .. }) = serde_json::from_str(&body) {
+            return Err(format!("{status} {}", failure.kind));
+        }
+        if let Ok(ErrorResponse { error }) = serde_json::from_str(&body) {
+            return Err(format!("{status} {}", error.code));
+        }
+        return Err(status.to_string());
+    }
+
+    // Output left in the blob store is already named by its checksum.
+    let stored_at = response
+        .headers()
+        .get(OUTPUT_LOCATION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix(STORAGE_POINTER_SCHEME))
+        .map(str::to_string);
+    if let Some(integrity) = stored_at {
+        return Ok(integrity);
+    }
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    Ok(Integrity::from(&body).to_string())
+}
+
+/// Run the named job `times` times on the same input, one after another or all at once, and
+/// summarize the results. Fails if any run failed or if the runs did not all give the same output.
+pub async fn repeat(name: String, input: Vec<u8>, times: u32, parallel: bool) -> Result<()> {
+    println!(
+        "Running job {} {times} times{}...",
+        name.blue().bold(),
+        if parallel { ", all at once" } else { "" }
+    );
+
+    let mut outcomes = Vec::with_capacity(times as usize);
+    if parallel {
+        let mut runs = JoinSet::new();
+        for _ in 0..times {
+            runs.spawn(run_once(name.clone(), input.clone()));
+        }
+        while let Some(outcome) = runs.join_next().await {
+            outcomes.push(outcome.unwrap_or_else(|e| Err(e.to_string())));
+        }
+    } else {
+        for _ in 0..times {
+            outcomes.push(run_once(name.clone(), input.clone()).await);
+        }
+    }
+
+    let mut outputs: BTreeMap<String, usize> = BTreeMap::new();
+    let mut failures: BTreeMap<String, usize> = BTreeMap::new();
+    for outcome in outcomes {
+        match outcome {
+            Ok(integrity) => *outputs.entry(integrity).or_default() += 1,
+            Err(reason) => *failures.entry(reason).or_default() += 1,
+        }
+    }
+
+    let succeeded: usize = outputs.values().sum();
+    let failed: usize = failures.values().sum();
+    println!("{} succeeded, {} failed", succeeded.green(), failed.red());
+    for (reason, count) in &failures {
+        println!("  {count} x {reason}");
+    }
+    println!("{} distinct output(s)", outputs.len().bold());
+    for (integrity, count) in &outputs {
+        println!("  {count} x {integrity}");
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {times} runs failed"));
+    }
+    if outputs.len() > 1 {
+        return Err(anyhow!("runs gave {} different outputs", outputs.len()));
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+// Writing a manifest skeleton for a Wasm module, by looking at what the module imports and exports.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use owo_colors::OwoColorize;
+use wasmparser::{ExternalKind, Parser, Payload};
+
+/// Import namespaces that every runner provides, so they need no extension or permission.
+const HOST_NAMESPACES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable", "serval"];
+
+/// The exports a runner will call to start a job, in the order it looks for them.
+const ENTRYPOINTS: &[&str] = &["", "_start"];
+
+/// What a module tells us about itself.
+#[derive(Debug, Default)]
+struct ModuleInfo {
+    /// Every function the module imports, as `(namespace, name)`.
+    imports: Vec<(String, String)>,
+    /// The export a runner will call, if the module has one.
+    entrypoint: Option<String>,
+}
+
+fn inspect(bytes: &[u8]) -> Result<ModuleInfo> {
+    let mut info = ModuleInfo::default();
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    info.imports
+                        .push((import.module.to_string(), import.name.to_string()));
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func
+                        && ENTRYPOINTS.contains(&export.name)
+                        && info.entrypoint.is_none()
+                    {
+                        info.entrypoint = Some(export.name.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// Manifest names may only contain letters and underscores, so squash anything else.
+fn job_name(binary: &Path) -> String {
+    let stem = binary
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    stem.chars()
+        .map(|c| if c.is_ascii_alphabetic() { c } else { '_' })
+        .collect()
+}
+
+/// Render the text of a manifest for the given module. Imports are listed as comments so that the
+/// author can see what the module expects of its host; imports from outside the namespaces every
+/// runner provides are assumed to be extensions and filled in as requirements.
+fn render(binary: &Path, namespace: &str, info: &ModuleInfo) -> String {
+    let file_name = binary
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extensions: BTreeSet<&str> = info
+        .imports
+        .iter()
+        .map(|(module, _)| module.as_str())
+        .filter(|module| !HOST_NAMESPACES.contains(module))
+        .collect();
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        "# Generated by `pounce init-manifest` from {file_name}.\n"
+    ));
+    out.push_str(&format!("name = \"{}\"\n", job_name(binary)));
+    out.push_str(&format!("namespace = \"{namespace}\"\n"));
+    out.push_str("version = \"0.1.0\"\n");
+    out.push_str(&format!("binary = \"{file_name}\"\n"));
+    out.push_str("description = \"\"\n");
+    out.push('\n');
+    match &info.entrypoint {
+        Some(name) if name.is_empty() => {
+            out.push_str("# Entrypoint: the module's default export\n")
+        }
+        Some(name) => out.push_str(&format!("# Entrypoint: {name}\n")),
+        None => out.push_str("# Entrypoint: none found! Runners call `_start`; export it.\n"),
+    }
+    if info.imports.is_empty() {
+        out.push_str("# Host imports: none\n");
+    } else {
+        out.push_str("# Host imports:\n");
+        for (module, name) in &info.imports {
+            out.push_str(&format!("#   {module}::{name}\n"));
+        }
+    }
+    out.push('\n');
+
+    let quoted = |items: Vec<String>| {
+        items
+            .iter()
+            .map(|item| format!("\"{item}\""))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let extension_names: Vec<String> = extensions.iter().map(|ext| ext.to_string()).collect();
+    let permissions: Vec<String> = extensions
+        .iter()
+        .map(|ext| format!("extension:{ext}"))
+        .collect();
+    out.push_str(&format!(
+        "required_extensions = [{}]\n",
+        quoted(extension_names)
+    ));
+    out.push_str(&format!(
+        "required_permissions = [{}]\n",
+        quoted(permissions)
+    ));
+    out
+}
+
+/// Inspect a Wasm module and write a manifest skeleton for it next to the module.
+pub fn init_manifest(binary: PathBuf, namespace: String) -> Result<()> {
+    let bytes = std::fs::read(&binary)?;
+    let info = inspect(&bytes)?;
+
+    let manifest_path = binary.with_file_name(format!("{}.toml", job_name(&binary)));
+    if manifest_path.exists() {
+        return Err(anyhow!(
+            "{} already exists; not overwriting it",
+            manifest_path.display()
+        ));
+    }
+    std::fs::write(&manifest_path, render(&binary, &namespace, &info))?;
+
+    println!("Wrote {}", manifest_path.display().bold());
+    if info.entrypoint.is_none() {
+        println!(
+            "{}",
+            "Warning: this module exports no `_start` function, so runners can't run it.".yellow()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::structs::Manifest;
+
+    use super::*;
+
+    #[test]
+    fn renders_a_valid_manifest() {
+        let module = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+                (import "birdfacts" "goose" (func (result i32)))
+                (func (export "_start")))"#,
+        )
+        .unwrap();
+        let info = inspect(&module).unwrap();
+        assert_eq!(info.entrypoint.as_deref(), Some("_start"));
+
+        let text = render(Path::new("/tmp/bird-facts.wasm"), "sh.serval", &info);
+        assert!(text.contains("#   birdfacts::goose"));
+
+        let manifest: Manifest = toml::from_str(&text).unwrap();
+        assert_eq!(manifest.fq_name(), "sh.serval.bird_facts");
+        let permissions: Vec<String> = manifest
+            .required_permissions()
+            .iter()
+            .map(|permission| permission.to_string())
+            .collect();
+        assert_eq!(permissions, vec!["extension:birdfacts"]);
+    }
+}
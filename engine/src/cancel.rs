@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use wasmtime::Engine;
+
+/// Stops a job that an engine is running, from any thread. The engine interrupts its guest at the
+/// next epoch check, which wasmtime places in every loop and function prologue, so even a guest
+/// spinning in a tight loop stops promptly.
+#[derive(Clone)]
+pub struct CancelHandle {
+    engine: Engine,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub(crate) fn new(engine: Engine, cancelled: Arc<AtomicBool>) -> Self {
+        Self { engine, cancelled }
+    }
+
+    /// Interrupt whatever the engine is running. Cancelling twice is harmless.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.engine.increment_epoch();
+    }
+
+    /// True once `cancel()` has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl std::fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Interrupts a run that goes on too long. The deadline stands down when it is dropped, so a run
+/// that finishes in time is left alone.
+pub(crate) struct Deadline {
+    expired: Arc<AtomicBool>,
+    _stand_down: Sender<()>,
+}
+
+impl Deadline {
+    /// Start the clock on a run by the given engine.
+    pub(crate) fn start(engine: Engine, limit: Duration) -> Self {
+        let expired = Arc::new(AtomicBool::new(false));
+        let (stand_down, wait) = mpsc::channel::<()>();
+        let flag = expired.clone();
+        std::thread::spawn(move || {
+            // Dropping the sender wakes us early with a disconnect; only a timeout means expiry.
+            if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(limit) {
+                flag.store(true, Ordering::SeqCst);
+                engine.increment_epoch();
+            }
+        });
+        Self {
+            expired,
+            _stand_down: stand_down,
+        }
+    }
+
+    /// True if the run was interrupted for taking too long.
+    pub(crate) fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,101 @@
+// Stand-ins for the WASI clock and randomness imports, for jobs that must give the same output on
+// every run. They replace wasmtime_wasi's own definitions in a run's linker.
+
+use wasmtime::{Caller, Linker};
+
+use crate::runtime::helpers::get_memory_from_caller;
+use crate::JobState;
+
+/// The wall-clock time that every deterministic run starts at: 2000-01-01T00:00:00Z, in
+/// nanoseconds since the Unix epoch.
+const PINNED_REALTIME_NANOS: u64 = 946_684_800_000_000_000;
+
+/// How far every clock moves each time a deterministic run reads one. The clocks do move, so that
+/// a job waiting for time to pass isn't stuck forever, but by the same amount on every run.
+const TICK_NANOS: u64 = 1_000_000;
+
+/// The seed for every deterministic run's random bytes.
+const SEED: u64 = 0x5345_5256_414c_2121;
+
+// The WASI errno values we answer with.
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_INVAL: i32 = 28;
+
+/// The clocks and random number generator of one deterministic run.
+#[derive(Debug)]
+pub struct Determinism {
+    elapsed_nanos: u64,
+    rng: u64,
+}
+
+impl Default for Determinism {
+    fn default() -> Self {
+        Determinism {
+            elapsed_nanos: 0,
+            rng: SEED,
+        }
+    }
+}
+
+impl Determinism {
+    /// Read the given WASI clock: 0 is the wall clock, and the rest count up from zero.
+    fn now(&mut self, clock_id: u32) -> Option<u64> {
+        self.elapsed_nanos += TICK_NANOS;
+        match clock_id {
+            0 => Some(PINNED_REALTIME_NANOS + self.elapsed_nanos),
+            1..=3 => Some(self.elapsed_nanos),
+            _ => None,
+        }
+    }
+
+    /// Fill the buffer from a splitmix64 generator. Not for cryptography, which a job asking for
+    /// determinism has given up on anyway.
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.rng;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/// Replace WASI's clock and randomness functions in the given linker with deterministic ones.
+/// Call this after the WASI functions are added and before any module is instantiated.
+pub fn shadow_wasi(linker: &mut Linker<JobState>) -> anyhow::Result<()> {
+    linker.allow_shadowing(true);
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "clock_time_get",
+        |mut caller: Caller<'_, JobState>, clock_id: u32, _precision: u64, time_ptr: u32| -> i32 {
+            let Some(now) = caller.data_mut().determinism.now(clock_id) else {
+                return ERRNO_INVAL;
+            };
+            write(&mut caller, time_ptr, &now.to_le_bytes())
+        },
+    )?;
+    linker.func_wrap(
+        "wasi_snapshot_preview1",
+        "random_get",
+        |mut caller: Caller<'_, JobState>, buf_ptr: u32, buf_len: u32| -> i32 {
+            let mut buf = vec![0u8; buf_len as usize];
+            caller.data_mut().determinism.fill(&mut buf);
+            write(&mut caller, buf_ptr, &buf)
+        },
+    )?;
+    linker.allow_shadowing(false);
+    Ok(())
+}
+
+fn write(caller: &mut Caller<'_, JobState>, ptr: u32, bytes: &[u8]) -> i32 {
+    let Ok(memory) = get_memory_from_caller(caller) else {
+        return ERRNO_FAULT;
+    };
+    match memory.write(caller, ptr as usize, bytes) {
+        Ok(()) => ERRNO_SUCCESS,
+        Err(_) => ERRNO_FAULT,
+    }
+}
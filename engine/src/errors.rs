@@ -1,8 +1,24 @@
+use std::time::Duration;
+
 use thiserror::Error;
-use wasmtime::MemoryAccessError;
+use utils::structs::{FailureKind, JobFailure, WasmFeature};
+use wasmtime::{MemoryAccessError, Trap, WasmBacktrace};
 
 #[derive(Error, Debug)]
 pub enum ServalEngineError {
+    #[error("Job was cancelled")]
+    Cancelled { stdout: Vec<u8>, stderr: Vec<u8> },
+
+    #[error("Job ran longer than its limit of {}ms", limit.as_millis())]
+    TimedOut {
+        limit: Duration,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+
+    #[error("Wasm components are not yet supported; please submit a core module")]
+    ComponentNotSupported,
+
     #[error("Failed to get the default export of the binary")]
     DefaultExportUnavailable,
 
@@ -28,9 +44,29 @@ pub enum ServalEngineError {
     #[error("Reading or writing from guest memory failed")]
     InteropMemoryAccessError(MemoryAccessError),
 
+    #[error("Input rejected by preprocessor '{preprocessor}': {reason}")]
+    InputRejected {
+        preprocessor: String,
+        reason: String,
+    },
+
     #[error("std::io::Error: {0}")]
     IoError(#[from] std::io::Error),
 
+    #[error("Pipeline stage '{stage}' failed")]
+    PipelineStageFailed {
+        stage: String,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+        error: anyhow::Error,
+    },
+
+    #[error("Pipeline stage '{0}' is not an exported function taking and returning nothing")]
+    PipelineStageUnavailable(String),
+
+    #[error("Job exceeded its memory limit of {0} bytes")]
+    MemoryLimitExceeded(usize),
+
     #[error("Failed to load Wasm module")]
     ModuleLoadError(anyhow::Error),
 
@@ -40,9 +76,77 @@ pub enum ServalEngineError {
     #[error("Error reading bytes from stdout pipe")]
     StandardOutputReadError(),
 
+    #[error("Extension '{0}' is not available on this node")]
+    ExtensionUnavailable(String),
+
+    #[error("Engine self-test failed: {0}")]
+    SelfTestFailed(String),
+
+    #[error("Job needs the shared dataset, but this node has none")]
+    SharedDataUnavailable,
+
     #[error("Host platform does not support a required feature")]
     UnsupportedFeatureError,
 
+    #[error("Job needs the Wasm feature '{0}', which this node does not support")]
+    UnsupportedWasmFeature(WasmFeature),
+
     #[error("Job does not have permission to use extension '{0}'")]
     ExtensionPermissionDenied(String),
 }
+
+impl From<&ServalEngineError> for JobFailure {
+    fn from(err: &ServalEngineError) -> Self {
+        match err {
+            ServalEngineError::ExecutionError { error, .. } => execution_failure(error),
+            ServalEngineError::PipelineStageFailed { stage, error, .. } => {
+                let mut failure = execution_failure(error);
+                failure.message = format!("stage {stage}: {}", failure.message);
+                failure
+            }
+            ServalEngineError::DefaultExportUnavailable
+            | ServalEngineError::InvalidDefaultExportFunctionSignature
+            | ServalEngineError::InputRejected { .. }
+            | ServalEngineError::PipelineStageUnavailable(_)
+            | ServalEngineError::ModuleLoadError(_) => {
+                JobFailure::new(FailureKind::BadInput, err.to_string())
+            }
+            ServalEngineError::Cancelled { .. } => {
+                JobFailure::new(FailureKind::Cancelled, err.to_string())
+            }
+            ServalEngineError::TimedOut { .. } => {
+                JobFailure::new(FailureKind::Timeout, err.to_string())
+            }
+            ServalEngineError::MemoryLimitExceeded(_) => {
+                JobFailure::new(FailureKind::LimitExceeded, err.to_string())
+            }
+            ServalEngineError::ComponentNotSupported
+            | ServalEngineError::ExtensionPermissionDenied(_)
+            | ServalEngineError::ExtensionUnavailable(_)
+            | ServalEngineError::SharedDataUnavailable
+            | ServalEngineError::UnsupportedFeatureError
+            | ServalEngineError::UnsupportedWasmFeature(_) => {
+                JobFailure::new(FailureKind::MissingCapability, err.to_string())
+            }
+            _ => JobFailure::new(FailureKind::Internal, err.to_string()),
+        }
+    }
+}
+
+// Describe an error raised while running guest code.
+fn execution_failure(error: &anyhow::Error) -> JobFailure {
+    // Wasmtime hands us the trap code as the root cause of the error, with a backtrace layered on
+    // as context. The message is the former; both go in the details.
+    let trap = error.downcast_ref::<Trap>();
+    let kind = match trap {
+        Some(Trap::Interrupt) | Some(Trap::OutOfFuel) => FailureKind::Timeout,
+        _ => FailureKind::EngineTrap,
+    };
+    let mut failure = JobFailure::new(kind, error.root_cause().to_string());
+    failure.trap = trap.map(|trap| format!("{trap:?}"));
+    failure.backtrace = error
+        .downcast_ref::<WasmBacktrace>()
+        .filter(|backtrace| !backtrace.frames().is_empty())
+        .map(|backtrace| backtrace.to_string());
+    failure
+}
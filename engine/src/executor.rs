@@ -0,0 +1,58 @@
+// The interface a runner uses to run a job, whatever kind of executable the job has.
+
+use utils::structs::{Permission, Runtime, WasmFeature, WasmResult};
+
+use crate::errors::ServalEngineError;
+use crate::{CancelHandle, ServalEngine};
+
+/// The runtimes this build has an executor for.
+pub const SUPPORTED_RUNTIMES: &[Runtime] = &[Runtime::Wasm];
+
+/// The Wasm features `ServalEngine` can turn on for a job. Threads are left out: jobs get no way
+/// to spawn them, so a module that needs them could never run as compiled.
+pub const SUPPORTED_WASM_FEATURES: &[WasmFeature] = &[
+    WasmFeature::Simd,
+    WasmFeature::RelaxedSimd,
+    WasmFeature::BulkMemory,
+    WasmFeature::ReferenceTypes,
+    WasmFeature::MultiValue,
+    WasmFeature::MultiMemory,
+    WasmFeature::Memory64,
+];
+
+/// Something that can run a job's executable on its input. Runners pick one by the runtime named
+/// in the job's manifest; `ServalEngine` is the executor for Wasm.
+pub trait Executor {
+    /// The runtime whose executables this executor runs.
+    fn runtime(&self) -> Runtime;
+
+    /// Run the executable on the given input, with the given elevated permissions.
+    fn execute(
+        &mut self,
+        executable: &[u8],
+        input: &[u8],
+        permissions: &[Permission],
+    ) -> Result<WasmResult, ServalEngineError>;
+
+    /// Get a handle that interrupts whatever this executor is running.
+    fn cancel_handle(&self) -> CancelHandle;
+}
+
+impl Executor for ServalEngine {
+    fn runtime(&self) -> Runtime {
+        Runtime::Wasm
+    }
+
+    fn execute(
+        &mut self,
+        executable: &[u8],
+        input: &[u8],
+        permissions: &[Permission],
+    ) -> Result<WasmResult, ServalEngineError> {
+        ServalEngine::execute(self, executable, input, permissions)
+    }
+
+    fn cancel_handle(&self) -> CancelHandle {
+        ServalEngine::cancel_handle(self)
+    }
+}
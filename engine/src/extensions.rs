@@ -25,9 +25,14 @@ impl ServalExtension {
     }
 
     pub fn module_for_engine(&self, engine: &Engine) -> Result<Module, ServalEngineError> {
-        let bytes = &fs::read(&self.filename)?[..];
+        let bytes = &self.bytes()?[..];
         Module::from_binary(engine, bytes).map_err(ServalEngineError::ModuleLoadError)
     }
+
+    /// Read the extension's Wasm from disk.
+    pub fn bytes(&self) -> Result<Vec<u8>, ServalEngineError> {
+        Ok(fs::read(&self.filename)?)
+    }
 }
 
 pub fn load_extensions(path: &PathBuf) -> Result<HashMap<String, ServalExtension>, ServalError> {
@@ -9,47 +9,157 @@
 
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::io::Cursor;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use cranelift_codegen_meta::isa::Isa;
 use extensions::ServalExtension;
-use utils::structs::{Permission, WasmResult};
+use utils::structs::{Permission, WasmFeature, WasmResult};
 use wasi_common::pipe::{ReadPipe, WritePipe};
 use wasi_common::I32Exit;
-use wasmtime::{Config, Engine, Linker, Module, Store};
+use wasmtime::{Config, Engine, Linker, Module, Store, TypedFunc, WasmBacktraceDetails};
 use wasmtime_wasi::{Dir, WasiCtx, WasiCtxBuilder};
 
+mod cancel;
+mod determinism;
 pub mod errors;
+pub mod executor;
 pub mod extensions;
+mod limits;
+pub mod module_cache;
 mod runtime;
+mod selftest;
 
+pub use crate::cancel::CancelHandle;
+use crate::cancel::Deadline;
+use crate::determinism::{shadow_wasi, Determinism};
 use crate::errors::ServalEngineError;
+use crate::limits::JobLimiter;
+use crate::module_cache::ModuleCache;
 use crate::runtime::register_exports;
 
+/// The two flavors of Wasm binary we might be handed. They share a magic number, and are told
+/// apart by the layer field that follows the version in the binary preamble.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BinaryKind {
+    /// A core WebAssembly module.
+    Module,
+    /// A WebAssembly component, as defined by the component model proposal.
+    Component,
+}
+
+impl BinaryKind {
+    /// Inspect the preamble of the given bytes; returns None if they are not Wasm at all.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 || !bytes.starts_with(b"\0asm") {
+            return None;
+        }
+        match u16::from_le_bytes([bytes[6], bytes[7]]) {
+            0 => Some(BinaryKind::Module),
+            1 => Some(BinaryKind::Component),
+            _ => None,
+        }
+    }
+}
+
+/// Where jobs find the node's shared dataset in their WASI filesystem.
+pub const SHARED_DATA_MOUNT: &str = "/shared";
+
+/// Everything a job's store carries: the job's WASI context plus the limits we hold it to.
+struct JobState {
+    wasi: WasiCtx,
+    limiter: JobLimiter,
+    determinism: Determinism,
+}
+
+/// Context attached to the error from a pipeline stage, naming the stage that failed.
+#[derive(Debug)]
+struct PipelineStage(String);
+
+impl std::fmt::Display for PipelineStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pipeline stage {} failed", self.0)
+    }
+}
+
 #[allow(missing_debug_implementations)]
 #[derive(Clone)]
 /// Make one of these to get a Wasm runner with the Serval glue.
 pub struct ServalEngine {
     extensions: HashMap<String, ServalExtension>,
     engine: Engine,
-    linker: Linker<WasiCtx>,
+    linker: Linker<JobState>,
+    max_memory_bytes: Option<usize>,
+    pipeline: Vec<String>,
+    deterministic: bool,
+    shared_data: Option<PathBuf>,
+    time_limit: Option<Duration>,
+    cancelled: Arc<AtomicBool>,
 }
 
 impl ServalEngine {
     /// Create a new serval engine.
     pub fn new(extensions: HashMap<String, ServalExtension>) -> Result<Self, ServalEngineError> {
+        Self::with_module_cache(extensions, None)
+    }
+
+    /// Create a new serval engine that keeps the modules it compiles in the given cache, and
+    /// loads them from there rather than compiling them again. Without one, wasmtime's default
+    /// cache settings for this user apply.
+    pub fn with_module_cache(
+        extensions: HashMap<String, ServalExtension>,
+        module_cache: Option<&ModuleCache>,
+    ) -> Result<Self, ServalEngineError> {
+        Self::with_wasm_features(extensions, module_cache, &[])
+    }
+
+    /// Create a new serval engine as `with_module_cache` does, with the given Wasm features turned
+    /// on as well as wasmtime's defaults. Fails if any of them is one we don't support; see
+    /// `executor::SUPPORTED_WASM_FEATURES`.
+    pub fn with_wasm_features(
+        extensions: HashMap<String, ServalExtension>,
+        module_cache: Option<&ModuleCache>,
+        wasm_features: &[WasmFeature],
+    ) -> Result<Self, ServalEngineError> {
         let mut config = Config::default();
-        config.cache_config_load_default().map_err(|_| {
-            ServalEngineError::EngineInitializationError(anyhow!(
-                "Failed to load default cache config"
-            ))
-        })?;
+        // Keep this in step with SUPPORTED_WASM_FEATURES.
+        for feature in wasm_features {
+            match feature {
+                WasmFeature::Simd => config.wasm_simd(true),
+                WasmFeature::RelaxedSimd => config.wasm_simd(true).wasm_relaxed_simd(true),
+                WasmFeature::BulkMemory => config.wasm_bulk_memory(true),
+                WasmFeature::ReferenceTypes => config.wasm_reference_types(true),
+                WasmFeature::MultiValue => config.wasm_multi_value(true),
+                WasmFeature::MultiMemory => config.wasm_multi_memory(true),
+                WasmFeature::Memory64 => config.wasm_memory64(true),
+                WasmFeature::Threads => {
+                    return Err(ServalEngineError::UnsupportedWasmFeature(*feature))
+                }
+            };
+        }
+        match module_cache {
+            Some(cache) => cache.configure(&mut config)?,
+            None => {
+                config.cache_config_load_default().map_err(|_| {
+                    ServalEngineError::EngineInitializationError(anyhow!(
+                        "Failed to load default cache config"
+                    ))
+                })?;
+            }
+        }
+        // Resolve trap backtraces to source files and lines for modules that carry debug info.
+        config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
+        // Lets a CancelHandle interrupt a running guest.
+        config.epoch_interruption(true);
         let engine = Engine::new(&config).map_err(|_| {
             ServalEngineError::EngineInitializationError(anyhow!("Failed to instantiate engine"))
         })?;
-        let mut linker: Linker<WasiCtx> = Linker::new(&engine);
-        wasmtime_wasi::add_to_linker(&mut linker, |s| s)
+        let mut linker: Linker<JobState> = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |s: &mut JobState| &mut s.wasi)
             .map_err(ServalEngineError::EngineInitializationError)?;
 
         // Wire up our host functions (functionality that we want to expose to the jobs we run)
@@ -61,10 +171,97 @@ impl ServalEngine {
             engine,
             linker,
             extensions,
+            max_memory_bytes: None,
+            pipeline: Vec::new(),
+            deterministic: false,
+            shared_data: None,
+            time_limit: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Cap the linear memory that each job run by this engine may grow to, in bytes. A job that
+    /// tries to grow past the cap fails with `MemoryLimitExceeded`. Pass None for no cap.
+    pub fn set_memory_limit(&mut self, max_memory_bytes: Option<usize>) {
+        self.max_memory_bytes = max_memory_bytes;
+    }
+
+    /// Run jobs as a pipeline of the named exports rather than through the module's default
+    /// export. The stages are called in order on a single instance of the module, each one reading
+    /// the previous stage's output as its stdin; the job's input goes to the first stage and the
+    /// last stage's output is the job's output. An empty list restores the default behavior.
+    pub fn set_pipeline(&mut self, stages: Vec<String>) {
+        self.pipeline = stages;
+    }
+
+    /// Run jobs against a pinned clock and seeded randomness. Every deterministic run of the same
+    /// module on the same input sees the same sequence of clock readings and random bytes, so a
+    /// job whose output depends only on those and its input gives identical output every time.
+    /// The wall clock starts at 2000-01-01T00:00:00Z and every clock advances a millisecond each
+    /// time it is read. Sleeping through `poll_oneoff` still takes real time. When off, jobs see
+    /// the host's real clocks and entropy.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Give jobs the given directory at `SHARED_DATA_MOUNT`, or nothing there if None. The same
+    /// directory is opened for every run, with no copy made, so the node should keep it read-only;
+    /// jobs are trusted not to write to it beyond what the host's permissions allow.
+    pub fn set_shared_data(&mut self, dir: Option<PathBuf>) {
+        self.shared_data = dir;
+    }
+
+    /// Interrupt each run by this engine that goes on longer than the given time, failing it with
+    /// `TimedOut` and whatever output it had produced by then. Pass None to let runs take as long
+    /// as they take.
+    pub fn set_time_limit(&mut self, limit: Option<Duration>) {
+        self.time_limit = limit;
+    }
+
+    /// Get a handle that interrupts whatever this engine is running. A job that is cancelled fails
+    /// with `Cancelled`, carrying whatever output it had produced by then. Once cancelled, an
+    /// engine stays cancelled; make a fresh one for the next job.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle::new(self.engine.clone(), self.cancelled.clone())
+    }
+
+    /// Run the named extension on a job's raw input, and return what it wrote to stdout as the
+    /// input the job should get. The extension runs as a WASI command with no elevated
+    /// permissions, and rejects the input by exiting nonzero; whatever it wrote to stderr is the
+    /// reason given in `InputRejected`.
+    pub fn preprocess(
+        &mut self,
+        extension: &str,
+        input: &[u8],
+    ) -> Result<Vec<u8>, ServalEngineError> {
+        let bytes = self
+            .extensions
+            .get(extension)
+            .ok_or_else(|| ServalEngineError::ExtensionUnavailable(extension.to_string()))?
+            .bytes()?;
+
+        // Preprocessors run their default export, whatever this engine does with jobs.
+        let pipeline = std::mem::take(&mut self.pipeline);
+        let result = self.execute(&bytes, input, &[]);
+        self.pipeline = pipeline;
+
+        let result = result?;
+        if result.code != 0 {
+            return Err(ServalEngineError::InputRejected {
+                preprocessor: extension.to_string(),
+                reason: String::from_utf8_lossy(&result.stderr).trim().to_string(),
+            });
+        }
+        Ok(result.stdout)
+    }
+
     /// Run the passed-in Wasm executable on the given input bytes.
+    ///
+    /// Every run is isolated from every other run on this engine, including earlier runs of the
+    /// same executable: each gets a fresh `Store` holding its own WASI context and limits, a fresh
+    /// instance of the module and of any extensions it imports, and its own copy of the linker to
+    /// instantiate them with. All of it is dropped when the run ends. Only compiled code and the
+    /// host functions registered in `new()` are shared.
     pub fn execute(
         &mut self,
         // WebAssembly module to execute
@@ -102,10 +299,38 @@ impl ServalEngine {
             wasi_builder = wasi_builder.preopened_dir(dir, path).unwrap();
         }
 
-        let mut store = Store::new(&self.engine, wasi_builder.build());
+        if let Some(path) = &self.shared_data {
+            let dir = Dir::from_std_file(File::open(path)?);
+            wasi_builder = wasi_builder
+                .preopened_dir(dir, SHARED_DATA_MOUNT)
+                .map_err(ServalEngineError::EngineInitializationError)?;
+        }
+
+        let state = JobState {
+            wasi: wasi_builder.build(),
+            limiter: JobLimiter::new(self.max_memory_bytes),
+            determinism: Determinism::default(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limiter);
+        // Instantiating modules defines their exports in the linker, and those definitions belong
+        // to this run's store; keep them out of the engine's own linker.
+        let mut linker = self.linker.clone();
+        if self.deterministic {
+            shadow_wasi(&mut linker).map_err(ServalEngineError::EngineInitializationError)?;
+        }
+        // Any tick of the epoch means a cancellation; trap on the very next one.
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_trap();
 
         log::info!("Module is {} bytes", wasm_module_bytes.len());
 
+        if BinaryKind::detect(wasm_module_bytes) == Some(BinaryKind::Component) {
+            // Components need to be instantiated against a WIT world rather than through our
+            // linear-memory ABI; until that lands, say so plainly instead of failing to parse.
+            return Err(ServalEngineError::ComponentNotSupported);
+        }
+
         let module = Module::from_binary(&self.engine, wasm_module_bytes)
             .map_err(ServalEngineError::ModuleLoadError)?;
 
@@ -143,28 +368,65 @@ impl ServalEngine {
 
             if let Err(err) = extension
                 .module_for_engine(&self.engine)
-                .map(|ext_module| self.linker.module(&mut store, &ext_name, &ext_module))
+                .map(|ext_module| linker.module(&mut store, &ext_name, &ext_module))
             {
                 log::warn!("Error when trying to load extension {ext_name}: {err}")
             };
         }
 
-        // Note: Any functions we want to expose to the module must be registered with the linker
-        // before the module itself, which we are about to do. I am leaving this note for future
-        // spelunkers: calling `linker.func_wrap(...)` etc. at any point after the following line
-        // will not work as you expect.
-        self.linker
-            .module(&mut store, "", &module)
-            .map_err(ServalEngineError::EngineInitializationError)?;
+        let cancel = self.cancel_handle();
+        let deadline = self
+            .time_limit
+            .map(|limit| (limit, Deadline::start(self.engine.clone(), limit)));
+        let executed = if self.pipeline.is_empty() {
+            // Note: Any functions we want to expose to the module must be registered with the
+            // linker before the module itself, which we are about to do. I am leaving this note for
+            // future spelunkers: calling `linker.func_wrap(...)` etc. at any point after the
+            // following line will not work as you expect.
+            if let Err(err) = linker.module(&mut store, "", &module) {
+                // A module whose initial memory is already over the cap fails right here.
+                if let Some(limit) = store.data().limiter.exceeded_memory() {
+                    return Err(ServalEngineError::MemoryLimitExceeded(limit));
+                }
+                return Err(ServalEngineError::EngineInitializationError(err));
+            }
 
-        let default_export = self
-            .linker
-            .get_default(&mut store, "")
-            .map_err(|_| ServalEngineError::DefaultExportUnavailable)?;
-        let default_func = default_export
-            .typed::<(), ()>(&store)
-            .map_err(|_| ServalEngineError::InvalidDefaultExportFunctionSignature)?;
-        let executed = default_func.call(&mut store, ());
+            let default_export = linker
+                .get_default(&mut store, "")
+                .map_err(|_| ServalEngineError::DefaultExportUnavailable)?;
+            let default_func = default_export
+                .typed::<(), ()>(&store)
+                .map_err(|_| ServalEngineError::InvalidDefaultExportFunctionSignature)?;
+            if cancel.is_cancelled() {
+                // Cancelled before we got going; the epoch tick has already come and gone.
+                Err(anyhow!("job was cancelled before it started"))
+            } else {
+                default_func.call(&mut store, ())
+            }
+        } else {
+            // A pipeline's stages share state, so they all run on one instance of the module.
+            let instance = match linker.instantiate(&mut store, &module) {
+                Ok(instance) => instance,
+                Err(err) => {
+                    if let Some(limit) = store.data().limiter.exceeded_memory() {
+                        return Err(ServalEngineError::MemoryLimitExceeded(limit));
+                    }
+                    return Err(ServalEngineError::EngineInitializationError(err));
+                }
+            };
+            let mut stages = Vec::with_capacity(self.pipeline.len());
+            for name in &self.pipeline {
+                let func = instance
+                    .get_typed_func::<(), ()>(&mut store, name)
+                    .map_err(|_| ServalEngineError::PipelineStageUnavailable(name.clone()))?;
+                stages.push((name.clone(), func));
+            }
+            run_pipeline(&mut store, &stages, stdin_bytes, &stdout, &cancel)
+        };
+        let timed_out = deadline
+            .filter(|(_, deadline)| deadline.expired())
+            .map(|(limit, _)| limit);
+        let exceeded_memory = store.data().limiter.exceeded_memory();
 
         // We have to drop the store here or we'll be unable to consume data from the WritePipe. See wasmtime docs.
         drop(store);
@@ -186,6 +448,27 @@ impl ServalEngine {
             Err(e) => {
                 if let Some(exit) = e.downcast_ref::<I32Exit>() {
                     exit.0
+                } else if let Some(limit) = timed_out {
+                    return Err(ServalEngineError::TimedOut {
+                        limit,
+                        stdout: outbytes,
+                        stderr: errbytes,
+                    });
+                } else if cancel.is_cancelled() {
+                    return Err(ServalEngineError::Cancelled {
+                        stdout: outbytes,
+                        stderr: errbytes,
+                    });
+                } else if let Some(limit) = exceeded_memory {
+                    // The job died after we refused to give it more memory; that's the real story.
+                    return Err(ServalEngineError::MemoryLimitExceeded(limit));
+                } else if let Some(PipelineStage(stage)) = e.downcast_ref::<PipelineStage>() {
+                    return Err(ServalEngineError::PipelineStageFailed {
+                        stage: stage.clone(),
+                        error: e,
+                        stdout: outbytes,
+                        stderr: errbytes,
+                    });
                 } else {
                     // This is a genuine error from the Wasm engine, not a non-zero exit code from the
                     // the Wasm executable.
@@ -216,9 +499,361 @@ impl ServalEngine {
     }
 }
 
+/// Call each stage of a pipeline in turn, handing each one the previous stage's output as its
+/// stdin. The last stage writes to the job's stdout. A stage that exits with status zero has simply
+/// finished; any other exit ends the pipeline, as does any trap.
+fn run_pipeline(
+    store: &mut Store<JobState>,
+    stages: &[(String, TypedFunc<(), ()>)],
+    input: &[u8],
+    stdout: &WritePipe<Cursor<Vec<u8>>>,
+    cancel: &CancelHandle,
+) -> anyhow::Result<()> {
+    let mut buffer = input.to_vec();
+    for (index, (name, func)) in stages.iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Err(anyhow!("job was cancelled before stage {name}"));
+        }
+        let is_last = index + 1 == stages.len();
+        let output = if is_last {
+            stdout.clone()
+        } else {
+            WritePipe::new_in_memory()
+        };
+        let wasi = &mut store.data_mut().wasi;
+        wasi.set_stdin(Box::new(ReadPipe::from(buffer)));
+        wasi.set_stdout(Box::new(output.clone()));
+
+        match func.call(&mut *store, ()) {
+            Ok(()) => {}
+            Err(e) if matches!(e.downcast_ref::<I32Exit>(), Some(I32Exit(0))) => {}
+            Err(e) if e.downcast_ref::<I32Exit>().is_some() => return Err(e),
+            Err(e) => return Err(e.context(PipelineStage(name.clone()))),
+        }
+        if is_last {
+            break;
+        }
+
+        // Let go of this stage's output pipe so that we can take its contents.
+        store
+            .data_mut()
+            .wasi
+            .set_stdout(Box::new(WritePipe::new_in_memory()));
+        buffer = output
+            .try_into_inner()
+            .map_err(|_| anyhow!("unable to read the output of stage {name}"))
+            .context(PipelineStage(name.clone()))?
+            .into_inner();
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use utils::structs::{FailureKind, JobFailure};
+
+    use super::*;
+
+    #[test]
+    fn detects_binary_kind() {
+        let module = b"\0asm\x01\x00\x00\x00";
+        assert_eq!(BinaryKind::detect(module), Some(BinaryKind::Module));
+
+        let component = b"\0asm\x0d\x00\x01\x00";
+        assert_eq!(BinaryKind::detect(component), Some(BinaryKind::Component));
+
+        assert_eq!(BinaryKind::detect(b"\0asm"), None);
+        assert_eq!(BinaryKind::detect(b"(module)"), None);
+    }
 
     #[test]
-    fn write_tests_please() {}
+    fn enforces_memory_limit() {
+        // Grow memory by the given number of 64KiB pages, trapping if the grow is refused.
+        let grower = |pages: u32| {
+            wat::parse_str(format!(
+                r#"(module
+                    (memory 1)
+                    (func (export "_start")
+                        (if (i32.eq (memory.grow (i32.const {pages})) (i32.const -1))
+                            (then unreachable))))"#
+            ))
+            .unwrap()
+        };
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        engine.set_memory_limit(Some(1024 * 1024));
+
+        let result = engine.execute(&grower(4), &[], &[]);
+        assert_eq!(result.unwrap().code, 0);
+
+        let result = engine.execute(&grower(100), &[], &[]);
+        assert!(matches!(
+            result,
+            Err(ServalEngineError::MemoryLimitExceeded(limit)) if limit == 1024 * 1024
+        ));
+    }
+
+    #[test]
+    fn caches_compiled_modules() {
+        let dir = std::env::temp_dir().join(format!("module-cache-{}", std::process::id()));
+        let cache = ModuleCache::new(&dir).unwrap();
+        let module = wat::parse_str(r#"(module (func (export "_start")))"#).unwrap();
+
+        let mut engine = ServalEngine::with_module_cache(HashMap::new(), Some(&cache)).unwrap();
+        assert_eq!(engine.execute(&module, &[], &[]).unwrap().code, 0);
+        let mut engine = ServalEngine::with_module_cache(HashMap::new(), Some(&cache)).unwrap();
+        assert_eq!(engine.execute(&module, &[], &[]).unwrap().code, 0);
+
+        // Besides our config file, the directory now holds the compiled module.
+        assert!(std::fs::read_dir(&dir).unwrap().count() > 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn isolates_runs_from_each_other() {
+        // Bump a global and exit with its value; a run that saw another's bump would exit with 2.
+        let counter = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                (global $runs (mut i32) (i32.const 0))
+                (func (export "_start")
+                    (global.set $runs (i32.add (global.get $runs) (i32.const 1)))
+                    (call $exit (global.get $runs))))"#,
+        )
+        .unwrap();
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        assert_eq!(engine.execute(&counter, &[], &[]).unwrap().code, 1);
+        assert_eq!(engine.execute(&counter, &[], &[]).unwrap().code, 1);
+        assert_eq!(engine.clone().execute(&counter, &[], &[]).unwrap().code, 1);
+    }
+
+    #[test]
+    fn runs_pipeline_stages_in_order() {
+        // Each stage copies stdin to stdout with one letter appended.
+        let module = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func $append (param $letter i32)
+                    (i32.store (i32.const 0) (i32.const 100))
+                    (i32.store (i32.const 4) (i32.const 1000))
+                    (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (i32.store8 (i32.add (i32.const 100) (i32.load (i32.const 8))) (local.get $letter))
+                    (i32.store (i32.const 4) (i32.add (i32.load (i32.const 8)) (i32.const 1)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))
+                (func (export "a") (call $append (i32.const 97)))
+                (func (export "b") (call $append (i32.const 98)))
+                (func (export "boom") unreachable))"#,
+        )
+        .unwrap();
+        let stages =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        engine.set_pipeline(stages(&["a", "b", "a"]));
+        let result = engine.execute(&module, b"x", &[]).unwrap();
+        assert_eq!(result.stdout, b"xaba");
+
+        engine.set_pipeline(stages(&["a", "boom", "b"]));
+        let err = engine.execute(&module, b"x", &[]).unwrap_err();
+        assert!(
+            matches!(&err, ServalEngineError::PipelineStageFailed { stage, .. } if stage == "boom")
+        );
+        assert!(JobFailure::from(&err).message.starts_with("stage boom:"));
+
+        engine.set_pipeline(stages(&["a", "missing"]));
+        let err = engine.execute(&module, b"x", &[]).unwrap_err();
+        assert!(
+            matches!(err, ServalEngineError::PipelineStageUnavailable(name) if name == "missing")
+        );
+    }
+
+    #[test]
+    fn preprocessor_transforms_or_rejects_input() {
+        // Echo stdin to stdout, unless it's empty; then complain on stderr and exit 2.
+        let echo = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 50) "empty input")
+                (func (export "_start")
+                    (i32.store (i32.const 0) (i32.const 100))
+                    (i32.store (i32.const 4) (i32.const 1000))
+                    (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (if (i32.eqz (i32.load (i32.const 8)))
+                        (then
+                            (i32.store (i32.const 0) (i32.const 50))
+                            (i32.store (i32.const 4) (i32.const 11))
+                            (drop (call $fd_write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+                            (call $exit (i32.const 2))))
+                    (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("echo-{}.wasm", std::process::id()));
+        std::fs::write(&path, echo).unwrap();
+        let extensions = HashMap::from([("echo".to_string(), ServalExtension::new(path.clone()))]);
+
+        let mut engine = ServalEngine::new(extensions).unwrap();
+        assert_eq!(engine.preprocess("echo", b"hello").unwrap(), b"hello");
+
+        let err = engine.preprocess("echo", b"").unwrap_err();
+        assert!(
+            matches!(&err, ServalEngineError::InputRejected { reason, .. } if reason == "empty input")
+        );
+        assert_eq!(JobFailure::from(&err).kind, FailureKind::BadInput);
+
+        let err = engine.preprocess("missing", b"hello").unwrap_err();
+        assert_eq!(JobFailure::from(&err).kind, FailureKind::MissingCapability);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deterministic_runs_repeat_exactly() {
+        // Write 8 random bytes and two wall-clock readings to stdout.
+        let module = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "random_get"
+                    (func $random_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "clock_time_get"
+                    (func $clock_time_get (param i32 i64 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "_start")
+                    (drop (call $random_get (i32.const 100) (i32.const 8)))
+                    (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 108)))
+                    (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 116)))
+                    (i32.store (i32.const 0) (i32.const 100))
+                    (i32.store (i32.const 4) (i32.const 24))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        )
+        .unwrap();
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        engine.set_deterministic(true);
+        let first = engine.execute(&module, &[], &[]).unwrap().stdout;
+        let second = engine.execute(&module, &[], &[]).unwrap().stdout;
+        assert_eq!(first, second);
+
+        let clock = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        assert_eq!(clock(&first[8..16]), 946_684_800_001_000_000);
+        assert!(clock(&first[16..24]) > clock(&first[8..16]));
+
+        engine.set_deterministic(false);
+        let real = engine.execute(&module, &[], &[]).unwrap().stdout;
+        assert_ne!(real[8..], first[8..]);
+    }
+
+    #[test]
+    fn mounts_shared_data() {
+        // Write the name of the first preopened directory to stdout, if there is one.
+        let module = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_prestat_get"
+                    (func $fd_prestat_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+                    (func $fd_prestat_dir_name (param i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "_start")
+                    (drop (call $fd_prestat_get (i32.const 3) (i32.const 200)))
+                    (drop (call $fd_prestat_dir_name (i32.const 3) (i32.const 100) (i32.load (i32.const 204))))
+                    (i32.store (i32.const 0) (i32.const 100))
+                    (i32.store (i32.const 4) (i32.load (i32.const 204)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#,
+        )
+        .unwrap();
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        assert!(engine.execute(&module, &[], &[]).unwrap().stdout.is_empty());
+
+        engine.set_shared_data(Some(std::env::temp_dir()));
+        let result = engine.execute(&module, &[], &[]).unwrap();
+        assert_eq!(result.stdout, SHARED_DATA_MOUNT.as_bytes());
+    }
+
+    #[test]
+    fn cancels_running_job() {
+        let spinner = wat::parse_str(r#"(module (func (export "_start") (loop br 0)))"#).unwrap();
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        let cancel = engine.cancel_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            cancel.cancel();
+        });
+
+        let result = engine.execute(&spinner, &[], &[]);
+        assert!(matches!(result, Err(ServalEngineError::Cancelled { .. })));
+    }
+
+    #[test]
+    fn times_out_long_running_job() {
+        let spinner = wat::parse_str(r#"(module (func (export "_start") (loop br 0)))"#).unwrap();
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        engine.set_time_limit(Some(Duration::from_millis(100)));
+        let result = engine.execute(&spinner, &[], &[]);
+        assert!(matches!(result, Err(ServalEngineError::TimedOut { .. })));
+
+        // A run that finishes in time is left alone.
+        let quick = wat::parse_str(r#"(module (func (export "_start")))"#).unwrap();
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        engine.set_time_limit(Some(Duration::from_secs(10)));
+        assert_eq!(engine.execute(&quick, &[], &[]).unwrap().code, 0);
+    }
+
+    #[test]
+    fn turns_on_requested_wasm_features() {
+        let two_memories =
+            wat::parse_str(r#"(module (memory 1) (memory $second 1) (func (export "_start")))"#)
+                .unwrap();
+
+        // Multiple memories are off unless a job asks for them.
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        assert!(engine.execute(&two_memories, &[], &[]).is_err());
+        let mut engine =
+            ServalEngine::with_wasm_features(HashMap::new(), None, &[WasmFeature::MultiMemory])
+                .unwrap();
+        assert_eq!(engine.execute(&two_memories, &[], &[]).unwrap().code, 0);
+
+        for feature in crate::executor::SUPPORTED_WASM_FEATURES {
+            assert!(ServalEngine::with_wasm_features(HashMap::new(), None, &[*feature]).is_ok());
+        }
+        let result =
+            ServalEngine::with_wasm_features(HashMap::new(), None, &[WasmFeature::Threads]);
+        assert!(matches!(
+            result,
+            Err(ServalEngineError::UnsupportedWasmFeature(
+                WasmFeature::Threads
+            ))
+        ));
+    }
+
+    #[test]
+    fn reports_trap_details() {
+        let module = wat::parse_str(
+            r#"(module
+                (func $explode unreachable)
+                (func (export "_start") call $explode))"#,
+        )
+        .unwrap();
+
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        let err = engine.execute(&module, &[], &[]).unwrap_err();
+        let failure = JobFailure::from(&err);
+        assert_eq!(failure.kind, FailureKind::EngineTrap);
+        assert_eq!(failure.trap.as_deref(), Some("UnreachableCodeReached"));
+        assert!(failure.backtrace.unwrap().contains("explode"));
+    }
 }
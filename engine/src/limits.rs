@@ -0,0 +1,53 @@
+use wasmtime::ResourceLimiter;
+
+/// The most elements any one table may grow to. Tables hold function references, so this is far
+/// more than any reasonable job needs while still stopping a runaway guest.
+const MAX_TABLE_ELEMENTS: u32 = 1 << 20;
+
+/// Caps how far a job's linear memories and tables may grow, and remembers whether the job tried
+/// to go past the cap, so that we can tell the caller why the job died.
+#[derive(Debug)]
+pub(crate) struct JobLimiter {
+    max_memory_bytes: Option<usize>,
+    exceeded: bool,
+}
+
+impl JobLimiter {
+    pub(crate) fn new(max_memory_bytes: Option<usize>) -> Self {
+        Self {
+            max_memory_bytes,
+            exceeded: false,
+        }
+    }
+
+    /// The memory cap, if this job has one, and whether the job asked for more than that.
+    pub(crate) fn exceeded_memory(&self) -> Option<usize> {
+        self.max_memory_bytes.filter(|_| self.exceeded)
+    }
+}
+
+impl ResourceLimiter for JobLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        // Refusing the grow makes `memory.grow` return -1 to the guest, which gets a chance to
+        // cope; most guests trap instead, and we report the limit as the reason.
+        if self.max_memory_bytes.map_or(false, |max| desired > max) {
+            self.exceeded = true;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        Ok(desired <= MAX_TABLE_ELEMENTS)
+    }
+}
@@ -0,0 +1,57 @@
+// Keeping compiled modules on disk, so that a job run again skips compiling its executable.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use wasmtime::Config;
+
+use crate::errors::ServalEngineError;
+
+/// The name of the wasmtime cache config file we write into the cache directory.
+const CONFIG_FILE: &str = "wasmtime-cache.toml";
+
+/// A directory of compiled modules, shared by every engine given it and kept across restarts.
+///
+/// This is wasmtime's own compilation cache. Entries are keyed by a hash of the module bytes
+/// together with the wasmtime version and compiler settings, so an upgraded engine never loads
+/// code compiled by an older one; it compiles afresh and caches that instead. Wasmtime also
+/// trims the directory of entries that go unused.
+#[derive(Clone, Debug)]
+pub struct ModuleCache {
+    config_path: PathBuf,
+}
+
+impl ModuleCache {
+    /// Use the given directory for compiled modules, creating it if need be. Do this once, before
+    /// making engines; every engine made with the result shares the directory.
+    pub fn new(dir: &Path) -> Result<Self, ServalEngineError> {
+        std::fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+
+        let mut cache = toml::Table::new();
+        cache.insert("enabled".to_string(), true.into());
+        cache.insert(
+            "directory".to_string(),
+            dir.to_string_lossy().to_string().into(),
+        );
+        let mut config = toml::Table::new();
+        config.insert("cache".to_string(), cache.into());
+        let config = toml::to_string(&config)
+            .map_err(|e| ServalEngineError::EngineInitializationError(e.into()))?;
+
+        let config_path = dir.join(CONFIG_FILE);
+        std::fs::write(&config_path, config)?;
+        Ok(Self { config_path })
+    }
+
+    /// Have engines made from the given config compile through this cache.
+    pub(crate) fn configure(&self, config: &mut Config) -> Result<(), ServalEngineError> {
+        config.cache_config_load(&self.config_path).map_err(|e| {
+            ServalEngineError::EngineInitializationError(anyhow!(
+                "Failed to load module cache config from {}: {e}",
+                self.config_path.display()
+            ))
+        })?;
+        Ok(())
+    }
+}
@@ -1,12 +1,11 @@
-use wasi_common::WasiCtx;
 use wasmtime::{Caller, Linker};
 
 use crate::runtime::helpers::{get_memory_from_caller, read_bytes, write_bytes};
 
-mod helpers;
+pub(crate) mod helpers;
 
 /// Registers all of our Serval-specific functions with the given Linker instance.
-pub fn register_exports(linker: &mut Linker<WasiCtx>) -> Result<(), ()> {
+pub fn register_exports<T: 'static>(linker: &mut Linker<T>) -> Result<(), ()> {
     // The first parameter to func_wrap is the name of the import namespace and the second is the
     // name of the function. The default namespace for Wasm imports is "env". For example, this:
     // ```
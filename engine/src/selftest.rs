@@ -0,0 +1,69 @@
+// A tiny job that any runner ought to be able to run, for checking that the engine works on this
+// node before the node offers to run anyone else's jobs.
+
+use crate::errors::ServalEngineError;
+use crate::ServalEngine;
+
+/// The self-test job. It asks the host to add two numbers, trapping if the answer is wrong, then
+/// copies its stdin to its stdout. Memory holds an iovec at 0, a byte count at 8, and the buffer
+/// from 16.
+const SELF_TEST: &str = r#"(module
+    (import "serval" "add" (func $add (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_read"
+        (func $fd_read (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write"
+        (func $fd_write (param i32 i32 i32 i32) (result i32)))
+    (memory (export "memory") 1)
+    (func (export "_start")
+        (if (i32.ne (call $add (i32.const 2) (i32.const 3)) (i32.const 5))
+            (then unreachable))
+        (i32.store (i32.const 0) (i32.const 16))
+        (loop $copy
+            (i32.store (i32.const 4) (i32.const 4096))
+            (if (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8))
+                (then unreachable))
+            (if (i32.eqz (i32.load (i32.const 8)))
+                (then return))
+            (i32.store (i32.const 4) (i32.load (i32.const 8)))
+            (if (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))
+                (then unreachable))
+            (br $copy))))"#;
+
+/// What we feed the self-test job, and expect back.
+const SELF_TEST_INPUT: &[u8] = b"serval self-test";
+
+impl ServalEngine {
+    /// Run the built-in self-test job. Fails if the job can't be compiled or run, or if it gives
+    /// back anything but its input.
+    pub fn self_test(&mut self) -> Result<(), ServalEngineError> {
+        let executable = wat::parse_str(SELF_TEST).map_err(|e| {
+            ServalEngineError::SelfTestFailed(format!("the self-test job would not assemble: {e}"))
+        })?;
+        let result = self.execute(&executable, SELF_TEST_INPUT, &[])?;
+        if result.code != 0 {
+            return Err(ServalEngineError::SelfTestFailed(format!(
+                "the self-test job exited with code {}",
+                result.code
+            )));
+        }
+        if result.stdout != SELF_TEST_INPUT {
+            return Err(ServalEngineError::SelfTestFailed(
+                "the self-test job gave back something other than its input".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn self_test_passes() {
+        let mut engine = ServalEngine::new(HashMap::new()).unwrap();
+        engine.self_test().unwrap();
+    }
+}
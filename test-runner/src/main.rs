@@ -135,6 +135,7 @@ fn main() -> anyhow::Result<()> {
         manifest.binary().display()
     );
     let mut engine = ServalEngine::new(extensions)?;
+    engine.set_memory_limit(manifest.max_memory_bytes().map(|bytes| bytes as usize));
     let result = match engine.execute(&binary, &stdin, &permissions) {
         Ok(result) => result,
         Err(err) => match err {
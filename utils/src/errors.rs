@@ -102,28 +102,195 @@ pub enum ServalError {
 
     #[error("Manifest contains an invalid job name: {0}")]
     InvalidManifestName(String),
+
+    /// Data we fetched does not hash to the integrity checksum it was stored under.
+    #[error("integrity mismatch for `{0}`")]
+    IntegrityMismatch(String),
+
+    /// There is no upload in progress with this id; it may have been finished or abandoned.
+    #[error("no upload in progress with id `{0}`")]
+    UploadNotFound(String),
+
+    /// An upload was started for more bytes than this node accepts in one executable.
+    #[error("an upload of {0} bytes is larger than the {1}-byte limit")]
+    UploadTooLarge(u64, u64),
+
+    /// This node already has as many uploads in progress as it is willing to hold.
+    #[error("too many uploads in progress; the limit is {0}")]
+    TooManyUploads(usize),
+
+    /// Following a manifest's chain of bases led back to a manifest already in the chain.
+    #[error("manifest base chain is circular: {0}")]
+    ManifestBaseCycle(String),
+
+    /// No job with this id is running on the node we asked.
+    #[error("no running job with id `{0}`")]
+    JobNotFound(String),
+
+    /// A job was given a label that is malformed, or too many labels.
+    #[error("invalid job label: {0}")]
+    InvalidLabel(String),
+
+    /// A signing key, or a key we were told to trust, could not be used.
+    #[error("invalid signing key: {0}")]
+    SigningKeyInvalid(String),
+
+    /// This node only stores signed manifests, and this one isn't.
+    #[error("manifest `{0}` is not signed")]
+    ManifestUnsigned(String),
+
+    /// The manifest's signature was not made by any key this node trusts.
+    #[error("manifest `{0}` is not signed by a trusted key")]
+    ManifestSignatureInvalid(String),
+
+    /// A node answered an API request with an error response.
+    #[error("{message} (code={code}; status={status})")]
+    ApiFailure {
+        status: u16,
+        code: String,
+        message: String,
+    },
 }
 
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 
-impl IntoResponse for ServalError {
-    fn into_response(self) -> axum::response::Response {
-        let status = match &self {
+use crate::structs::api::{ErrorDetail, ErrorResponse};
+
+/// An error response from the HTTP API. Every handler error that isn't a job's failure to run is
+/// sent as one of these, with an `ErrorResponse` body.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: String,
+    message: String,
+    details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code: code.to_string(),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Attach more about the error, for programs to use.
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn bad_request(code: &str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn not_found(code: &str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    /// This node can't do what was asked right now, most likely because no peer can.
+    pub fn unavailable(code: &str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, code, message)
+    }
+
+    /// Storage wasn't set up on a node that routed a storage request to itself.
+    pub fn storage_uninitialized() -> Self {
+        Self::unavailable(
+            "storage_uninitialized",
+            "storage uninitialized; programmer error",
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: ErrorDetail {
+                code: self.code,
+                message: self.message,
+                details: self.details,
+            },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl From<ServalError> for ApiError {
+    fn from(err: ServalError) -> Self {
+        let (status, code) = match &err {
             ServalError::AbnormalWasmExit { result: _ } => {
                 // We probably shouldn't be responding with this error directly ever,
                 // but we provide an implementation just in case. The assumption here is
                 // that the WASM executable was bad in some way.
-                StatusCode::BAD_REQUEST
+                (StatusCode::BAD_REQUEST, "abnormal_exit")
+            }
+            ServalError::BlobAddressInvalid(_) => (StatusCode::BAD_REQUEST, "invalid_address"),
+            ServalError::BlobAddressNotFound(_) | ServalError::DataNotFound(_) => {
+                (StatusCode::NOT_FOUND, "data_not_found")
+            }
+            ServalError::ManifestNotFound(_) => (StatusCode::NOT_FOUND, "manifest_not_found"),
+            ServalError::ExecutableNotFound(_) => (StatusCode::NOT_FOUND, "executable_not_found"),
+            ServalError::ManifestBaseCycle(_) => (StatusCode::BAD_REQUEST, "manifest_base_cycle"),
+            ServalError::UploadNotFound(_) => (StatusCode::NOT_FOUND, "upload_not_found"),
+            ServalError::UploadTooLarge(..) => (StatusCode::PAYLOAD_TOO_LARGE, "upload_too_large"),
+            ServalError::TooManyUploads(_) => (StatusCode::SERVICE_UNAVAILABLE, "too_many_uploads"),
+            ServalError::JobNotFound(_) => (StatusCode::NOT_FOUND, "job_not_found"),
+            ServalError::InvalidLabel(_) => (StatusCode::BAD_REQUEST, "invalid_label"),
+            ServalError::IntegrityMismatch(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "integrity_mismatch")
+            }
+            ServalError::ManifestUnsigned(_) => (StatusCode::FORBIDDEN, "manifest_unsigned"),
+            ServalError::ManifestSignatureInvalid(_) => (StatusCode::FORBIDDEN, "bad_signature"),
+            ServalError::IoError(_) => (StatusCode::NOT_FOUND, "io_error"),
+            ServalError::ServiceNotFound => (StatusCode::NOT_FOUND, "service_not_found"),
+            ServalError::ApiFailure {
+                status,
+                code,
+                message,
+            } => {
+                // Another node's error, which we relay as it was.
+                let status =
+                    StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                return ApiError::new(status, code, message.clone());
             }
-            ServalError::BlobAddressInvalid(_) => StatusCode::BAD_REQUEST,
-            ServalError::BlobAddressNotFound(_) => StatusCode::NOT_FOUND,
-            ServalError::IoError(_) => StatusCode::NOT_FOUND,
-            ServalError::ServiceNotFound => StatusCode::NOT_FOUND,
             // Catch-all for anything we don't want to add specific status codes for.
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
         };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl IntoResponse for ServalError {
+    fn into_response(self) -> Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serval_errors_keep_their_status() {
+        let err = ApiError::from(ServalError::ManifestNotFound(
+            "sh.serval.loudify".to_string(),
+        ));
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert_eq!(err.code, "manifest_not_found");
+        assert!(err.message.contains("sh.serval.loudify"));
 
-        (status, self.to_string()).into_response()
+        // Errors relayed from another node come through as that node sent them.
+        let err = ApiError::from(ServalError::ApiFailure {
+            status: 422,
+            code: "integrity_mismatch".to_string(),
+            message: "integrity mismatch for `x`".to_string(),
+        });
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.code, "integrity_mismatch");
+        assert_eq!(err.message, "integrity mismatch for `x`");
     }
 }
@@ -3,4 +3,5 @@ pub mod errors;
 pub mod futures;
 pub mod mesh;
 pub mod networking;
+pub mod signing;
 pub mod structs;
@@ -5,10 +5,15 @@ use std::time::Duration;
 
 use async_trait::async_trait;
 use bincode::{Decode, Encode};
+use hmac::{Hmac, Mac};
 use if_addrs::Interface;
 use kaboodle::errors::KaboodleError;
 use kaboodle::Kaboodle;
+use once_cell::sync::OnceCell;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::ToSchema;
 
 use crate::errors::ServalError;
 
@@ -28,8 +33,11 @@ pub trait KaboodleMesh {
 
 /// This type encodes the responsibilities of the resources we are meshing together.
 pub trait KaboodlePeer {
-    /// Create a new peer structure from the node identity payload plus an address.
-    fn from_identity(address: IpAddr, encoded: Vec<u8>) -> Self;
+    /// Create a new peer structure from the node identity payload plus an address. Returns None
+    /// if the payload can't be decoded.
+    fn from_identity(address: IpAddr, encoded: Vec<u8>) -> Option<Self>
+    where
+        Self: Sized;
     /// Create an identity payload from whatever internal information matters to your implementation.
     fn identity(&self) -> Vec<u8>;
     /// Get the address of this node.
@@ -39,7 +47,7 @@ pub trait KaboodlePeer {
 // End of tiny wrapper around Kaboodle.
 
 /// These are the roles we allow peers to advertise on the mesh
-#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode, Hash, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum ServalRole {
     Scheduler,
@@ -82,6 +90,58 @@ struct VersionEnvelope {
     rest: Vec<u8>,
 }
 
+/// The envelope version of the identities we send. Each version so far has added a field to the
+/// end of the one before; see `MetadataInner::decode`.
+const IDENTITY_VERSION: u8 = 4;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The shared secret that mesh members must prove they hold, from the `MESH_SECRET` environment
+/// variable. Without one, we sign nothing and accept every peer.
+fn mesh_secret() -> Option<&'static [u8]> {
+    static SECRET: OnceCell<Option<Vec<u8>>> = OnceCell::new();
+    SECRET
+        .get_or_init(|| {
+            std::env::var("MESH_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(String::into_bytes)
+        })
+        .as_deref()
+}
+
+// Sign a peer's encoded metadata, binding it to the address the peer talks to the mesh from so
+// that a captured identity can't be replayed from another machine.
+fn identity_signature(secret: &[u8], address: IpAddr, rest: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(address.to_string().as_bytes());
+    mac.update(rest);
+    mac
+}
+
+/// Returns true if a peer's identity payload carries a valid signature made with our mesh secret
+/// for the address it came from. When we have no secret, every identity is trusted.
+///
+/// The signature follows the versioned envelope in the payload, so peers that don't know about
+/// signatures can still read the identities of peers that send them.
+pub fn is_trusted_identity(address: IpAddr, encoded: &[u8]) -> bool {
+    let Some(secret) = mesh_secret() else {
+        return true;
+    };
+    let config = bincode::config::standard();
+    let decoded = bincode::decode_from_slice::<VersionEnvelope, _>(encoded, config);
+    let Ok((envelope, len)) = decoded else {
+        return false;
+    };
+    let decoded = bincode::decode_from_slice::<Vec<u8>, _>(&encoded[len..], config);
+    let Ok((signature, _len)) = decoded else {
+        return false;
+    };
+    identity_signature(secret, address, &envelope.rest)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
 /// Represents a peer within the mesh. Generally speaking, this contains the data needed to identify
 /// and communicate with a particular peer.
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
@@ -95,12 +155,93 @@ pub struct PeerMetadata {
 #[derive(Debug, Clone, Decode, Encode, Hash, Eq, PartialEq, Deserialize, Serialize)]
 struct MetadataInner {
     instance_id: String,
+    namespace: String,
     http_port: Option<u16>, // Observer-only mesh members will not be listening over HTTP at all
     roles: Vec<ServalRole>,
+    weight: u32, // Relative capacity; peers with twice the weight get twice the proxied work
+    http_host: Option<IpAddr>, // Set when the HTTP API listens somewhere other than the mesh address
+}
+
+// What peers sent before mesh namespaces (version 1), node weights (version 2), and separate HTTP
+// hosts (version 3).
+#[derive(Decode)]
+struct MetadataV1 {
+    instance_id: String,
+    http_port: Option<u16>,
+    roles: Vec<ServalRole>,
+}
+
+#[derive(Decode)]
+struct MetadataV2 {
+    instance_id: String,
+    namespace: String,
+    http_port: Option<u16>,
+    roles: Vec<ServalRole>,
+}
+
+#[derive(Decode)]
+struct MetadataV3 {
+    instance_id: String,
+    namespace: String,
+    http_port: Option<u16>,
+    roles: Vec<ServalRole>,
+    weight: u32,
+}
+
+impl MetadataInner {
+    /// Decode metadata sent in an envelope of the given version. Peers from before a field
+    /// existed get its default. Newer versions only add fields at the end, so we read as much of
+    /// them as we understand.
+    fn decode(version: u8, rest: &[u8]) -> Option<Self> {
+        let config = bincode::config::standard();
+        let inner = match version {
+            0 => return None,
+            1 => {
+                let (v1, _len): (MetadataV1, usize) =
+                    bincode::decode_from_slice(rest, config).ok()?;
+                MetadataInner {
+                    instance_id: v1.instance_id,
+                    namespace: String::new(),
+                    http_port: v1.http_port,
+                    roles: v1.roles,
+                    weight: 1,
+                    http_host: None,
+                }
+            }
+            2 => {
+                let (v2, _len): (MetadataV2, usize) =
+                    bincode::decode_from_slice(rest, config).ok()?;
+                MetadataInner {
+                    instance_id: v2.instance_id,
+                    namespace: v2.namespace,
+                    http_port: v2.http_port,
+                    roles: v2.roles,
+                    weight: 1,
+                    http_host: None,
+                }
+            }
+            3 => {
+                let (v3, _len): (MetadataV3, usize) =
+                    bincode::decode_from_slice(rest, config).ok()?;
+                MetadataInner {
+                    instance_id: v3.instance_id,
+                    namespace: v3.namespace,
+                    http_port: v3.http_port,
+                    roles: v3.roles,
+                    weight: v3.weight,
+                    http_host: None,
+                }
+            }
+            _ => bincode::decode_from_slice(rest, config).ok()?.0,
+        };
+        Some(inner)
+    }
 }
 
 impl PeerMetadata {
-    /// Create a new metadata node from useful information.
+    /// Create a new metadata node from useful information. The node is placed in the mesh namespace
+    /// configured for this process and given this host's weight; see `mesh_namespace()` and
+    /// `node_weight()`.
     pub fn new(
         instance_id: String,
         http_port: Option<u16>,
@@ -109,25 +250,46 @@ impl PeerMetadata {
     ) -> Self {
         let inner = MetadataInner {
             instance_id,
+            namespace: mesh_namespace(),
             http_port,
             roles,
+            weight: node_weight(),
+            http_host: None,
         };
         Self { address, inner }
     }
 
+    /// Advertise that this peer's HTTP API is bound to the given host rather than to the address
+    /// it talks to the mesh on.
+    pub fn with_http_host(mut self, host: IpAddr) -> Self {
+        self.inner.http_host = Some(host);
+        self
+    }
+
     /// Get the instance_id for this peer.
     pub fn instance_id(&self) -> &str {
         &self.inner.instance_id
     }
 
+    /// Get the mesh namespace this peer belongs to. Peers only ever see others in the same namespace.
+    pub fn namespace(&self) -> &str {
+        &self.inner.namespace
+    }
+
     /// Get the roles this peer has chosen to advertise.
     pub fn roles(&self) -> Vec<ServalRole> {
         self.inner.roles.clone()
     }
 
+    /// Get the relative capacity this peer advertises. Always at least 1.
+    pub fn weight(&self) -> u32 {
+        self.inner.weight.max(1)
+    }
+
     /// Get the advertised http address of this peer.
     pub fn http_address(&self) -> Option<SocketAddr> {
-        self.inner.http_port.map(|port| match self.address() {
+        let host = self.inner.http_host.unwrap_or_else(|| self.address());
+        self.inner.http_port.map(|port| match host {
             IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
             IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
         })
@@ -135,22 +297,40 @@ impl PeerMetadata {
 }
 
 impl KaboodlePeer for PeerMetadata {
-    fn from_identity(address: IpAddr, encoded: Vec<u8>) -> Self {
-        // TODO: this is actually fallible; when might it fail?
+    fn from_identity(address: IpAddr, encoded: Vec<u8>) -> Option<Self> {
         let config = bincode::config::standard();
-        let (envelope, _len): (VersionEnvelope, usize) =
-            bincode::decode_from_slice(&encoded[..], config).unwrap();
-        // In the future, switch on version in the envelope and decode into variants.
-        let (inner, _len): (MetadataInner, usize) =
-            bincode::decode_from_slice(&envelope.rest[..], config).unwrap();
-        PeerMetadata { address, inner }
+        let decoded = bincode::decode_from_slice::<VersionEnvelope, _>(&encoded[..], config);
+        let Ok((envelope, _len)) = decoded else {
+            log::debug!("ignoring peer with an unreadable identity; addr={address}");
+            return None;
+        };
+        let Some(inner) = MetadataInner::decode(envelope.version, &envelope.rest) else {
+            log::debug!(
+                "ignoring peer with an unreadable identity; addr={address}; version={}",
+                envelope.version
+            );
+            return None;
+        };
+        Some(PeerMetadata { address, inner })
     }
 
     fn identity(&self) -> Vec<u8> {
         let config = bincode::config::standard();
         let rest: Vec<u8> = bincode::encode_to_vec(self.inner.clone(), config).unwrap_or_default();
-        let envelope = VersionEnvelope { version: 1, rest };
-        let identity: Vec<u8> = bincode::encode_to_vec(envelope, config).unwrap_or_default();
+        let signature = mesh_secret().map(|secret| {
+            identity_signature(secret, self.address, &rest)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        });
+        let envelope = VersionEnvelope {
+            version: IDENTITY_VERSION,
+            rest,
+        };
+        let mut identity: Vec<u8> = bincode::encode_to_vec(envelope, config).unwrap_or_default();
+        if let Some(signature) = signature {
+            identity.extend(bincode::encode_to_vec(signature, config).unwrap_or_default());
+        }
         identity
     }
 
@@ -164,7 +344,7 @@ impl KaboodlePeer for PeerMetadata {
 #[derive(Debug)]
 pub struct ServalMesh {
     kaboodle: Kaboodle,
-    _metadata: PeerMetadata, // TODO: do I need this?
+    metadata: PeerMetadata,
 }
 
 impl ServalMesh {
@@ -176,10 +356,13 @@ impl ServalMesh {
     ) -> Result<Self, KaboodleError> {
         let identity = metadata.identity();
         let kaboodle = Kaboodle::new(port, interface, identity)?;
-        Ok(Self {
-            kaboodle,
-            _metadata: metadata,
-        })
+        Ok(Self { kaboodle, metadata })
+    }
+
+    /// Returns true if the given peer is in the same mesh namespace as we are. Peers from other
+    /// namespaces share the underlying gossip network with us, but are otherwise invisible.
+    pub fn shares_namespace(&self, peer: &PeerMetadata) -> bool {
+        peer.namespace() == self.metadata.namespace()
     }
 
     /// Returns a map of all peers with known latencies.
@@ -188,14 +371,13 @@ impl ServalMesh {
             .peer_states()
             .await
             .into_iter()
+            .filter(|(addr, peer_info)| is_trusted_identity(addr.ip(), &peer_info.identity))
             .filter_map(|(addr, peer_info)| {
-                peer_info.latency.map(|latency| {
-                    (
-                        PeerMetadata::from_identity(addr.ip(), peer_info.identity.to_vec()),
-                        latency,
-                    )
-                })
+                let latency = peer_info.latency?;
+                let peer = PeerMetadata::from_identity(addr.ip(), peer_info.identity.to_vec())?;
+                Some((peer, latency))
             })
+            .filter(|(peer, _)| self.shares_namespace(peer))
             .collect::<HashMap<_, _>>()
     }
 
@@ -209,6 +391,31 @@ impl ServalMesh {
             .collect()
     }
 
+    /// Pick one peer advertising the given role, at random but biased by each peer's advertised
+    /// weight, so that bigger nodes get a proportionally bigger share of the work.
+    pub async fn weighted_peer_with_role(&self, role: &ServalRole) -> Option<PeerMetadata> {
+        self.weighted_peer_with_role_except(role, &[]).await
+    }
+
+    /// Like `weighted_peer_with_role()`, but never picks any of the given instances; for finding
+    /// somewhere else to go when a peer has turned us away.
+    pub async fn weighted_peer_with_role_except(
+        &self,
+        role: &ServalRole,
+        excluded: &[String],
+    ) -> Option<PeerMetadata> {
+        let candidates: Vec<PeerMetadata> = self
+            .peers_with_role(role)
+            .await
+            .into_iter()
+            .filter(|peer| !excluded.iter().any(|id| id == peer.instance_id()))
+            .collect();
+        candidates
+            .choose_weighted(&mut rand::thread_rng(), |peer| peer.weight())
+            .ok()
+            .cloned()
+    }
+
     // Delegation would be nice.
     pub fn discover_peers(
         &mut self,
@@ -240,16 +447,116 @@ impl KaboodleMesh for ServalMesh {
         let peers = self.kaboodle.peers().await;
         peers
             .into_iter()
-            .map(|(addr, identity)| PeerMetadata::from_identity(addr.ip(), identity.to_vec()))
+            .filter(|(addr, identity)| {
+                let trusted = is_trusted_identity(addr.ip(), identity);
+                if !trusted {
+                    log::debug!("ignoring peer without a valid mesh signature; addr={addr}");
+                }
+                trusted
+            })
+            .filter_map(|(addr, identity)| {
+                PeerMetadata::from_identity(addr.ip(), identity.to_vec())
+            })
+            .filter(|peer| self.shares_namespace(peer))
             .collect()
     }
 }
 
-/// Discover a single nearby node in the mesh, without the overhead of joining it.
+/// Discover a single nearby node in our mesh namespace, without the overhead of joining the mesh.
 pub async fn discover() -> Result<PeerMetadata, KaboodleError> {
     let (iface, port) = mesh_interface_and_port();
-    let (address, identity) = Kaboodle::discover_mesh_member(port, Some(iface)).await?;
-    Ok(PeerMetadata::from_identity(address.ip(), identity.to_vec()))
+    let namespace = mesh_namespace();
+    loop {
+        let (address, identity) =
+            Kaboodle::discover_mesh_member(port, Some(iface.clone())).await?;
+        if !is_trusted_identity(address.ip(), &identity) {
+            log::debug!("ignoring peer without a valid mesh signature; addr={address}");
+            continue;
+        }
+        let Some(peer) = PeerMetadata::from_identity(address.ip(), identity.to_vec()) else {
+            continue;
+        };
+        if peer.namespace() == namespace {
+            return Ok(peer);
+        }
+        log::debug!(
+            "ignoring peer from another mesh namespace; peer={}; namespace={}",
+            peer.instance_id(),
+            peer.namespace()
+        );
+    }
+}
+
+/// Collect every peer in our mesh namespace, optionally only those advertising the given role, by
+/// briefly joining the mesh as an observer and listening for the given length of time. Observers
+/// are never included. Finding nobody is not an error; the result is simply empty.
+pub async fn discover_all(role: Option<&ServalRole>, timeout: Duration) -> Vec<PeerMetadata> {
+    let (iface, port) = mesh_interface_and_port();
+    let metadata = PeerMetadata::new(
+        format!("observer@{}", iface.ip()),
+        None,
+        vec![ServalRole::Observer],
+        iface.ip(),
+    );
+    let mut mesh = match ServalMesh::new(metadata, port, Some(iface)).await {
+        Ok(mesh) => mesh,
+        Err(err) => {
+            log::warn!("unable to create a mesh observer for discovery; err={err:?}");
+            return Vec::new();
+        }
+    };
+    if let Err(err) = mesh.start().await {
+        log::warn!("unable to join the mesh for discovery; err={err:?}");
+        return Vec::new();
+    }
+
+    tokio::time::sleep(timeout).await;
+    let peers = mesh
+        .peers()
+        .await
+        .into_iter()
+        .filter(|peer| !peer.roles().contains(&ServalRole::Observer))
+        .filter(|peer| role.map_or(true, |role| peer.roles().contains(role)))
+        .collect();
+
+    if let Err(err) = mesh.stop().await {
+        log::info!("unable to leave the mesh cleanly after discovery; err={err:?}");
+    }
+    peers
+}
+
+/// The longest we are willing to wait to find a peer, from the `DISCOVERY_TIMEOUT` environment
+/// variable, in seconds. Discovery finishes as soon as a peer answers; this is only the ceiling.
+pub fn discovery_timeout() -> Duration {
+    match std::env::var("DISCOVERY_TIMEOUT") {
+        Ok(secs_str) => Duration::from_secs_f64(
+            secs_str
+                .parse::<f64>()
+                .expect("Invalid value given for DISCOVERY_TIMEOUT"),
+        ),
+        Err(_) => Duration::from_secs(10),
+    }
+}
+
+/// The weight this node advertises to its peers, from the `NODE_WEIGHT` environment variable. If
+/// that is not set, we use the number of cores available to us as a rough proxy for capacity.
+pub fn node_weight() -> u32 {
+    match std::env::var("NODE_WEIGHT") {
+        Ok(weight_str) => weight_str
+            .parse::<u32>()
+            .expect("Invalid value given for NODE_WEIGHT")
+            .max(1),
+        Err(_) => std::thread::available_parallelism()
+            .map(|cores| cores.get() as u32)
+            .unwrap_or(1),
+    }
+}
+
+/// The mesh namespace this process should join, from the `MESH_NAMESPACE` environment variable.
+/// Defaults to the empty string. Independent meshes can share a network (and a mesh port) as long
+/// as they use different namespaces.
+pub fn mesh_namespace() -> String {
+    std::env::var("MESH_NAMESPACE").unwrap_or_default()
 }
 
 pub fn mesh_interface_and_port() -> (if_addrs::Interface, u16) {
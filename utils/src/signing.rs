@@ -0,0 +1,180 @@
+// Signing manifests, so that storage nodes can tell who published a job before accepting it.
+//
+// A signature covers the manifest as serialized without its signature. Signed manifests also carry
+// the integrity checksum of their executable, so the signature vouches for the executable as well.
+
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use ssri::Integrity;
+
+use crate::errors::{ServalError, ServalResult};
+use crate::structs::Manifest;
+
+/// The env var holding the hex-encoded 32-byte ed25519 seed that `pounce store` signs with.
+pub const SIGNING_KEY_ENV: &str = "SERVAL_SIGNING_KEY";
+
+/// A key for signing manifests.
+#[derive(Debug)]
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+    /// Make a key from a hex-encoded 32-byte ed25519 seed.
+    pub fn from_hex(seed: &str) -> ServalResult<Self> {
+        let seed = hex::decode(seed.trim())
+            .map_err(|e| ServalError::SigningKeyInvalid(format!("not hex: {e}")))?;
+        let pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|_| ServalError::SigningKeyInvalid("not a 32-byte seed".to_string()))?;
+        Ok(SigningKey(pair))
+    }
+
+    /// The hex-encoded public half of this key, for storage nodes to trust.
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.0.public_key().as_ref())
+    }
+
+    /// Sign a manifest along with the executable it will be stored with.
+    pub fn sign(&self, manifest: &mut Manifest, executable: &[u8]) -> ServalResult<()> {
+        manifest.set_executable_integrity(Some(Integrity::from(executable).to_string()));
+        let payload = signed_payload(manifest)?;
+        let signature = self.0.sign(payload.as_bytes());
+        manifest.set_signature(Some(hex::encode(signature.as_ref())));
+        Ok(())
+    }
+}
+
+/// The bytes a manifest's signature is made over: the manifest as toml, minus its signature.
+fn signed_payload(manifest: &Manifest) -> ServalResult<String> {
+    let mut unsigned = manifest.clone();
+    unsigned.set_signature(None);
+    Ok(toml::to_string(&unsigned)?)
+}
+
+/// What a storage node accepts: the public keys it trusts, and whether it takes unsigned manifests.
+#[derive(Debug, Default)]
+pub struct SigningPolicy {
+    trusted_keys: Vec<Vec<u8>>,
+    require_signed: bool,
+}
+
+impl SigningPolicy {
+    /// Trust the given hex-encoded public keys. Fails if any of them isn't hex.
+    pub fn new(trusted_keys: &[&str], require_signed: bool) -> ServalResult<Self> {
+        let trusted_keys = trusted_keys
+            .iter()
+            .map(|key| {
+                hex::decode(key.trim()).map_err(|e| {
+                    ServalError::SigningKeyInvalid(format!("trusted key {key} is not hex: {e}"))
+                })
+            })
+            .collect::<ServalResult<Vec<_>>>()?;
+        Ok(SigningPolicy {
+            trusted_keys,
+            require_signed,
+        })
+    }
+
+    pub fn require_signed(&self) -> bool {
+        self.require_signed
+    }
+
+    /// Decide whether to accept a manifest for storage. Signatures are checked whenever we trust
+    /// any keys; unsigned manifests are turned away only if we require signatures.
+    pub fn check_manifest(&self, manifest: &Manifest) -> ServalResult<()> {
+        let Some(signature) = manifest.signature() else {
+            if self.require_signed {
+                return Err(ServalError::ManifestUnsigned(manifest.fq_name()));
+            }
+            return Ok(());
+        };
+        if self.trusted_keys.is_empty() && !self.require_signed {
+            return Ok(());
+        }
+
+        let bad_signature = || ServalError::ManifestSignatureInvalid(manifest.fq_name());
+        let signature = hex::decode(signature).map_err(|_| bad_signature())?;
+        let payload = signed_payload(manifest)?;
+        let trusted = self.trusted_keys.iter().any(|key| {
+            UnparsedPublicKey::new(&ED25519, key)
+                .verify(payload.as_bytes(), &signature)
+                .is_ok()
+        });
+        if trusted {
+            Ok(())
+        } else {
+            Err(bad_signature())
+        }
+    }
+
+    /// Decide whether to accept an executable for the given version of a stored manifest. If the
+    /// manifest was signed for that version, the executable must be the one it names. If we
+    /// require signatures, we only take executables for the signed version.
+    pub fn check_executable(
+        &self,
+        manifest: &Manifest,
+        version: &str,
+        bytes: &[u8],
+    ) -> ServalResult<()> {
+        let name = format!("{}@{version}", manifest.fq_name());
+        let expected = match manifest.executable_integrity() {
+            Some(integrity) if manifest.version() == version => integrity,
+            _ if self.require_signed => return Err(ServalError::ManifestUnsigned(name)),
+            _ => return Ok(()),
+        };
+        let expected: Integrity = expected.parse()?;
+        expected
+            .check(bytes)
+            .map(|_| ())
+            .map_err(|_| ServalError::IntegrityMismatch(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    #[test]
+    fn signed_manifests_verify_against_trusted_keys() {
+        let key = SigningKey::from_hex(SEED).unwrap();
+        let mut manifest = Manifest::new(&PathBuf::from("/tmp/loudify.wasm"));
+        key.sign(&mut manifest, b"\0asm").unwrap();
+
+        let policy = SigningPolicy::new(&[&key.public_key_hex()], true).unwrap();
+        assert!(policy.check_manifest(&manifest).is_ok());
+        assert!(policy
+            .check_executable(&manifest, manifest.version(), b"\0asm")
+            .is_ok());
+        assert!(policy
+            .check_executable(&manifest, manifest.version(), b"other")
+            .is_err());
+
+        // Changing anything the signature covers invalidates it.
+        let mut tampered = manifest.clone();
+        tampered.set_executable_integrity(Some(Integrity::from(b"other").to_string()));
+        assert!(policy.check_manifest(&tampered).is_err());
+
+        let untrusted = SigningPolicy::new(&[&"00".repeat(32)], false).unwrap();
+        assert!(untrusted.check_manifest(&manifest).is_err());
+
+        let unsigned = Manifest::new(&PathBuf::from("/tmp/loudify.wasm"));
+        assert!(policy.check_manifest(&unsigned).is_err());
+        assert!(SigningPolicy::default().check_manifest(&unsigned).is_ok());
+
+        // A manifest filled in from a base says more than its signer did, so it loses the
+        // signature, but its executable is still the signed one.
+        let base = Manifest::from_string(
+            "name = \"base\"\nnamespace = \"sh.serval\"\nbinary = \"/tmp/base.wasm\"\n\
+             version = \"1\"\ndescription = \"shared\"\nmax_input_bytes = 1024\n",
+        )
+        .unwrap();
+        let mut flattened = manifest.clone();
+        flattened.inherit_from(&base);
+        assert_eq!(flattened.signature(), None);
+        assert!(policy.check_manifest(&flattened).is_err());
+        assert!(policy
+            .check_executable(&flattened, flattened.version(), b"other")
+            .is_err());
+    }
+}
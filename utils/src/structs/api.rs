@@ -1,16 +1,46 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-use crate::mesh::PeerMetadata;
+use crate::mesh::{PeerMetadata, ServalRole};
+use crate::structs::{JobFailure, Runtime, WasmFeature};
+
+/// The response header a storage node uses to tell the fetcher of an executable what integrity
+/// checksum the executable was stored under.
+pub const INTEGRITY_HEADER: &str = "Serval-Integrity";
+
+/// The request header that names the tenant a request is acting for. Manifests and executables
+/// stored by a tenant are only visible to requests made for that same tenant.
+pub const TENANT_HEADER: &str = "X-Serval-Tenant";
+
+/// The response header a runner uses to say whether a pure job's result came from its cache
+/// (`hit`) or from actually running the job (`miss`).
+pub const CACHE_HEADER: &str = "Serval-Cache";
+
+/// The response header a runner uses to report the exit code of a job that ran successfully. This
+/// is only interesting for jobs whose manifests accept nonzero exit codes.
+pub const EXIT_CODE_HEADER: &str = "Serval-Exit-Code";
+
+/// The response header a runner uses to say that a job's output was written to the blob store
+/// rather than sent back. Its value, which is also the body of the response, is a pointer of the
+/// form `serval://<integrity>`; the output can be fetched from `/v1/storage/data/<integrity>`.
+pub const OUTPUT_LOCATION_HEADER: &str = "Serval-Output-Location";
+
+/// The scheme of a pointer to data in the blob store.
+pub const STORAGE_POINTER_SCHEME: &str = "serval://";
 
 /// A MeshMember is effectively a limited subset of information from a PeerMetadata instance. Unlike
 /// PeerMetadata, MeshMember is publicly visible via the HTTP API. The intention is for it to only
 /// contain enoug information to know how to talk to a node and who that node is.
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct MeshMember {
+    #[schema(value_type = Option<String>)]
     pub http_address: Option<SocketAddr>,
     pub instance_id: String,
+    pub weight: u32,
 }
 
 impl From<PeerMetadata> for MeshMember {
@@ -18,6 +48,203 @@ impl From<PeerMetadata> for MeshMember {
         MeshMember {
             http_address: peer_metadata.http_address(),
             instance_id: peer_metadata.instance_id().to_string(),
+            weight: peer_metadata.weight(),
         }
     }
 }
+
+/// The body of a request to begin uploading an executable in pieces.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct StartUpload {
+    /// The total size of the executable, in bytes.
+    pub size: u64,
+}
+
+/// The response to beginning an upload: where to send the pieces.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct UploadStarted {
+    pub upload_id: Uuid,
+}
+
+/// The response to finalizing an upload that is still missing some bytes.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct MissingRanges {
+    /// Half-open byte ranges, `[start, end)`, that still need to be sent.
+    #[schema(value_type = Vec<Vec<u64>>)]
+    pub missing: Vec<(u64, u64)>,
+}
+
+/// A snapshot of how busy a node is, as reported by `/monitor/status`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct NodeStatus {
+    pub instance_id: String,
+    /// The one-minute load average, if the platform reports one.
+    pub load_average: Option<f64>,
+    /// Memory available for new work, in bytes, if the platform reports it.
+    pub available_memory_bytes: Option<u64>,
+    /// How many jobs this node is running right now.
+    pub running_jobs: usize,
+    /// The runtimes this node can run jobs for; empty if it doesn't run jobs.
+    #[serde(default)]
+    pub runtimes: Vec<Runtime>,
+    /// The Wasm features this node can turn on for jobs that need them; empty if it doesn't run
+    /// jobs.
+    #[serde(default)]
+    pub wasm_features: Vec<WasmFeature>,
+    /// The circuits for relaying requests to other nodes that have seen failures lately.
+    #[serde(default)]
+    pub proxy_circuits: Vec<CircuitStatus>,
+    /// Which build of the agent the node is running; missing from nodes too old to say.
+    #[serde(default)]
+    pub build: Option<BuildInfo>,
+}
+
+/// Which build of the agent a node is running, as reported by `/monitor/version`.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct BuildInfo {
+    /// The agent's crate version.
+    pub version: String,
+    /// The commit the agent was built from, if it was built from a git checkout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_sha: Option<String>,
+    /// When the agent was built, as an RFC 3339 timestamp in UTC.
+    pub built_at: String,
+}
+
+/// Whether a node is relaying requests for a role to other nodes, as reported by `/monitor/status`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct CircuitStatus {
+    pub role: ServalRole,
+    pub state: CircuitState,
+    /// How many relays to the role have failed since one last succeeded.
+    pub consecutive_failures: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests are relayed as usual.
+    Closed,
+    /// Requests are refused at once until the cooldown is up.
+    Open,
+    /// The cooldown is up; the next request will be relayed to see whether the role is back.
+    HalfOpen,
+}
+
+/// A node's view of the mesh, as reported by `/monitor/mesh`.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct MeshHealth {
+    /// The peers this node can see right now.
+    pub visible_peers: Vec<MeshMember>,
+    /// Peers this node expected to see but can't: those it was told to expect, and those it saw
+    /// recently. Any at all suggest the mesh may have split, though a peer that was shut down on
+    /// purpose is listed here too until it is forgotten.
+    pub missing_peers: Vec<MissingPeer>,
+    /// True if any peers are missing.
+    pub suspected_partition: bool,
+}
+
+/// A peer a node expected to see in the mesh but can't.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct MissingPeer {
+    #[schema(value_type = String)]
+    pub http_address: SocketAddr,
+    /// The peer's instance id when it was last seen, if it ever was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// How long ago the peer was last seen, if it ever was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen_secs_ago: Option<u64>,
+    /// True if the peer is on the node's list of peers to expect.
+    pub known: bool,
+}
+
+/// The body of every error response from the HTTP API, other than a job's failure to run. The
+/// code is stable and meant for programs to switch on; the message is meant for people.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// A short snake_case name for the kind of error, e.g. `manifest_not_found`.
+    pub code: String,
+    pub message: String,
+    /// Anything more that a program might want to know, specific to the kind of error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<serde_json::Value>,
+}
+
+/// The body a runner responds with when it was unable to run a job to completion.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobFailureResponse {
+    pub failure: JobFailure,
+    /// Whatever the job managed to write to stdout before it was cancelled, if anything.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub stdout: String,
+    /// Whatever the job managed to write to stderr before it failed, if anything.
+    #[serde(default)]
+    pub stderr: String,
+}
+
+/// The body a runner POSTs to a job's callback URL once the job has finished.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobCallback {
+    pub job_id: Uuid,
+    /// The fully-qualified name of the job that ran.
+    pub name: String,
+    /// The job's exit code, if it ran to completion.
+    pub exit_code: Option<i32>,
+    /// Why the job failed, if it did not run to completion.
+    pub failure: Option<JobFailure>,
+    /// The labels the job was run with.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A job a node is running right now, as listed by `GET /v1/jobs`.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct RunningJob {
+    pub id: Uuid,
+    /// The fully-qualified name of the job.
+    pub name: String,
+    /// When the job started, in seconds since the Unix epoch.
+    pub started_at_secs: u64,
+    /// How long the job has been running, in milliseconds.
+    pub elapsed_ms: u64,
+    /// The size of the job's input, in bytes.
+    pub input_bytes: u64,
+    /// The labels the job was run with.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// The index at the front of a storage export. It names every file in the archive, the storage key
+/// that file is restored under, and the integrity checksum it must match.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArchiveIndex {
+    /// The version of the export format.
+    pub version: u32,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+/// One stored manifest or executable in a storage export.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArchiveEntry {
+    /// Where the file is in the archive.
+    pub path: String,
+    /// The storage key it was exported from.
+    pub key: String,
+    pub integrity: String,
+}
+
+/// The response to importing a storage export.
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct ImportSummary {
+    /// How many manifests and executables were written.
+    pub imported: usize,
+    /// How many were already present with the same contents.
+    pub skipped: usize,
+}
@@ -1,10 +1,12 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::errors::ServalError;
@@ -22,8 +24,134 @@ pub struct WasmResult {
     pub stderr: Vec<u8>,
 }
 
+/// Broad categories of job failure. These exist so that callers can tell a job that is genuinely
+/// broken apart from a transient problem with the node that tried to run it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The Wasm executable trapped; e.g., it hit an `unreachable` or read out of bounds.
+    EngineTrap,
+    /// The job was interrupted before it could finish.
+    Timeout,
+    /// The job needs an extension, permission, or platform feature that this node lacks.
+    MissingCapability,
+    /// The executable or the input we were handed was unusable.
+    BadInput,
+    /// The job went over a size limit declared in its manifest.
+    LimitExceeded,
+    /// Somebody asked for the job to be stopped while it was running.
+    Cancelled,
+    /// The job ran to completion but exited with a code its manifest does not accept.
+    NonZeroExit,
+    /// The job's output does not match the JSON Schema its manifest names.
+    SchemaViolation,
+    /// Something went wrong on our end. Retrying, possibly on another node, may well succeed.
+    Internal,
+}
+
+impl Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureKind::EngineTrap => write!(f, "engine_trap"),
+            FailureKind::Timeout => write!(f, "timeout"),
+            FailureKind::MissingCapability => write!(f, "missing_capability"),
+            FailureKind::BadInput => write!(f, "bad_input"),
+            FailureKind::LimitExceeded => write!(f, "limit_exceeded"),
+            FailureKind::Cancelled => write!(f, "cancelled"),
+            FailureKind::NonZeroExit => write!(f, "nonzero_exit"),
+            FailureKind::SchemaViolation => write!(f, "schema_violation"),
+            FailureKind::Internal => write!(f, "internal"),
+        }
+    }
+}
+
+/// A description of why a job failed, suitable for sending over the wire.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct JobFailure {
+    /// The category of failure.
+    pub kind: FailureKind,
+    /// A human-readable explanation of what went wrong.
+    pub message: String,
+    /// The Wasm trap code, if the job failed by trapping.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trap: Option<String>,
+    /// The guest's stack at the point it trapped, one frame per line, if the engine captured one.
+    /// Frames only have file and line information when the module was built with debug info.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>,
+    /// The code the job exited with, if it ran to completion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+impl JobFailure {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            trap: None,
+            backtrace: None,
+            exit_code: None,
+        }
+    }
+}
+
+/// The kind of executor that a job's executable is built for. Wasm is the only one so far.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Runtime {
+    #[default]
+    Wasm,
+}
+
+impl Runtime {
+    fn is_wasm(&self) -> bool {
+        *self == Runtime::Wasm
+    }
+}
+
+impl Display for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Runtime::Wasm => write!(f, "wasm"),
+        }
+    }
+}
+
+/// A WebAssembly proposal beyond the MVP that a module may be compiled to rely on.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum WasmFeature {
+    Simd,
+    RelaxedSimd,
+    BulkMemory,
+    ReferenceTypes,
+    MultiValue,
+    MultiMemory,
+    Memory64,
+    Threads,
+}
+
+impl Display for WasmFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            WasmFeature::Simd => "simd",
+            WasmFeature::RelaxedSimd => "relaxed_simd",
+            WasmFeature::BulkMemory => "bulk_memory",
+            WasmFeature::ReferenceTypes => "reference_types",
+            WasmFeature::MultiValue => "multi_value",
+            WasmFeature::MultiMemory => "multi_memory",
+            WasmFeature::Memory64 => "memory64",
+            WasmFeature::Threads => "threads",
+        };
+        write!(f, "{str}")
+    }
+}
+
 /// Wasm executable metadata, for human reasons.
-#[derive(Clone, Debug, Default, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, ToSchema)]
 pub struct Manifest {
     /// Short name of this Wasm manifest. Lower-cased alphanumerics plus underscore.
     name: String,
@@ -32,6 +160,7 @@ pub struct Manifest {
     /// A semver-compatible version string. Semver not yet enforced.
     version: String,
     /// Path to a compiled Wasm exectuable.
+    #[schema(value_type = String)]
     binary: PathBuf,
     /// Human-readable description.
     description: String,
@@ -46,7 +175,73 @@ pub struct Manifest {
     /// Required permissions; it is up to the agent to ensure that the submitter of this job is
     /// actually authorized to run a job with said permissions.
     #[serde(default)]
+    #[schema(value_type = Vec<String>)]
     required_permissions: Vec<Permission>,
+    /// The largest input, in bytes, that this job is willing to accept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_input_bytes: Option<u64>,
+    /// The largest output, in bytes, that this job should ever produce.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_output_bytes: Option<u64>,
+    /// The integrity address of a JSON Schema in the blob store that this job's output must
+    /// match. In a manifest file it may instead be a path to the schema, relative to the manifest;
+    /// `pounce store` stores the schema and puts its address here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_schema: Option<String>,
+    /// The executor this job's executable is for. Left out of manifests for Wasm jobs.
+    #[serde(default, skip_serializing_if = "Runtime::is_wasm")]
+    runtime: Runtime,
+    /// Wasm features the executable was compiled to use, e.g. `simd`. Runners turn these on for
+    /// the job, and refuse it up front if they can't.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    wasm_features: Vec<WasmFeature>,
+    /// True if this job's output depends only on its input, so that results may be reused.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pure: bool,
+    /// True if this job should see a pinned clock and seeded randomness, so that a job which
+    /// reads the time or asks for random bytes still gives the same output on every run.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deterministic: bool,
+    /// True if this job reads the node's shared dataset, which it finds read-only at
+    /// `/shared`. Nodes without a shared dataset refuse to run such jobs.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    needs_shared_data: bool,
+    /// Nonzero exit codes that this job uses to mean something other than failure.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    acceptable_exit_codes: Vec<i32>,
+    /// True if this job's output should be written to the blob store, with only a pointer to it
+    /// sent back to the caller. For jobs whose output is too big to pass around comfortably.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    output_to_storage: bool,
+    /// Exports to call in order, each reading the previous one's output, instead of the module's
+    /// default export. All stages run on the same instance of the module.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pipeline: Vec<String>,
+    /// The name of an extension to run on the raw input before the job sees it. The extension is
+    /// a WASI command: it reads the input on stdin and writes the input the job should get to
+    /// stdout. Exiting nonzero rejects the input, with whatever it wrote to stderr as the reason.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preprocessor: Option<String>,
+    /// The most linear memory, in bytes, that this job may grow to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_memory_bytes: Option<u64>,
+    /// The longest this job may run, in milliseconds, before it is stopped and fails with a
+    /// timeout. Nodes may hold jobs to a shorter limit of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_duration_ms: Option<u64>,
+    /// The fully-qualified name of a stored manifest to inherit unset fields from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base: Option<String>,
+    /// Free-form tags, for finding this job type among many.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// The integrity checksum of the executable for this manifest's version. Set when the
+    /// manifest is signed, so that the signature covers the executable too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    executable_integrity: Option<String>,
+    /// A hex-encoded ed25519 signature over the rest of the manifest; see `crate::signing`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
 }
 
 impl Manifest {
@@ -59,9 +254,36 @@ impl Manifest {
             description: String::from(""),
             required_extensions: vec![],
             required_permissions: vec![],
+            max_input_bytes: None,
+            max_output_bytes: None,
+            output_schema: None,
+            runtime: Runtime::Wasm,
+            wasm_features: vec![],
+            pure: false,
+            deterministic: false,
+            needs_shared_data: false,
+            acceptable_exit_codes: vec![],
+            output_to_storage: false,
+            pipeline: vec![],
+            preprocessor: None,
+            max_memory_bytes: None,
+            max_duration_ms: None,
+            base: None,
+            tags: vec![],
+            executable_integrity: None,
+            signature: None,
         }
     }
 
+    /// A manifest for an executable that was sent to run without being stored. It belongs to the
+    /// `adhoc` namespace and asks for nothing, so the job runs under a node's default limits.
+    pub fn ad_hoc(name: &str) -> Manifest {
+        let mut manifest = Manifest::new(&PathBuf::from(name));
+        manifest.name = name.to_string();
+        manifest.namespace = String::from("adhoc");
+        manifest
+    }
+
     pub fn from_string(input: &str) -> Result<Self, ServalError> {
         let manifest: Manifest = toml::from_str(input)?;
         if manifest.binary.is_relative() {
@@ -84,10 +306,23 @@ impl Manifest {
         Ok(manifest)
     }
 
+    /// Write this manifest to the given path as toml. Fields are always written in the same order,
+    /// so a manifest read with `from_file()` and written back unchanged gives an identical file.
+    pub fn to_file(&self, path: &Path) -> Result<(), ServalError> {
+        let toml = toml::to_string(self)?;
+        fs::write(path, toml)?;
+        Ok(())
+    }
+
     pub fn binary(&self) -> &PathBuf {
         &self.binary
     }
 
+    /// Point this manifest at the executable it was built from.
+    pub fn set_binary(&mut self, path: PathBuf) {
+        self.binary = path;
+    }
+
     /// Get the list of permissions that this manifest is requesting. Note that this list needs to
     /// be validated elsewhere to ensure that the running user is authorized to assign said
     /// permissions.
@@ -95,21 +330,220 @@ impl Manifest {
         &self.required_permissions
     }
 
+    /// The largest input this job accepts, if the manifest declares a limit.
+    pub fn max_input_bytes(&self) -> Option<u64> {
+        self.max_input_bytes
+    }
+
+    /// The largest output this job may produce, if the manifest declares a limit.
+    pub fn max_output_bytes(&self) -> Option<u64> {
+        self.max_output_bytes
+    }
+
+    /// Where the JSON Schema for this job's output is, if its output has one to match.
+    pub fn output_schema(&self) -> Option<&str> {
+        self.output_schema.as_deref()
+    }
+
+    /// Point this manifest at the stored JSON Schema its output must match.
+    pub fn set_output_schema(&mut self, address: Option<String>) {
+        self.output_schema = address;
+    }
+
+    /// The executor this job must be run by.
+    pub fn runtime(&self) -> Runtime {
+        self.runtime
+    }
+
+    /// The Wasm features this job's executable needs the engine to have turned on.
+    pub fn wasm_features(&self) -> &[WasmFeature] {
+        &self.wasm_features
+    }
+
+    /// True if the manifest promises that this job's output depends only on its input.
+    pub fn pure(&self) -> bool {
+        self.pure
+    }
+
+    /// True if this job runs with a pinned clock and seeded randomness.
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// True if this job needs the node's shared dataset mounted.
+    pub fn needs_shared_data(&self) -> bool {
+        self.needs_shared_data
+    }
+
+    /// The nonzero exit codes this job may exit with and still be considered successful.
+    pub fn acceptable_exit_codes(&self) -> &[i32] {
+        &self.acceptable_exit_codes
+    }
+
+    /// True if this job's output goes to the blob store instead of back to the caller.
+    pub fn output_to_storage(&self) -> bool {
+        self.output_to_storage
+    }
+
+    /// The exports to run as a pipeline; empty if the job runs its default export.
+    pub fn pipeline(&self) -> &[String] {
+        &self.pipeline
+    }
+
+    /// The extension that transforms this job's input before it runs, if any.
+    pub fn preprocessor(&self) -> Option<&str> {
+        self.preprocessor.as_deref()
+    }
+
+    /// The most memory this job may use, if the manifest declares a limit.
+    pub fn max_memory_bytes(&self) -> Option<u64> {
+        self.max_memory_bytes
+    }
+
+    /// The longest this job may run, in milliseconds, if the manifest declares a limit.
+    pub fn max_duration_ms(&self) -> Option<u64> {
+        self.max_duration_ms
+    }
+
+    /// The fully-qualified name of the manifest this one inherits from, if any.
+    pub fn base(&self) -> Option<&str> {
+        self.base.as_deref()
+    }
+
+    /// Fill in every field this manifest leaves unset from the given base manifest. A job's own
+    /// name, namespace, version, and binary always belong to it and are never inherited. Neither
+    /// are its runtime or its true-or-false flags such as `pure`: a manifest can't say that one
+    /// of those is unset, so a child that wants one turned on has to say so itself.
+    ///
+    /// A signature covers only what its signer wrote, so if inheriting changes anything, the
+    /// signature is dropped. The executable checksum stays, and still pins the executable.
+    pub fn inherit_from(&mut self, base: &Manifest) {
+        let before = self.clone();
+        if self.description.is_empty() {
+            self.description = base.description.clone();
+        }
+        if self.required_extensions.is_empty() {
+            self.required_extensions = base.required_extensions.clone();
+        }
+        if self.required_permissions.is_empty() {
+            self.required_permissions = base.required_permissions.clone();
+        }
+        if self.tags.is_empty() {
+            self.tags = base.tags.clone();
+        }
+        if self.wasm_features.is_empty() {
+            self.wasm_features = base.wasm_features.clone();
+        }
+        if self.acceptable_exit_codes.is_empty() {
+            self.acceptable_exit_codes = base.acceptable_exit_codes.clone();
+        }
+        if self.pipeline.is_empty() {
+            self.pipeline = base.pipeline.clone();
+        }
+        if self.preprocessor.is_none() {
+            self.preprocessor = base.preprocessor.clone();
+        }
+        self.max_input_bytes = self.max_input_bytes.or(base.max_input_bytes);
+        self.max_output_bytes = self.max_output_bytes.or(base.max_output_bytes);
+        self.max_memory_bytes = self.max_memory_bytes.or(base.max_memory_bytes);
+        self.max_duration_ms = self.max_duration_ms.or(base.max_duration_ms);
+        if self.output_schema.is_none() {
+            self.output_schema = base.output_schema.clone();
+        }
+        if *self != before {
+            self.signature = None;
+        }
+    }
+
+    /// Adjust this manifest for a single run. Only the fields `ManifestOverrides` has can change.
+    pub fn apply_overrides(&mut self, overrides: &ManifestOverrides) {
+        self.max_input_bytes = overrides.max_input_bytes.or(self.max_input_bytes);
+        self.max_output_bytes = overrides.max_output_bytes.or(self.max_output_bytes);
+        self.max_memory_bytes = overrides.max_memory_bytes.or(self.max_memory_bytes);
+        self.max_duration_ms = overrides.max_duration_ms.or(self.max_duration_ms);
+    }
+
+    /// The tags this manifest has been given.
+    pub fn tags(&self) -> &Vec<String> {
+        &self.tags
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Returns true if this manifest carries the given tag (if any), and if the given search
+    /// string (if any) appears in its name or description. Searching ignores case.
+    pub fn matches(&self, tag: Option<&str>, query: Option<&str>) -> bool {
+        if let Some(tag) = tag {
+            if !self.tags.iter().any(|t| t == tag) {
+                return false;
+            }
+        }
+        if let Some(query) = query {
+            let query = query.to_lowercase();
+            if !self.fq_name().to_lowercase().contains(&query)
+                && !self.description.to_lowercase().contains(&query)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
     pub fn version(&self) -> &str {
         &self.version
     }
 
+    /// The integrity checksum that this manifest's signer says its executable has, if signed.
+    pub fn executable_integrity(&self) -> Option<&str> {
+        self.executable_integrity.as_deref()
+    }
+
+    /// The manifest's signature, if it has one.
+    pub fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    pub(crate) fn set_executable_integrity(&mut self, integrity: Option<String>) {
+        self.executable_integrity = integrity;
+    }
+
+    pub(crate) fn set_signature(&mut self, signature: Option<String>) {
+        self.signature = signature;
+    }
+
+    /// Point this manifest at another stored version of its executable.
+    pub fn set_version(&mut self, version: &str) {
+        self.version = version.to_string();
+    }
+
     /// Get the fully-qualified-by-namespace name for this job type manifest.
     pub fn fq_name(&self) -> String {
         let name = self.name.to_ascii_lowercase();
         format!("{}.{name}", self.namespace)
     }
 
+    /// Scope a fully-qualified name to a tenant, if there is one. Scoped names are what storage
+    /// keys are built from, so each tenant's job types live apart from everyone else's.
+    pub fn scoped_name(tenant: Option<&str>, fq_name: &str) -> String {
+        match tenant {
+            Some(tenant) => format!("{tenant}:{fq_name}"),
+            None => fq_name.to_string(),
+        }
+    }
+
     /// Given a name but no manifest, build a key.
     pub fn make_manifest_key(name: &str) -> String {
         format!("{name}.manifest.toml")
     }
 
+    /// Build the key for the manifest that was stored along with one version of a job. Unlike
+    /// the key from `make_manifest_key()`, this one isn't replaced when a new version is stored.
+    pub fn make_versioned_manifest_key(name: &str, version: &str) -> String {
+        format!("{name}.{version}.manifest")
+    }
+
     /// Get the storage key for this manifest.
     pub fn manifest_key(&self) -> String {
         Manifest::make_manifest_key(&self.fq_name())
@@ -124,6 +558,22 @@ impl Manifest {
     pub fn executable_key(&self) -> String {
         Manifest::make_executable_key(&self.fq_name(), &self.version)
     }
+
+    /// Given a name and a version tag such as `stable`, build the key that the version the tag
+    /// points at is stored under. (Version tags are unrelated to a manifest's search tags.)
+    pub fn make_version_tag_key(name: &str, tag: &str) -> String {
+        format!("{name}.{tag}.tag")
+    }
+
+    /// Returns true if the given string may be used as a version tag. Version tags end up in
+    /// storage keys and in `name@tag` references, so they are kept to a safe alphabet.
+    pub fn is_valid_version_tag(tag: &str) -> bool {
+        !tag.is_empty()
+            && tag.len() <= 64
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
 }
 
 impl Display for Manifest {
@@ -156,6 +606,42 @@ impl<'de> Deserialize<'de> for Manifest {
             required_extensions: Vec<String>,
             #[serde(default)]
             required_permissions: Vec<Permission>,
+            #[serde(default)]
+            max_input_bytes: Option<u64>,
+            #[serde(default)]
+            max_output_bytes: Option<u64>,
+            #[serde(default)]
+            output_schema: Option<String>,
+            #[serde(default)]
+            runtime: Runtime,
+            #[serde(default)]
+            wasm_features: Vec<WasmFeature>,
+            #[serde(default)]
+            pure: bool,
+            #[serde(default)]
+            deterministic: bool,
+            #[serde(default)]
+            needs_shared_data: bool,
+            #[serde(default)]
+            acceptable_exit_codes: Vec<i32>,
+            #[serde(default)]
+            output_to_storage: bool,
+            #[serde(default)]
+            pipeline: Vec<String>,
+            #[serde(default)]
+            preprocessor: Option<String>,
+            #[serde(default)]
+            max_memory_bytes: Option<u64>,
+            #[serde(default)]
+            max_duration_ms: Option<u64>,
+            #[serde(default)]
+            base: Option<String>,
+            #[serde(default)]
+            tags: Vec<String>,
+            #[serde(default)]
+            executable_integrity: Option<String>,
+            #[serde(default)]
+            signature: Option<String>,
         }
 
         let inner = InnerManifest::deserialize(deserializer)?;
@@ -184,10 +670,43 @@ impl<'de> Deserialize<'de> for Manifest {
             description: inner.description,
             required_extensions: inner.required_extensions,
             required_permissions: inner.required_permissions,
+            max_input_bytes: inner.max_input_bytes,
+            max_output_bytes: inner.max_output_bytes,
+            output_schema: inner.output_schema,
+            runtime: inner.runtime,
+            wasm_features: inner.wasm_features,
+            pure: inner.pure,
+            deterministic: inner.deterministic,
+            needs_shared_data: inner.needs_shared_data,
+            acceptable_exit_codes: inner.acceptable_exit_codes,
+            output_to_storage: inner.output_to_storage,
+            pipeline: inner.pipeline,
+            preprocessor: inner.preprocessor,
+            max_memory_bytes: inner.max_memory_bytes,
+            max_duration_ms: inner.max_duration_ms,
+            base: inner.base,
+            tags: inner.tags,
+            executable_integrity: inner.executable_integrity,
+            signature: inner.signature,
         })
     }
 }
 
+/// The parts of a stored manifest that a caller may change for one run, e.g. to give a job more
+/// memory in one environment than another. Anything else in an overrides object is refused.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_input_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_duration_ms: Option<u64>,
+}
+
 /// Metadata about a specific job instance.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Job {
@@ -199,9 +718,19 @@ pub struct Job {
     executable: Vec<u8>,
     /// Input data
     input: Vec<u8>,
+    /// Labels the caller attached to this run, e.g. `team=birds`, for finding it again later.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
     // TODO: might have version chosen to run here, plus run options; might also store the input
 }
 
+/// The most labels one job may carry.
+pub const MAX_LABELS: usize = 32;
+/// The longest a label's key may be.
+pub const MAX_LABEL_KEY_LEN: usize = 64;
+/// The longest a label's value may be.
+pub const MAX_LABEL_VALUE_LEN: usize = 256;
+
 impl Job {
     pub fn new(manifest: Manifest, executable: Vec<u8>, input: Vec<u8>) -> Self {
         let id = Uuid::new_v4();
@@ -210,9 +739,45 @@ impl Job {
             manifest,
             executable,
             input,
+            labels: BTreeMap::new(),
         }
     }
 
+    /// Attach labels to this job. Check them with `Job::check_labels` first.
+    pub fn with_labels(mut self, labels: BTreeMap<String, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Make sure labels are fit to attach to a job: not too many of them, with keys made only of
+    /// letters, digits, `.`, `_`, and `-`, and neither keys nor values overly long.
+    pub fn check_labels(labels: &BTreeMap<String, String>) -> Result<(), ServalError> {
+        if labels.len() > MAX_LABELS {
+            return Err(ServalError::InvalidLabel(format!(
+                "{} labels given; a job may have at most {MAX_LABELS}",
+                labels.len()
+            )));
+        }
+        for (key, value) in labels {
+            let key_ok = !key.is_empty()
+                && key.len() <= MAX_LABEL_KEY_LEN
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+            if !key_ok {
+                return Err(ServalError::InvalidLabel(format!(
+                    "`{key}` is not a valid label key; keys are 1 to {MAX_LABEL_KEY_LEN} letters, digits, `.`, `_`, or `-`"
+                )));
+            }
+            if value.len() > MAX_LABEL_VALUE_LEN {
+                return Err(ServalError::InvalidLabel(format!(
+                    "the value for `{key}` is longer than {MAX_LABEL_VALUE_LEN} bytes"
+                )));
+            }
+        }
+        Ok(())
+    }
+
     pub fn id(&self) -> &Uuid {
         &self.id
     }
@@ -228,6 +793,10 @@ impl Job {
     pub fn input(&self) -> &Vec<u8> {
         &self.input
     }
+
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -333,4 +902,183 @@ required_permissions = []
         let result = Manifest::from_string(valid_manifest);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn manifest_size_limits() {
+        let manifest = Manifest::from_string(
+            r###"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/tmp/loudify.wasm"
+version = "1"
+description = "SHOUT SHOUT LET IT ALL OUT"
+max_input_bytes = 1024
+"###,
+        )
+        .expect("manifest with limits should parse");
+        assert_eq!(manifest.max_input_bytes(), Some(1024));
+        assert_eq!(manifest.max_output_bytes(), None);
+    }
+
+    #[test]
+    fn overrides_adjust_only_whitelisted_fields() {
+        let mut manifest = Manifest::new(&PathBuf::from("/tmp/loudify.wasm"));
+        let overrides: ManifestOverrides =
+            serde_json::from_str(r#"{"max_memory_bytes": 65536}"#).unwrap();
+        manifest.apply_overrides(&overrides);
+        assert_eq!(manifest.max_memory_bytes(), Some(65536));
+        assert_eq!(manifest.max_input_bytes(), None);
+
+        let refused = serde_json::from_str::<ManifestOverrides>(r#"{"binary": "/tmp/evil.wasm"}"#);
+        assert!(refused.is_err());
+    }
+
+    #[test]
+    fn manifest_exit_codes() {
+        let manifest = Manifest::from_string(
+            r###"
+name = "grep"
+namespace = "sh.serval"
+binary = "/tmp/grep.wasm"
+version = "1"
+description = "exits 1 when nothing matches, which is not a failure"
+acceptable_exit_codes = [1]
+"###,
+        )
+        .unwrap();
+        assert_eq!(manifest.acceptable_exit_codes(), &[1]);
+        assert!(manifest.to_string().contains("acceptable_exit_codes = [1]"));
+    }
+
+    #[test]
+    fn manifest_file_roundtrip() {
+        let manifest = Manifest::from_string(
+            r###"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/tmp/loudify.wasm"
+version = "1.2.3"
+description = "SHOUT SHOUT LET IT ALL OUT"
+required_extensions = ["shouting"]
+required_permissions = ["proc:read:*", "extension:shouting"]
+max_input_bytes = 1024
+max_output_bytes = 2048
+output_schema = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+runtime = "wasm"
+wasm_features = ["simd", "multi_memory"]
+pure = true
+deterministic = true
+needs_shared_data = true
+acceptable_exit_codes = [1, 2]
+output_to_storage = true
+pipeline = ["decode", "shout", "encode"]
+preprocessor = "gunzip"
+max_memory_bytes = 65536
+max_duration_ms = 30000
+base = "sh.serval.shouty_base"
+tags = ["text", "loud"]
+"###,
+        )
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("manifest-{}.toml", Uuid::new_v4()));
+        manifest.to_file(&path).unwrap();
+        let first = fs::read_to_string(&path).unwrap();
+        let reread = Manifest::from_file(&path).unwrap();
+        assert_eq!(reread, manifest);
+
+        reread.to_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), first);
+        fs::remove_file(&path).unwrap();
+
+        let minimal = Manifest::new(&PathBuf::from("/tmp/minimal.wasm"));
+        minimal.to_file(&path).unwrap();
+        assert_eq!(Manifest::from_file(&path).unwrap(), minimal);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn manifest_inheritance() {
+        let base = Manifest::from_string(
+            r###"
+name = "shouty_base"
+namespace = "sh.serval"
+binary = "/tmp/nothing.wasm"
+version = "1"
+description = "shared settings for shouting"
+required_extensions = ["shouting"]
+max_input_bytes = 1024
+max_output_bytes = 2048
+acceptable_exit_codes = [3]
+pipeline = ["decode", "shout"]
+preprocessor = "gunzip"
+"###,
+        )
+        .unwrap();
+        let mut child = Manifest::from_string(
+            r###"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/tmp/loudify.wasm"
+version = "2"
+description = ""
+max_output_bytes = 4096
+base = "sh.serval.shouty_base"
+"###,
+        )
+        .unwrap();
+        assert_eq!(child.base(), Some("sh.serval.shouty_base"));
+
+        child.inherit_from(&base);
+        assert_eq!(child.fq_name(), "sh.serval.loudify");
+        assert_eq!(child.version(), "2");
+        assert_eq!(child.description, "shared settings for shouting");
+        assert_eq!(child.required_extensions, vec!["shouting".to_string()]);
+        assert_eq!(child.max_input_bytes(), Some(1024));
+        assert_eq!(child.max_output_bytes(), Some(4096));
+        assert_eq!(child.acceptable_exit_codes, vec![3]);
+        assert_eq!(child.pipeline, ["decode", "shout"]);
+        assert_eq!(child.preprocessor.as_deref(), Some("gunzip"));
+    }
+
+    #[test]
+    fn manifest_search() {
+        let manifest = Manifest::from_string(
+            r###"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/tmp/loudify.wasm"
+version = "1"
+description = "SHOUT SHOUT LET IT ALL OUT"
+tags = ["text", "silly"]
+"###,
+        )
+        .unwrap();
+        assert!(manifest.matches(None, None));
+        assert!(manifest.matches(Some("silly"), None));
+        assert!(!manifest.matches(Some("image"), None));
+        assert!(manifest.matches(None, Some("LOUD")));
+        assert!(manifest.matches(Some("text"), Some("let it all")));
+        assert!(!manifest.matches(Some("text"), Some("whisper")));
+    }
+
+    #[test]
+    fn job_label_checks() {
+        let labels = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert!(Job::check_labels(&labels(&[("team", "birds"), ("build.id", "42")])).is_ok());
+        assert!(Job::check_labels(&labels(&[("", "empty")])).is_err());
+        assert!(Job::check_labels(&labels(&[("has space", "x")])).is_err());
+        let long_value = "x".repeat(MAX_LABEL_VALUE_LEN + 1);
+        assert!(Job::check_labels(&labels(&[("team", &long_value)])).is_err());
+
+        let too_many: BTreeMap<String, String> = (0..=MAX_LABELS)
+            .map(|i| (format!("k{i}"), String::new()))
+            .collect();
+        assert!(Job::check_labels(&too_many).is_err());
+    }
 }